@@ -1,13 +1,18 @@
+use fpush_traits::secret::SecretValue;
 use serde::Deserialize;
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
 #[serde(rename_all = "camelCase")]
 pub struct AppleApnsConfig {
     cert_file_path: String,
-    cert_password: String,
+    cert_password: SecretValue,
     topic: String,
     #[serde(default = "ApnsEndpoint::production")]
     environment: ApnsEndpoint,
+    #[serde(default)]
+    badge_from_summary: bool,
+    #[serde(default)]
+    sender_as_subtitle: bool,
 }
 
 impl AppleApnsConfig {
@@ -15,7 +20,7 @@ impl AppleApnsConfig {
         &self.cert_file_path
     }
 
-    pub fn cert_password(&self) -> &str {
+    pub fn cert_password(&self) -> &SecretValue {
         &self.cert_password
     }
 
@@ -23,6 +28,19 @@ impl AppleApnsConfig {
         &self.topic
     }
 
+    pub fn badge_from_summary(&self) -> bool {
+        self.badge_from_summary
+    }
+
+    pub fn sender_as_subtitle(&self) -> bool {
+        self.sender_as_subtitle
+    }
+
+    /// Return true if a secret is read from a file or environment variable
+    pub fn has_secret_references(&self) -> bool {
+        self.cert_password.is_reference()
+    }
+
     pub fn endpoint(&self) -> a2::Endpoint {
         match self.environment {
             ApnsEndpoint::Production => a2::Endpoint::Production,
@@ -31,7 +49,7 @@ impl AppleApnsConfig {
     }
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
 #[serde(rename_all = "camelCase")]
 pub enum ApnsEndpoint {
     Production,
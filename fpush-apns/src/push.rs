@@ -5,6 +5,7 @@ use a2::{
     PushType,
 };
 use fpush_traits::push::{PushError, PushResult, PushTrait};
+use fpush_traits::request::{PushPriority, PushRequest};
 
 use async_trait::async_trait;
 use log::{debug, error};
@@ -13,33 +14,38 @@ use crate::AppleApnsConfig;
 pub struct FpushApns {
     apns: a2::client::Client,
     topic: String,
+    badge_from_summary: bool,
+    sender_as_subtitle: bool,
 }
 
 impl FpushApns {
     fn open_cert(filename: &str) -> PushResult<std::fs::File> {
-        if let Ok(file) = std::fs::File::open(filename) {
-            Ok(file)
-        } else {
-            Err(PushError::CertLoading)
-        }
+        std::fs::File::open(filename).map_err(|e| {
+            PushError::Config(format!("certFilePath: could not open {}: {}", filename, e))
+        })
     }
 
     pub fn init(apns_config: &AppleApnsConfig) -> PushResult<Self> {
         let mut certificate = FpushApns::open_cert(apns_config.cert_file_path())?;
-        match Client::certificate(
-            &mut certificate,
-            apns_config.cert_password(),
-            apns_config.endpoint(),
-        ) {
+        let cert_password = apns_config
+            .cert_password()
+            .resolve()
+            .map_err(|e| PushError::Config(format!("certPassword: {}", e)))?;
+        match Client::certificate(&mut certificate, &cert_password, apns_config.endpoint()) {
             Ok(apns_conn) => {
                 let wrapped_conn = Self {
                     apns: apns_conn,
                     topic: apns_config.topic().to_string(),
+                    badge_from_summary: apns_config.badge_from_summary(),
+                    sender_as_subtitle: apns_config.sender_as_subtitle(),
                 };
                 Ok(wrapped_conn)
             }
-            Err(a2::error::Error::ReadError(_)) => Err(PushError::PushEndpointPersistent),
-            Err(_) => Err(PushError::PushEndpointTmp),
+            Err(e) => Err(PushError::Config(format!(
+                "certFilePath: could not load certificate {}, check certPassword: {}",
+                apns_config.cert_file_path(),
+                e
+            ))),
         }
     }
 }
@@ -47,16 +53,34 @@ impl FpushApns {
 #[async_trait]
 impl PushTrait for FpushApns {
     #[inline(always)]
-    async fn send(&self, token: String) -> PushResult<()> {
-        let notification_builder = DefaultNotificationBuilder::new()
+    async fn send(&self, request: &PushRequest) -> PushResult<()> {
+        let token = request.token();
+        let summary = request.summary();
+        let mut notification_builder = DefaultNotificationBuilder::new()
             .set_title("New Message")
             .set_body("New Message?")
             .set_mutable_content()
             .set_sound("default");
+        // only forward summary details if the operator enabled it for this module
+        if self.badge_from_summary {
+            if let Some(message_count) = summary.message_count() {
+                notification_builder = notification_builder
+                    .set_badge(u32::try_from(message_count).unwrap_or(u32::MAX));
+            }
+        }
+        if self.sender_as_subtitle {
+            if let Some(sender) = summary.last_message_sender() {
+                notification_builder = notification_builder.set_subtitle(sender);
+            }
+        }
+        let apns_priority = match request.priority() {
+            PushPriority::High => Priority::High,
+            PushPriority::Normal => Priority::Normal,
+        };
         let payload = notification_builder.build(
-            &token,
+            token,
             NotificationOptions {
-                apns_priority: Some(Priority::High),
+                apns_priority: Some(apns_priority),
                 apns_topic: Some(&self.topic),
                 apns_expiration: Some(
                     SystemTime::now().elapsed().unwrap().as_secs() + 4 * 7 * 24 * 3600,
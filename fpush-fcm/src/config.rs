@@ -1,13 +1,37 @@
+use fpush_traits::secret::SecretValue;
 use serde::Deserialize;
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
 #[serde(rename_all = "camelCase")]
 pub struct GoogleFcmConfig {
-    pub fcm_secret_path: String,
+    /// path of the service account key json file
+    #[serde(default)]
+    pub fcm_secret_path: Option<String>,
+    /// content of the service account key json file, alternative to `fcm_secret_path`
+    #[serde(default)]
+    pub fcm_secret: Option<SecretValue>,
+    #[serde(default)]
+    pub forward_summary: bool,
 }
 
 impl GoogleFcmConfig {
-    pub fn fcm_secret_path(&self) -> &str {
-        &self.fcm_secret_path
+    pub fn fcm_secret_path(&self) -> Option<&str> {
+        self.fcm_secret_path.as_deref()
+    }
+
+    pub fn fcm_secret(&self) -> Option<&SecretValue> {
+        self.fcm_secret.as_ref()
+    }
+
+    pub fn forward_summary(&self) -> bool {
+        self.forward_summary
+    }
+
+    /// Return true if a secret is read from a file or environment variable
+    ///
+    /// `fcm_secret_path` counts as reference, the file is read whenever the push module is loaded.
+    pub fn has_secret_references(&self) -> bool {
+        self.fcm_secret_path.is_some()
+            || matches!(&self.fcm_secret, Some(fcm_secret) if fcm_secret.is_reference())
     }
 }
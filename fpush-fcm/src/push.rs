@@ -1,13 +1,15 @@
 use std::{collections::HashMap, path::Path};
 
 use fpush_traits::push::{PushError, PushResult, PushTrait};
+use fpush_traits::request::{PushPriority, PushRequest};
+use fpush_traits::summary::PushSummary;
 
 use async_trait::async_trait;
 use google_fcm1::{
     api::{Message, SendMessageRequest, Notification, AndroidConfig, AndroidNotification, ApnsConfig},
     oauth2, FirebaseCloudMessaging,
 };
-use log::{error, warn};
+use log::warn;
 
 use serde::Deserialize;
 
@@ -16,32 +18,68 @@ pub struct FpushFcm {
     fcm_conn:
         FirebaseCloudMessaging<hyper_rustls::HttpsConnector<hyper::client::connect::HttpConnector>>,
     fcm_parent: String,
+    forward_summary: bool,
 }
 
 impl FpushFcm {
-    async fn load_oauth2_app_secret(fcm_config: &GoogleFcmConfig) -> oauth2::ServiceAccountKey {
-        match oauth2::read_service_account_key(Path::new(fcm_config.fcm_secret_path())).await {
-            Ok(s) => s,
-            Err(e) => panic!(
-                "Could not read fcm config file at {} reason: {}",
-                fcm_config.fcm_secret_path(),
-                e
-            ),
+    /// Load the service account key and return it with a description of its origin for errors
+    async fn load_oauth2_app_secret(
+        fcm_config: &GoogleFcmConfig,
+    ) -> PushResult<(oauth2::ServiceAccountKey, String)> {
+        match (fcm_config.fcm_secret_path(), fcm_config.fcm_secret()) {
+            (Some(fcm_secret_path), None) => {
+                oauth2::read_service_account_key(Path::new(fcm_secret_path))
+                    .await
+                    .map(|fcm_secret| (fcm_secret, format!("fcmSecretPath: {}", fcm_secret_path)))
+                    .map_err(|e| {
+                        PushError::Config(format!(
+                            "fcmSecretPath: could not read {}: {}",
+                            fcm_secret_path, e
+                        ))
+                    })
+            }
+            (None, Some(fcm_secret)) => {
+                let service_account_key = fcm_secret
+                    .resolve()
+                    .map_err(|e| PushError::Config(format!("fcmSecret: {}", e)))?;
+                oauth2::parse_service_account_key(service_account_key)
+                    .map(|key| (key, format!("fcmSecret: {}", fcm_secret)))
+                    .map_err(|e| {
+                        PushError::Config(format!(
+                            "fcmSecret: {} is not a service account key: {}",
+                            fcm_secret, e
+                        ))
+                    })
+            }
+            _ => Err(PushError::Config(
+                "either fcmSecretPath or fcmSecret has to be set".to_string(),
+            )),
         }
     }
 
     pub async fn init(fcm_config: &GoogleFcmConfig) -> PushResult<Self> {
-        let fcm_secret = Self::load_oauth2_app_secret(fcm_config).await;
+        let (fcm_secret, secret_origin) = Self::load_oauth2_app_secret(fcm_config).await?;
+        let project_id = match fcm_secret.project_id.clone() {
+            Some(project_id) => project_id,
+            None => {
+                return Err(PushError::Config(format!(
+                    "{} does not contain a project_id",
+                    secret_origin
+                )))
+            }
+        };
 
         // create login auth object
-        let auth = match oauth2::ServiceAccountAuthenticator::builder(fcm_secret.clone())
+        let auth = match oauth2::ServiceAccountAuthenticator::builder(fcm_secret)
             .build()
             .await
         {
             Ok(auth) => auth,
             Err(e) => {
-                error!("Could not load fcm DeviceFlowAuthenticator: {}", e);
-                return Err(PushError::CertLoading);
+                return Err(PushError::Config(format!(
+                    "{}: could not create authenticator: {}",
+                    secret_origin, e
+                )))
             }
         };
 
@@ -55,7 +93,8 @@ impl FpushFcm {
         let fcm_conn = FirebaseCloudMessaging::new(hyper_client, auth);
         Ok(Self {
             fcm_conn,
-            fcm_parent: format!("projects/{}", fcm_secret.project_id.unwrap()),
+            fcm_parent: format!("projects/{}", project_id),
+            forward_summary: fcm_config.forward_summary(),
         })
     }
 }
@@ -87,9 +126,18 @@ enum FcmErrorCode {
 #[async_trait]
 impl PushTrait for FpushFcm {
     #[inline(always)]
-    async fn send(&self, token: String) -> PushResult<()> {
+    async fn send(&self, request: &PushRequest) -> PushResult<()> {
+        let data = if self.forward_summary {
+            create_summary_data(request.summary())
+        } else {
+            HashMap::new()
+        };
         let req = SendMessageRequest {
-            message: Some(create_push_message(token)),
+            message: Some(create_push_message(
+                request.token().to_string(),
+                data,
+                request.priority(),
+            )),
             validate_only: None,
         };
 
@@ -122,19 +170,42 @@ impl PushTrait for FpushFcm {
 }
 
 #[inline(always)]
-fn create_push_message(token: String) -> Message {
+fn create_push_message(
+    token: String,
+    data: HashMap<String, String>,
+    priority: PushPriority,
+) -> Message {
     Message {
-        data: Some(HashMap::new()),
+        data: Some(data),
         token: Some(token),
         notification: Some(create_notification()),
         // add this to make sure we set the tag to group on Android
         // so the user only ever sees 1 notification in their drawer
-        android: Some(create_android_config()),
+        android: Some(create_android_config(priority)),
         apns: Some(create_apns_config()),
         ..Default::default()
     }
 }
 
+/// forward the non confidential parts of the push summary as fcm data
+#[inline(always)]
+fn create_summary_data(summary: &PushSummary) -> HashMap<String, String> {
+    let mut data = HashMap::new();
+    if let Some(message_count) = summary.message_count() {
+        data.insert("message-count".to_string(), message_count.to_string());
+    }
+    if let Some(sender) = summary.last_message_sender() {
+        data.insert("last-message-sender".to_string(), sender.to_string());
+    }
+    if let Some(pending_subscription_count) = summary.pending_subscription_count() {
+        data.insert(
+            "pending-subscription-count".to_string(),
+            pending_subscription_count.to_string(),
+        );
+    }
+    data
+}
+
 #[inline(always)]
 fn create_notification() -> Notification {
     Notification {
@@ -146,9 +217,14 @@ fn create_notification() -> Notification {
 
 
 #[inline(always)]
-fn create_android_config() -> AndroidConfig {
+fn create_android_config(priority: PushPriority) -> AndroidConfig {
+    let android_priority = match priority {
+        PushPriority::High => "HIGH",
+        PushPriority::Normal => "NORMAL",
+    };
     AndroidConfig {
         notification: Some(create_android_notification()),
+        priority: Some(android_priority.to_string()),
         ..Default::default()
     }
 }
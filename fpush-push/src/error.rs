@@ -6,7 +6,14 @@ pub type PushRequestResult<T> = std::result::Result<T, PushRequestError>;
 pub enum PushRequestError {
     TokenRatelimited,
     TokenBlocked,
+    /// the push service reported the token as permanently invalid
+    TokenInvalid,
+    NotAuthorized,
+    /// a filter rule of the push module dropped the push request
+    Filtered,
     Internal,
+    /// the push service failed transiently and the push request is queued for a later replay
+    Queued,
     UnkownPushModule,
 }
 
@@ -14,3 +21,17 @@ pub enum PushRequestError {
 pub(crate) enum Error {
     PushErrors(fpush_traits::push::PushError),
 }
+
+/// Error loading the configured push modules
+#[derive(Debug, Display)]
+pub enum PushModuleError {
+    /// the push module configuration is invalid
+    #[display(fmt = "{}", _0)]
+    Config(String),
+    /// the push module with the given identifier could not be initialized
+    #[display(fmt = "pushModules.{}: {}", _0, _1)]
+    Init(String, String),
+    /// the push queue could not be opened
+    #[display(fmt = "pushQueue: {}", _0)]
+    Queue(String),
+}
@@ -0,0 +1,204 @@
+use std::time::Duration;
+
+use crate::error::PushRequestError;
+
+use derive_getters::Getters;
+use serde::Deserialize;
+
+/// Reply sent to the XMPP server for each kind of failed push request
+#[derive(Debug, Deserialize, Clone, PartialEq, Eq, Getters)]
+#[serde(rename_all = "camelCase", default)]
+pub struct ErrorPolicy {
+    token_ratelimited: ErrorReply,
+    token_blocked: ErrorReply,
+    token_invalid: ErrorReply,
+    not_authorized: ErrorReply,
+    filtered: ErrorReply,
+    internal: ErrorReply,
+    queued: ErrorReply,
+    unknown_push_module: ErrorReply,
+}
+
+impl ErrorPolicy {
+    /// Return the configured reply for `error`
+    pub fn reply_for(&self, error: &PushRequestError) -> &ErrorReply {
+        match error {
+            PushRequestError::TokenRatelimited => &self.token_ratelimited,
+            PushRequestError::TokenBlocked => &self.token_blocked,
+            PushRequestError::TokenInvalid => &self.token_invalid,
+            PushRequestError::NotAuthorized => &self.not_authorized,
+            PushRequestError::Filtered => &self.filtered,
+            PushRequestError::Internal => &self.internal,
+            PushRequestError::Queued => &self.queued,
+            PushRequestError::UnkownPushModule => &self.unknown_push_module,
+        }
+    }
+}
+
+impl Default for ErrorPolicy {
+    fn default() -> Self {
+        Self {
+            // Some admins did not understood the wait_iq -> we know send an ack
+            token_ratelimited: ErrorReply::Ack,
+            token_blocked: ErrorReply::Error(StanzaErrorReply::new(
+                StanzaErrorType::Cancel,
+                StanzaErrorCondition::PolicyViolation,
+                "A error occured",
+            )),
+            // XEP-0357 servers disable the push registration on item-not-found
+            token_invalid: ErrorReply::Error(StanzaErrorReply::new(
+                StanzaErrorType::Cancel,
+                StanzaErrorCondition::ItemNotFound,
+                "Push token is no longer valid",
+            )),
+            not_authorized: ErrorReply::Error(StanzaErrorReply::new(
+                StanzaErrorType::Auth,
+                StanzaErrorCondition::NotAuthorized,
+                "Invalid push secret",
+            )),
+            // the push was intentionally not sent, the XMPP server should neither retry nor disable
+            filtered: ErrorReply::Ack,
+            internal: ErrorReply::Error(StanzaErrorReply::new(
+                StanzaErrorType::Cancel,
+                StanzaErrorCondition::BadRequest,
+                "A error occured",
+            )),
+            // the push is sent once the push service recovers
+            queued: ErrorReply::Ack,
+            unknown_push_module: ErrorReply::Error(StanzaErrorReply::new(
+                StanzaErrorType::Cancel,
+                StanzaErrorCondition::BadRequest,
+                "A error occured",
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+#[serde(tag = "action", rename_all = "camelCase")]
+pub enum ErrorReply {
+    /// acknowledge the push request as if it succeeded
+    Ack,
+    /// reply with a stanza error
+    Error(StanzaErrorReply),
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq, Eq, Getters)]
+#[serde(rename_all = "camelCase")]
+pub struct StanzaErrorReply {
+    #[serde(rename = "type")]
+    error_type: StanzaErrorType,
+    condition: StanzaErrorCondition,
+    text: String,
+    /// hint when the XMPP server should retry, appended to the text
+    ///
+    /// XEP-0357 has no structured element for it, so XMPP servers do not act on the hint.
+    #[serde(default, deserialize_with = "serde_humantime_opt")]
+    retry_after: Option<Duration>,
+}
+
+impl StanzaErrorReply {
+    pub fn new(error_type: StanzaErrorType, condition: StanzaErrorCondition, text: &str) -> Self {
+        Self {
+            error_type,
+            condition,
+            text: text.to_string(),
+            retry_after: None,
+        }
+    }
+
+    /// Text of the stanza error including the retry hint
+    pub fn text_with_retry_hint(&self) -> String {
+        match self.retry_after {
+            Some(retry_after) => format!("{}, retry after {}s", self.text, retry_after.as_secs()),
+            None => self.text.clone(),
+        }
+    }
+}
+
+/// Type of a stanza error as defined in RFC 6120 section 8.3.2
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum StanzaErrorType {
+    Auth,
+    Cancel,
+    Continue,
+    Modify,
+    Wait,
+}
+
+/// Defined condition of a stanza error as defined in RFC 6120 section 8.3.3
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum StanzaErrorCondition {
+    BadRequest,
+    Conflict,
+    FeatureNotImplemented,
+    Forbidden,
+    InternalServerError,
+    ItemNotFound,
+    NotAcceptable,
+    NotAllowed,
+    NotAuthorized,
+    PolicyViolation,
+    RecipientUnavailable,
+    RegistrationRequired,
+    ResourceConstraint,
+    ServiceUnavailable,
+    UndefinedCondition,
+    UnexpectedRequest,
+}
+
+fn serde_humantime_opt<'de, D>(deserializer: D) -> std::result::Result<Option<Duration>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    serde_humantime::De::<Option<Duration>>::deserialize(deserializer)
+        .map(|wrapped_de: serde_humantime::De<Option<Duration>>| wrapped_de.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_policy_keeps_ack_for_ratelimit() {
+        let policy = ErrorPolicy::default();
+        assert_eq!(
+            policy.reply_for(&PushRequestError::TokenRatelimited),
+            &ErrorReply::Ack
+        );
+    }
+
+    #[test]
+    fn test_parse_error_policy() {
+        let policy: ErrorPolicy = serde_json::from_str(
+            r#"{
+                "tokenRatelimited": {
+                    "action": "error",
+                    "type": "wait",
+                    "condition": "resource-constraint",
+                    "text": "Too many push requests",
+                    "retryAfter": "60s"
+                }
+            }"#,
+        )
+        .unwrap();
+        match policy.reply_for(&PushRequestError::TokenRatelimited) {
+            ErrorReply::Error(reply) => {
+                assert_eq!(reply.error_type(), &StanzaErrorType::Wait);
+                assert_eq!(reply.condition(), &StanzaErrorCondition::ResourceConstraint);
+                assert_eq!(
+                    reply.text_with_retry_hint(),
+                    "Too many push requests, retry after 60s"
+                );
+            }
+            ErrorReply::Ack => panic!("expected stanza error"),
+        }
+        // not configured variants keep their default
+        assert_eq!(
+            policy.reply_for(&PushRequestError::TokenBlocked),
+            ErrorPolicy::default().reply_for(&PushRequestError::TokenBlocked)
+        );
+    }
+}
@@ -0,0 +1,233 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use fpush_traits::request::PushRequest;
+
+use log::{debug, info};
+use serde::Deserialize;
+
+/// Rule deciding whether a push request is sent or dropped
+///
+/// A rule matches if all of its configured conditions match. Rules without any condition
+/// match every push request.
+#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct FilterRule {
+    /// name of the rule used when reporting the number of filtered push requests
+    #[serde(default)]
+    name: Option<String>,
+    #[serde(default)]
+    summary_field: Option<SummaryFieldMatch>,
+    /// domains of sending XMPP servers
+    #[serde(default)]
+    sender_domains: Option<Vec<String>>,
+    #[serde(default)]
+    has_publish_options: Option<bool>,
+    action: FilterAction,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum FilterAction {
+    Allow,
+    Drop,
+}
+
+/// Condition on a field of the `urn:xmpp:push:summary` form
+#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct SummaryFieldMatch {
+    var: String,
+    condition: FieldCondition,
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum FieldCondition {
+    /// the field is not part of the summary
+    Missing,
+    /// the field is part of the summary
+    Present,
+    /// the field is part of the summary but has no or an empty value
+    Empty,
+    Equals(String),
+    GreaterThan(u64),
+    LessThan(u64),
+}
+
+impl FilterRule {
+    fn new_legacy(name: &str, condition: FieldCondition) -> Self {
+        Self {
+            name: Some(name.to_string()),
+            summary_field: Some(SummaryFieldMatch {
+                var: "last-message-body".to_string(),
+                condition,
+            }),
+            sender_domains: None,
+            has_publish_options: Some(false),
+            action: FilterAction::Drop,
+        }
+    }
+
+    /// Filter rules used if a push module does not configure its own rules
+    ///
+    /// Actual push notifications from prosody have a child value in the message body of one of
+    /// the <field> tags, but in some cases mod_cloud_notify sends unimportant notifications
+    /// that do not correspond to unread messages.
+    pub fn default_rules() -> Vec<FilterRule> {
+        vec![
+            Self::new_legacy("unimportant-notification", FieldCondition::Empty),
+            Self::new_legacy(
+                "skipped-notification",
+                FieldCondition::Equals("false".to_string()),
+            ),
+        ]
+    }
+
+    fn matches(&self, request: &PushRequest) -> bool {
+        if let Some(summary_field) = &self.summary_field {
+            if !summary_field.matches(request) {
+                return false;
+            }
+        }
+        if let Some(sender_domains) = &self.sender_domains {
+            match request.domain() {
+                Some(domain) if sender_domains.iter().any(|d| d == domain) => {}
+                _ => return false,
+            }
+        }
+        if let Some(has_publish_options) = self.has_publish_options {
+            if request.publish_options().is_empty() == has_publish_options {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+impl SummaryFieldMatch {
+    fn matches(&self, request: &PushRequest) -> bool {
+        let values = request.summary().field(&self.var);
+        let first_value = values.and_then(|values| values.first());
+        let number = first_value.and_then(|value| value.parse::<u64>().ok());
+        match &self.condition {
+            FieldCondition::Missing => values.is_none(),
+            FieldCondition::Present => values.is_some(),
+            FieldCondition::Empty => {
+                values.is_some() && matches!(first_value.map(String::as_str), None | Some(""))
+            }
+            FieldCondition::Equals(expected) => first_value == Some(expected),
+            FieldCondition::GreaterThan(limit) => matches!(number, Some(number) if number > *limit),
+            FieldCondition::LessThan(limit) => matches!(number, Some(number) if number < *limit),
+        }
+    }
+}
+
+/// Filter rules of a push module together with the number of push requests each rule dropped
+pub struct PushFilter {
+    rules: Vec<FilterRule>,
+    filtered: Vec<AtomicU64>,
+}
+
+impl PushFilter {
+    pub fn new(rules: &[FilterRule]) -> Self {
+        Self {
+            rules: rules.to_vec(),
+            filtered: rules.iter().map(|_| AtomicU64::new(0)).collect(),
+        }
+    }
+
+    /// Return true if the first matching rule drops the push request
+    pub fn is_filtered(&self, request: &PushRequest) -> bool {
+        match self
+            .rules
+            .iter()
+            .enumerate()
+            .find(|(_, rule)| rule.matches(request))
+        {
+            Some((index, rule)) if rule.action == FilterAction::Drop => {
+                debug!(
+                    "Dropping push request for token {} due to filter rule {}",
+                    request.token(),
+                    self.rule_name(index)
+                );
+                self.filtered[index].fetch_add(1, Ordering::Relaxed);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Log the number of push requests dropped by each rule since the last report and reset them
+    pub fn report(&self, module_id: &str) {
+        for (index, filtered) in self.filtered.iter().enumerate() {
+            let count = filtered.swap(0, Ordering::Relaxed);
+            if count > 0 {
+                info!(
+                    "{}: Filter rule {} dropped {} push requests",
+                    module_id,
+                    self.rule_name(index),
+                    count
+                );
+            }
+        }
+    }
+
+    fn rule_name(&self, index: usize) -> String {
+        self.rules[index]
+            .name
+            .clone()
+            .unwrap_or_else(|| format!("#{}", index))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use fpush_traits::{request::PushRequest, summary::PushSummary};
+
+    use super::*;
+
+    fn request_with_body(body: Option<Vec<String>>) -> PushRequest {
+        let mut fields = HashMap::new();
+        if let Some(body) = body {
+            fields.insert("last-message-body".to_string(), body);
+        }
+        PushRequest::new("token".to_string())
+            .with_sender("user@example.org".to_string(), "example.org".to_string())
+            .with_summary(PushSummary::default().with_fields(fields))
+    }
+
+    #[test]
+    fn test_default_rules_drop_unimportant_notifications() {
+        let filter = PushFilter::new(&FilterRule::default_rules());
+
+        assert!(filter.is_filtered(&request_with_body(Some(vec![]))));
+        assert!(filter.is_filtered(&request_with_body(Some(vec!["false".to_string()]))));
+        assert!(!filter.is_filtered(&request_with_body(Some(vec!["Hi".to_string()]))));
+        assert!(!filter.is_filtered(&request_with_body(None)));
+
+        // the heuristic only applies to push requests without publish-options
+        let mut options = HashMap::new();
+        options.insert("secret".to_string(), vec!["s".to_string()]);
+        assert!(!filter.is_filtered(&request_with_body(Some(vec![])).with_publish_options(options)));
+    }
+
+    #[test]
+    fn test_first_matching_rule_wins() {
+        let rules: Vec<FilterRule> = serde_json::from_str(
+            r#"[
+                { "senderDomains": ["example.org"], "action": "allow" },
+                { "summaryField": { "var": "last-message-body", "condition": "missing" }, "action": "drop" }
+            ]"#,
+        )
+        .unwrap();
+        let filter = PushFilter::new(&rules);
+
+        assert!(!filter.is_filtered(&request_with_body(None)));
+        let other_domain = request_with_body(None)
+            .with_sender("user@example.com".to_string(), "example.com".to_string());
+        assert!(filter.is_filtered(&other_domain));
+        assert_eq!(filter.filtered[1].load(Ordering::Relaxed), 1);
+    }
+}
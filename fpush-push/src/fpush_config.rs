@@ -1,11 +1,15 @@
 use std::collections::HashMap;
 
+use crate::error_policy::ErrorPolicy;
+use crate::filter::FilterRule;
+use crate::retry::RetryPolicy;
 use fpush_ratelimit::RatelimitSettings;
 use fpush_tokenblocker::BlacklistSettings;
+use fpush_traits::secret::SecretValue;
 
 use serde::Deserialize;
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Clone)]
 #[serde(transparent)]
 pub struct FpushPushConfig(std::collections::HashMap<String, PushConfig>);
 
@@ -21,6 +25,64 @@ impl FpushPushConfig {
     pub fn insert(&mut self, identifier: String, push_config: PushConfig) {
         self.0.insert(identifier, push_config);
     }
+
+    /// Check the settings shared between push modules
+    pub fn validate(&self) -> Result<(), String> {
+        let mut default_modules: Vec<&String> = self
+            .0
+            .iter()
+            .filter(|(_, push_config)| push_config.is_default_module())
+            .map(|(push_module_id, _)| push_module_id)
+            .collect();
+        if default_modules.len() > 1 {
+            default_modules.sort();
+            return Err(format!(
+                "pushModules: {:?} are all set as is_default_module, at most one push module can be the default module",
+                default_modules
+            ));
+        }
+        for (push_module_id, push_config) in &self.0 {
+            push_config
+                .retry()
+                .validate()
+                .map_err(|e| format!("pushModules.{}.retry: {}", push_module_id, e))?;
+        }
+        self.alias_map()?;
+        Ok(())
+    }
+
+    /// Return the entries of the push module map, including the `default` entry
+    ///
+    /// Each entry consists of the key in the push module map, the identifier of the push module
+    /// and its configuration.
+    pub(crate) fn module_entries(&self) -> Vec<(String, &String, &PushConfig)> {
+        let mut entries = Vec::with_capacity(self.0.len() + 1);
+        for (push_module_id, push_config) in &self.0 {
+            entries.push((push_module_id.to_string(), push_module_id, push_config));
+            if push_config.is_default_module() {
+                entries.push(("default".to_string(), push_module_id, push_config));
+            }
+        }
+        entries
+    }
+
+    /// Return the map of alias to push module identifier
+    pub(crate) fn alias_map(&self) -> Result<HashMap<String, String>, String> {
+        let mut aliases = HashMap::new();
+        for (push_module_id, push_config) in &self.0 {
+            for alias in push_config.aliases() {
+                if let Some(other_module_id) =
+                    aliases.insert(alias.to_string(), push_module_id.to_string())
+                {
+                    return Err(format!(
+                        "pushModules.{}.aliases: alias {} is already configured for push module {}",
+                        push_module_id, alias, other_module_id
+                    ));
+                }
+            }
+        }
+        Ok(aliases)
+    }
 }
 
 impl Default for FpushPushConfig {
@@ -29,36 +91,218 @@ impl Default for FpushPushConfig {
     }
 }
 
-#[derive(Debug, Deserialize)]
+/// Configuration of a single push module
+#[derive(Debug, Deserialize, Clone)]
+pub struct PushConfig {
+    #[serde(flatten)]
+    backend: PushBackendConfig,
+    #[serde(default)]
+    blacklist: BlacklistSettings,
+    #[serde(default)]
+    ratelimit: RatelimitSettings,
+    #[serde(default)]
+    is_default_module: bool,
+    #[serde(default)]
+    secret: Option<SecretSettings>,
+    #[serde(default, rename = "errorPolicy")]
+    error_policy: ErrorPolicy,
+    /// localparts or resources of the component JID selecting this push module
+    #[serde(default)]
+    aliases: Vec<String>,
+    #[serde(default = "FilterRule::default_rules")]
+    filters: Vec<FilterRule>,
+    #[serde(default)]
+    retry: RetryPolicy,
+}
+
+impl PushConfig {
+    pub fn backend(&self) -> &PushBackendConfig {
+        &self.backend
+    }
+
+    pub fn blacklist(&self) -> &BlacklistSettings {
+        &self.blacklist
+    }
+
+    pub fn ratelimit(&self) -> &RatelimitSettings {
+        &self.ratelimit
+    }
+
+    pub fn is_default_module(&self) -> bool {
+        self.is_default_module
+    }
+
+    pub fn secret(&self) -> Option<&SecretSettings> {
+        self.secret.as_ref()
+    }
+
+    /// Return true if the backend or the push secret reads a file or environment variable
+    pub fn has_secret_references(&self) -> bool {
+        self.backend.has_secret_references()
+            || matches!(&self.secret, Some(secret) if secret.is_reference())
+    }
+
+    pub fn error_policy(&self) -> &ErrorPolicy {
+        &self.error_policy
+    }
+
+    pub fn aliases(&self) -> &[String] {
+        &self.aliases
+    }
+
+    pub fn filters(&self) -> &[FilterRule] {
+        &self.filters
+    }
+
+    pub fn retry(&self) -> &RetryPolicy {
+        &self.retry
+    }
+
+    /// Return the keys of all settings that differ from `other`
+    pub fn changed_settings(&self, other: &PushConfig) -> Vec<&'static str> {
+        let mut changed = Vec::new();
+        if self.backend != other.backend {
+            changed.push("backend");
+        }
+        if self.blacklist != other.blacklist {
+            changed.push("blacklist");
+        }
+        if self.ratelimit != other.ratelimit {
+            changed.push("ratelimit");
+        }
+        if self.is_default_module != other.is_default_module {
+            changed.push("is_default_module");
+        }
+        if self.secret != other.secret {
+            changed.push("secret");
+        }
+        if self.error_policy != other.error_policy {
+            changed.push("errorPolicy");
+        }
+        if self.aliases != other.aliases {
+            changed.push("aliases");
+        }
+        if self.filters != other.filters {
+            changed.push("filters");
+        }
+        if self.retry != other.retry {
+            changed.push("retry");
+        }
+        changed
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
 #[serde(tag = "type", rename_all = "camelCase")]
-pub enum PushConfig {
+pub enum PushBackendConfig {
     #[cfg(feature = "enable_apns_support")]
-    Apple {
-        apns: fpush_apns::AppleApnsConfig,
-        #[serde(default)]
-        blacklist: BlacklistSettings,
-        #[serde(default)]
-        ratelimit: RatelimitSettings,
-        #[serde(default)]
-        is_default_module: bool,
-    },
+    Apple { apns: fpush_apns::AppleApnsConfig },
     #[cfg(feature = "enable_fcm_support")]
-    Google {
-        fcm: fpush_fcm::GoogleFcmConfig,
-        #[serde(default)]
-        blacklist: BlacklistSettings,
-        #[serde(default)]
-        ratelimit: RatelimitSettings,
-        #[serde(default)]
-        is_default_module: bool,
-    },
+    Google { fcm: fpush_fcm::GoogleFcmConfig },
     #[cfg(feature = "enable_demo_support")]
-    Demo {
-        #[serde(default)]
-        blacklist: BlacklistSettings,
-        #[serde(default)]
-        ratelimit: RatelimitSettings,
-        #[serde(default)]
-        is_default_module: bool,
-    },
+    Demo {},
+}
+
+impl PushBackendConfig {
+    /// Check if push modules of `module_type` are supported by this build
+    ///
+    /// Returns the reason, including the feature flag to enable, if they are not.
+    pub fn check_supported_type(module_type: &str) -> Result<(), String> {
+        let (feature, enabled) = match module_type {
+            "apple" => ("enable_apns_support", cfg!(feature = "enable_apns_support")),
+            "google" => ("enable_fcm_support", cfg!(feature = "enable_fcm_support")),
+            "demo" => ("enable_demo_support", cfg!(feature = "enable_demo_support")),
+            _ => {
+                return Err(format!(
+                    "unknown push module type {}, expected apple, google or demo",
+                    module_type
+                ))
+            }
+        };
+        if enabled {
+            Ok(())
+        } else {
+            Err(format!(
+                "push module type {} is not supported by this build, compile fpush with feature {}",
+                module_type, feature
+            ))
+        }
+    }
+
+    /// Return true if the backend reads a secret from a file or environment variable
+    pub fn has_secret_references(&self) -> bool {
+        match self {
+            #[cfg(feature = "enable_apns_support")]
+            PushBackendConfig::Apple { apns } => apns.has_secret_references(),
+            #[cfg(feature = "enable_fcm_support")]
+            PushBackendConfig::Google { fcm } => fcm.has_secret_references(),
+            #[cfg(feature = "enable_demo_support")]
+            PushBackendConfig::Demo {} => false,
+        }
+    }
+}
+
+/// Secret a XMPP server has to include as `secret` publish-option in every push request
+#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+#[serde(tag = "mode", rename_all = "camelCase")]
+pub enum SecretSettings {
+    /// The same secret is expected for all tokens of the push module
+    Static { secret: SecretValue },
+    /// The secret is the hex encoded HMAC-SHA256 of the token using the configured key
+    Hmac { key: SecretValue },
+}
+
+impl SecretSettings {
+    /// Return true if the secret or key is read from a file or environment variable
+    pub fn is_reference(&self) -> bool {
+        match self {
+            SecretSettings::Static { secret } => secret.is_reference(),
+            SecretSettings::Hmac { key } => key.is_reference(),
+        }
+    }
+}
+
+// the demo push module is the only backend that needs no credentials
+#[cfg(all(test, feature = "enable_demo_support"))]
+mod tests {
+    use super::*;
+
+    fn parse_config(config: &str) -> FpushPushConfig {
+        serde_json::from_str(config).unwrap()
+    }
+
+    #[test]
+    fn test_changed_settings() {
+        let old = parse_config(r#"{ "demo": { "type": "demo" } }"#);
+        let new = parse_config(
+            r#"{ "demo": { "type": "demo", "aliases": ["demoApp"], "ratelimit": {
+                "hardRatelimitTime": "1m",
+                "ratelimitTime": "10s",
+                "ratelimitCleanupInterval": "5m",
+                "enabled": true
+            } } }"#,
+        );
+        let changed = old.config()["demo"].changed_settings(&new.config()["demo"]);
+        assert_eq!(changed, vec!["ratelimit", "aliases"]);
+        assert!(new.config()["demo"]
+            .changed_settings(&new.config()["demo"])
+            .is_empty());
+    }
+
+    #[test]
+    fn test_validate_rejects_duplicate_alias() {
+        let config = parse_config(
+            r#"{
+                "demo": { "type": "demo", "aliases": ["app"] },
+                "other": { "type": "demo", "aliases": ["app"] }
+            }"#,
+        );
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_check_supported_type() {
+        assert!(PushBackendConfig::check_supported_type("demo").is_ok());
+        assert!(PushBackendConfig::check_supported_type("unknown").is_err());
+    }
 }
@@ -1,106 +1,375 @@
 mod error;
-pub use error::{PushRequestError, PushRequestResult};
+pub use error::{PushModuleError, PushRequestError, PushRequestResult};
+mod error_policy;
+pub use error_policy::{
+    ErrorPolicy, ErrorReply, StanzaErrorCondition, StanzaErrorReply, StanzaErrorType,
+};
+mod filter;
+pub use filter::{FieldCondition, FilterAction, FilterRule, SummaryFieldMatch};
 mod fpush_config;
 pub use fpush_config::FpushPushConfig;
-pub use fpush_config::PushConfig;
+pub use fpush_config::{PushBackendConfig, PushConfig, SecretSettings};
 
 mod push_handler;
 pub use push_handler::handle_push_request;
 mod push_module;
+mod queue;
+pub use queue::{PushQueue, PushQueueSettings};
+mod retry;
+pub use retry::{BackgroundRetries, RetryMode, RetryPolicy};
+mod secret;
 
 use dashmap::DashMap;
-use push_module::{PushModule, PushModuleEnum, PushModuleMapArc};
-use std::sync::Arc;
+use error::Result;
+use push_module::{PushModule, PushModuleEnum, PushModuleMapArc, TokenState};
+use std::{collections::HashMap, sync::Arc};
+use tokio::{sync::Mutex, task::JoinHandle};
 
-use log::{debug, info};
+use log::{debug, error, info};
+
+pub use fpush_traits::request::{PushPriority, PushRequest};
+pub use fpush_traits::secret::SecretValue;
+pub use fpush_traits::summary::PushSummary;
 
 pub type FpushPushArc = Arc<FpushPush>;
 
 pub struct FpushPush {
     push_modules: PushModuleMapArc,
+    /// map of alias to push module identifier
+    module_aliases: DashMap<String, String>,
+    /// configuration of the loaded push modules, compared against on reload
+    module_config: Mutex<FpushPushConfig>,
+    /// push requests that failed transiently, if a push queue is configured
+    queue: Option<Arc<PushQueue>>,
+    queue_replay: Option<JoinHandle<()>>,
+    background_retries: Arc<BackgroundRetries>,
+}
+
+/// Change of a single entry of the push module map on reload
+enum ModuleChange {
+    Add(PushModuleEnum),
+    /// replace the push module, keeping the blocklist and ratelimit of its tokens
+    Replace(PushModuleEnum, TokenState),
+    /// apply changed blocklist and ratelimit settings to the loaded push module
+    UpdateSettings(TokenState),
 }
 
 impl FpushPush {
-    pub async fn new(module_config: &FpushPushConfig) -> Self {
+    pub async fn new(
+        module_config: &FpushPushConfig,
+        queue_settings: Option<&PushQueueSettings>,
+    ) -> std::result::Result<Self, PushModuleError> {
+        module_config.validate().map_err(PushModuleError::Config)?;
+        let queue = match queue_settings {
+            Some(queue_settings) => Some(Arc::new(
+                PushQueue::open(queue_settings).map_err(PushModuleError::Queue)?,
+            )),
+            None => None,
+        };
         let mut a = Self {
             push_modules: Arc::new(DashMap::default()),
+            module_aliases: DashMap::default(),
+            module_config: Mutex::new(module_config.clone()),
+            queue,
+            queue_replay: None,
+            background_retries: Arc::new(BackgroundRetries::default()),
         };
-        a.load_push_modules(module_config).await;
-        a
+        a.load_push_modules(module_config).await?;
+        a.queue_replay = a
+            .queue
+            .as_ref()
+            .map(|queue| queue.spawn_replay(a.push_modules.clone()));
+        Ok(a)
     }
 
-    async fn load_push_modules(&mut self, module_config: &FpushPushConfig) {
-        let mut default_counter = 0;
-        for (push_module_id, module_config) in module_config.config() {
-            let (is_default_module, push_module) =
-                Self::init_push_module(push_module_id.clone(), module_config).await;
-            self.push_modules
-                .insert(push_module_id.to_string(), push_module);
-            if is_default_module {
-                default_counter += 1;
+    async fn load_push_modules(
+        &self,
+        module_config: &FpushPushConfig,
+    ) -> std::result::Result<(), PushModuleError> {
+        for (entry_id, push_module_id, push_config) in module_config.module_entries() {
+            if entry_id == "default" {
                 info!("Loading {} as default push module", push_module_id);
-                let (_, push_module) =
-                    Self::init_push_module(push_module_id.clone(), module_config).await;
-                self.push_modules.insert("default".to_string(), push_module);
             }
+            let push_module = Self::init_push_module(
+                push_module_id.clone(),
+                push_config,
+                TokenState::new(push_config),
+            )
+            .await?;
+            self.push_modules.insert(entry_id, Arc::new(push_module));
         }
-        if default_counter > 1 {
-            panic!("At most one push module can be defined as the default module");
+        for (alias, push_module_id) in module_config.alias_map().map_err(PushModuleError::Config)? {
+            self.module_aliases.insert(alias, push_module_id);
         }
+        Ok(())
     }
 
     /// Load and init push module using the provided configuration
-    /// Return true if the push module is the default push module
-    async fn init_push_module(key: String, module_config: &PushConfig) -> (bool, PushModuleEnum) {
-        match module_config {
+    async fn init_push_module(
+        key: String,
+        module_config: &PushConfig,
+        token_state: TokenState,
+    ) -> std::result::Result<PushModuleEnum, PushModuleError> {
+        Self::init_push_backend(key.clone(), module_config, token_state)
+            .await
+            .map_err(|e| PushModuleError::Init(key, e.to_string()))
+    }
+
+    async fn init_push_backend(
+        key: String,
+        module_config: &PushConfig,
+        token_state: TokenState,
+    ) -> Result<PushModuleEnum> {
+        let push_module = match module_config.backend() {
             #[cfg(feature = "enable_apns_support")]
-            PushConfig::Apple {
-                apns,
-                blacklist,
-                ratelimit,
-                is_default_module,
-            } => {
+            PushBackendConfig::Apple { apns } => {
                 let apple_push_module =
-                    PushModule::new_apple_module(key.clone(), apns, blacklist, ratelimit).unwrap();
-                (*is_default_module, PushModuleEnum::Apple(apple_push_module))
+                    PushModule::new_apple_module(key, apns, module_config, token_state)?;
+                PushModuleEnum::Apple(apple_push_module)
             }
             #[cfg(feature = "enable_fcm_support")]
-            PushConfig::Google {
-                fcm,
-                blacklist,
-                ratelimit,
-                is_default_module,
-            } => {
+            PushBackendConfig::Google { fcm } => {
                 let google_fcm_push_module =
-                    PushModule::new_fcm_module(key.clone(), fcm, blacklist, ratelimit)
-                        .await
-                        .unwrap();
-                (
-                    *is_default_module,
-                    PushModuleEnum::Google(google_fcm_push_module),
-                )
+                    PushModule::new_fcm_module(key, fcm, module_config, token_state).await?;
+                PushModuleEnum::Google(google_fcm_push_module)
             }
             #[cfg(feature = "enable_demo_support")]
-            PushConfig::Demo {
-                blacklist,
-                ratelimit,
-                is_default_module,
-            } => {
-                let demo = PushModule::new_demo_module(key.clone(), blacklist, ratelimit)
+            PushBackendConfig::Demo {} => {
+                let demo = PushModule::new_demo_module(key, module_config, token_state).await?;
+                PushModuleEnum::Demo(demo)
+            }
+        };
+        Ok(push_module)
+    }
+
+    /// Apply a changed push module configuration
+    ///
+    /// Only push modules whose configuration changed are touched. If any push module can not be
+    /// loaded, the previous configuration stays active.
+    pub async fn reload(&self, new_config: &FpushPushConfig) {
+        let mut current_config = self.module_config.lock().await;
+        if let Err(e) = new_config.validate() {
+            error!("Not reloading push modules: {}", e);
+            return;
+        }
+        let current_entries: HashMap<String, (&String, &PushConfig)> = current_config
+            .module_entries()
+            .into_iter()
+            .map(|(entry_id, push_module_id, push_config)| {
+                (entry_id, (push_module_id, push_config))
+            })
+            .collect();
+        let new_entries = new_config.module_entries();
+
+        // load all new push modules before changing anything
+        let mut changes = Vec::new();
+        for (entry_id, push_module_id, push_config) in &new_entries {
+            let loaded_module = self
+                .push_modules
+                .get(entry_id)
+                .map(|entry| entry.value().clone());
+            let change = match (current_entries.get(entry_id), loaded_module) {
+                (Some((current_module_id, current_push_config)), Some(loaded_module))
+                    if current_module_id == push_module_id =>
+                {
+                    let mut changed_settings = current_push_config.changed_settings(push_config);
+                    if !changed_settings.is_empty() {
+                        info!("{}: Changed {}", entry_id, changed_settings.join(", "));
+                    } else if push_config.has_secret_references() {
+                        // referenced secret files and environment variables may have changed
+                        info!("{}: Reading referenced secrets again", entry_id);
+                        changed_settings.push("secrets");
+                    } else {
+                        continue;
+                    }
+                    if current_push_config.ratelimit() != push_config.ratelimit() {
+                        info!(
+                            "{}: ratelimit {:?} -> {:?}",
+                            entry_id,
+                            current_push_config.ratelimit(),
+                            push_config.ratelimit()
+                        );
+                    }
+                    if current_push_config.blacklist() != push_config.blacklist() {
+                        info!(
+                            "{}: blacklist {:?} -> {:?}",
+                            entry_id,
+                            current_push_config.blacklist(),
+                            push_config.blacklist()
+                        );
+                    }
+                    let token_state = TokenState::of(&loaded_module);
+                    if changed_settings
+                        .iter()
+                        .all(|setting| *setting == "ratelimit" || *setting == "blacklist")
+                    {
+                        ModuleChange::UpdateSettings(token_state)
+                    } else {
+                        match Self::init_push_module(
+                            push_module_id.to_string(),
+                            push_config,
+                            token_state.clone(),
+                        )
+                        .await
+                        {
+                            Ok(push_module) => ModuleChange::Replace(push_module, token_state),
+                            Err(e) => {
+                                error!("Not reloading push modules: {}", e);
+                                return;
+                            }
+                        }
+                    }
+                }
+                _ => {
+                    info!("{}: Adding push module {}", entry_id, push_module_id);
+                    match Self::init_push_module(
+                        push_module_id.to_string(),
+                        push_config,
+                        TokenState::new(push_config),
+                    )
                     .await
-                    .unwrap();
-                (*is_default_module, PushModuleEnum::Demo(demo))
+                    {
+                        Ok(push_module) => ModuleChange::Add(push_module),
+                        Err(e) => {
+                            error!("Not reloading push modules: {}", e);
+                            return;
+                        }
+                    }
+                }
+            };
+            changes.push((entry_id.to_string(), *push_config, change));
+        }
+
+        for (entry_id, push_config, change) in changes {
+            match change {
+                ModuleChange::Add(push_module) => {
+                    self.push_modules.insert(entry_id, Arc::new(push_module));
+                }
+                ModuleChange::Replace(push_module, token_state) => {
+                    token_state.update_settings(push_config);
+                    self.push_modules.insert(entry_id, Arc::new(push_module));
+                }
+                ModuleChange::UpdateSettings(token_state) => {
+                    token_state.update_settings(push_config);
+                }
+            }
+        }
+        let removed_entries: Vec<String> = self
+            .push_modules
+            .iter()
+            .map(|entry| entry.key().to_string())
+            .filter(|entry_id| !new_entries.iter().any(|(new_id, _, _)| new_id == entry_id))
+            .collect();
+        for entry_id in removed_entries {
+            info!("{}: Removing push module", entry_id);
+            self.push_modules.remove(&entry_id);
+        }
+        if let Ok(aliases) = new_config.alias_map() {
+            self.module_aliases
+                .retain(|alias, _| aliases.contains_key(alias));
+            for (alias, push_module_id) in aliases {
+                self.module_aliases.insert(alias, push_module_id);
             }
         }
+
+        *current_config = new_config.clone();
+        info!("Reloaded push modules");
+    }
+
+    /// Return the identifiers of all loaded push modules, excluding the default alias
+    pub fn push_module_ids(&self) -> Vec<String> {
+        let mut module_ids: Vec<String> = self
+            .push_modules
+            .iter()
+            .map(|entry| entry.key().to_string())
+            .filter(|module_id| module_id != "default")
+            .collect();
+        module_ids.sort();
+        module_ids
+    }
+
+    /// Return the error policy of the push module
+    ///
+    /// Unknown push modules use the policy of the default push module, if any.
+    pub fn error_policy(&self, module_id: &str) -> ErrorPolicy {
+        self.push_modules
+            .get(module_id)
+            .or_else(|| self.push_modules.get("default"))
+            .map(|push_module| push_module.error_policy().clone())
+            .unwrap_or_default()
     }
 
+    /// Return the identifier of the push module configured with `alias`
     #[inline(always)]
-    pub async fn push(&self, module_id: &str, token: String) -> PushRequestResult<()> {
-        if let Some(push_module) = self.push_modules.get(module_id) {
-            handle_push_request(push_module.value(), token).await
+    pub fn push_module_for_alias(&self, alias: &str) -> Option<String> {
+        self.module_aliases
+            .get(alias)
+            .map(|module_id| module_id.value().to_string())
+    }
+
+    #[inline(always)]
+    pub fn has_push_module(&self, module_id: &str) -> bool {
+        self.push_modules.contains_key(module_id)
+    }
+
+    #[inline(always)]
+    pub async fn push(&self, module_id: &str, request: &PushRequest) -> PushRequestResult<()> {
+        self.push_with_retry_mode(module_id, request, None).await
+    }
+
+    /// Send a push request and reply after the last retry, if any
+    ///
+    /// Unlike [`FpushPush::push`] this never retries in background, even if the retry policy of
+    /// the push module uses the background mode, so transient errors are always returned.
+    pub async fn push_blocking(
+        &self,
+        module_id: &str,
+        request: &PushRequest,
+    ) -> PushRequestResult<()> {
+        self.push_with_retry_mode(module_id, request, Some(RetryMode::Blocking))
+            .await
+    }
+
+    async fn push_with_retry_mode(
+        &self,
+        module_id: &str,
+        request: &PushRequest,
+        retry_mode: Option<RetryMode>,
+    ) -> PushRequestResult<()> {
+        // do not hold the map lock while the push is sent
+        let push_module = self
+            .push_modules
+            .get(module_id)
+            .map(|entry| entry.value().clone());
+        if let Some(push_module) = push_module {
+            handle_push_request(
+                &push_module,
+                request,
+                self.queue.as_ref(),
+                &self.background_retries,
+                retry_mode,
+            )
+            .await
         } else {
             debug!("Unkown push_module requested: {}", module_id);
             Err(PushRequestError::UnkownPushModule)
         }
     }
+
+    /// Unload all push modules and stop their background tasks
+    ///
+    /// Waits until the push queue, if any, is written to disk.
+    pub async fn shutdown(&self) {
+        if let Some(queue_replay) = &self.queue_replay {
+            queue_replay.abort();
+        }
+        self.background_retries.shutdown(self.queue.as_deref());
+        if let Some(queue) = &self.queue {
+            queue.compact();
+            queue.flush().await;
+        }
+        let module_ids = self.push_module_ids();
+        self.push_modules.clear();
+        info!("Unloaded push modules {:?}", module_ids);
+    }
 }
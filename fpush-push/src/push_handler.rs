@@ -1,59 +1,87 @@
+use std::sync::Arc;
+
 use crate::error::{PushRequestError, PushRequestResult};
 
 use crate::push_module::PushModuleEnum;
-use fpush_traits::push::PushError;
+use crate::queue::PushQueue;
+use crate::retry::{BackgroundRetries, RetryMode};
+use fpush_tokenblocker::BlockReason;
+use fpush_traits::push::{PushError, PushResult};
+use fpush_traits::request::PushRequest;
 
-use log::{info, warn};
+use log::{debug, info, warn};
+use tokio::time::Instant;
 
+/// Send a push request using `push_module`
+///
+/// `retry_mode` overrides the retry mode of the retry policy of the push module, if set.
 #[inline(always)]
 pub async fn handle_push_request(
-    push_module: &PushModuleEnum,
-    token: String,
+    push_module: &Arc<PushModuleEnum>,
+    request: &PushRequest,
+    queue: Option<&Arc<PushQueue>>,
+    background_retries: &Arc<BackgroundRetries>,
+    retry_mode: Option<RetryMode>,
 ) -> PushRequestResult<()> {
-    if push_module.blocklist().is_blocked(&token) {
-        return Err(PushRequestError::TokenBlocked);
+    let token = request.token().to_string();
+    if let Some(secret_validator) = push_module.secret_validator() {
+        if !request.secret_verified()
+            && !secret_validator.verify(&token, request.publish_option("secret"))
+        {
+            return Err(PushRequestError::NotAuthorized);
+        }
     }
+    if push_module.filter().is_filtered(request) {
+        return Err(PushRequestError::Filtered);
+    }
+    check_blocklist(push_module, &token)?;
     if push_module
         .ratelimit()
         .lookup_ratelimit(token.to_string())
         .await
     {
-        match push_module.send(token.to_string()).await {
-            Ok(()) => {
-                info!(
-                    "{}: Send push message to token {}",
-                    push_module.identifier(),
-                    token
-                );
-                Ok(())
-            }
-            Err(PushError::TokenBlocked) => {
-                info!(
-                    "{}: Received push request from blocked token {}",
-                    push_module.identifier(),
-                    token,
-                );
-                push_module.blocklist().block_invalid_token(token);
-                Err(PushRequestError::TokenBlocked)
-            }
-            Err(PushError::TokenRateLimited) => {
-                push_module.ratelimit().hard_ratelimit(token.to_string());
-                Err(PushRequestError::TokenRatelimited)
-            }
-            Err(PushError::PushEndpointTmp) => Err(PushRequestError::Internal),
-            Err(PushError::PushEndpointPersistent) => Err(PushRequestError::Internal),
-            Err(e) => {
-                warn!(
-                    "{}: Blocking token {} due to error: {}",
-                    push_module.identifier(),
-                    token,
-                    e
-                );
-                push_module
-                    .blocklist()
-                    .block_after_unhandled_push_error(token);
-                Err(PushRequestError::Internal)
+        let first_attempt = Instant::now();
+        match push_module.send(request).await {
+            Err(PushError::PushEndpointTmp) if push_module.retry_policy().is_enabled() => {
+                match retry_mode.unwrap_or(*push_module.retry_policy().mode()) {
+                    RetryMode::Blocking => {
+                        retry_push(push_module, request, first_attempt, queue).await
+                    }
+                    RetryMode::Background => {
+                        let retry = {
+                            let push_module = push_module.clone();
+                            let request = request.clone();
+                            let queue = queue.cloned();
+                            async move {
+                                // the push request was already acknowledged
+                                let _ = retry_push(
+                                    &push_module,
+                                    &request,
+                                    first_attempt,
+                                    queue.as_ref(),
+                                )
+                                .await;
+                            }
+                        };
+                        if background_retries.spawn(push_module.identifier(), request, retry) {
+                            info!(
+                                "{}: Retrying push for token {} in background",
+                                push_module.identifier(),
+                                token
+                            );
+                            Ok(())
+                        } else {
+                            warn!(
+                                "{}: Too many push requests retried in background",
+                                push_module.identifier()
+                            );
+                            give_up_push(push_module, request, queue)
+                        }
+                    }
+                }
             }
+            Err(PushError::PushEndpointTmp) => give_up_push(push_module, request, queue),
+            result => handle_push_result(push_module, &token, result),
         }
     } else {
         info!(
@@ -64,3 +92,151 @@ pub async fn handle_push_request(
         Err(PushRequestError::TokenRatelimited)
     }
 }
+
+fn check_blocklist(push_module: &PushModuleEnum, token: &str) -> PushRequestResult<()> {
+    match push_module.blocklist().blocked_reason(token) {
+        Some(BlockReason::InvalidToken) => Err(PushRequestError::TokenInvalid),
+        Some(BlockReason::PushError) => Err(PushRequestError::TokenBlocked),
+        None => Ok(()),
+    }
+}
+
+/// Retry a push request that failed with a transient error of the push service
+///
+/// Each retry checks the blocklist again and waits while the token ratelimit suppresses pushes to
+/// the token, without using up another ratelimit slot. No retry is started or awaited after the
+/// deadline of the retry policy.
+async fn retry_push(
+    push_module: &PushModuleEnum,
+    request: &PushRequest,
+    first_attempt: Instant,
+    queue: Option<&Arc<PushQueue>>,
+) -> PushRequestResult<()> {
+    let retry_policy = push_module.retry_policy();
+    let token = request.token();
+    let deadline = first_attempt + *retry_policy.deadline();
+    for attempt in 2..=*retry_policy.max_attempts() {
+        check_blocklist(push_module, token)?;
+        let delay = match retry_policy.retry_delay(
+            attempt - 1,
+            push_module.ratelimit().suppressed_for(token),
+            deadline.saturating_duration_since(Instant::now()),
+        ) {
+            Some(delay) => delay,
+            None => break,
+        };
+        tokio::time::sleep(delay).await;
+        check_blocklist(push_module, token)?;
+
+        debug!(
+            "{}: Retrying push for token {}, attempt {} of {}",
+            push_module.identifier(),
+            token,
+            attempt,
+            retry_policy.max_attempts()
+        );
+        match tokio::time::timeout_at(deadline, push_module.send(request)).await {
+            Ok(Err(PushError::PushEndpointTmp)) => {}
+            Ok(result) => return handle_push_result(push_module, token, result),
+            Err(_) => break,
+        }
+    }
+    give_up_push(push_module, request, queue)
+}
+
+/// Queue a push request that failed transiently for a later replay, if a queue is configured
+fn give_up_push(
+    push_module: &PushModuleEnum,
+    request: &PushRequest,
+    queue: Option<&Arc<PushQueue>>,
+) -> PushRequestResult<()> {
+    let token = request.token();
+    match queue {
+        Some(queue) => {
+            info!(
+                "{}: Queueing push for token {} after transient errors",
+                push_module.identifier(),
+                token
+            );
+            queue.enqueue(push_module.identifier(), request);
+            Err(PushRequestError::Queued)
+        }
+        None => {
+            warn!(
+                "{}: Giving up push for token {} after transient errors",
+                push_module.identifier(),
+                token
+            );
+            Err(PushRequestError::Internal)
+        }
+    }
+}
+
+/// Send a queued push request again
+///
+/// Returns `None` if the push service still fails transiently. The secret and the filter rules
+/// were already checked when the push request was queued. Push requests are not delayed by the
+/// token ratelimit, but rejected if a push to the token was sent within the `ratelimitTime`.
+pub(crate) async fn replay_push(
+    push_module: &PushModuleEnum,
+    request: &PushRequest,
+) -> Option<PushRequestResult<()>> {
+    let token = request.token();
+    if let Err(e) = check_blocklist(push_module, token) {
+        return Some(Err(e));
+    }
+    if !push_module.ratelimit().try_lookup_ratelimit(token) {
+        return Some(Err(PushRequestError::TokenRatelimited));
+    }
+    match push_module.send(request).await {
+        Err(PushError::PushEndpointTmp) => None,
+        result => Some(handle_push_result(push_module, token, result)),
+    }
+}
+
+/// Map the result of the push service and update the blocklist and ratelimit of the token
+fn handle_push_result(
+    push_module: &PushModuleEnum,
+    token: &str,
+    result: PushResult<()>,
+) -> PushRequestResult<()> {
+    match result {
+        Ok(()) => {
+            info!(
+                "{}: Send push message to token {}",
+                push_module.identifier(),
+                token
+            );
+            Ok(())
+        }
+        Err(PushError::TokenBlocked) => {
+            info!(
+                "{}: Push service reported token {} as invalid",
+                push_module.identifier(),
+                token,
+            );
+            push_module
+                .blocklist()
+                .block_invalid_token(token.to_string());
+            Err(PushRequestError::TokenInvalid)
+        }
+        Err(PushError::TokenRateLimited) => {
+            push_module.ratelimit().hard_ratelimit(token.to_string());
+            Err(PushRequestError::TokenRatelimited)
+        }
+        Err(PushError::PushEndpointTmp) => Err(PushRequestError::Internal),
+        Err(PushError::PushEndpointPersistent) => Err(PushRequestError::Internal),
+        Err(e) => {
+            warn!(
+                "{}: Blocking token {} due to error: {}",
+                push_module.identifier(),
+                token,
+                e
+            );
+            push_module
+                .blocklist()
+                .block_after_unhandled_push_error(token.to_string());
+            Err(PushRequestError::Internal)
+        }
+    }
+}
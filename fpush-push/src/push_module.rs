@@ -1,16 +1,22 @@
 use std::sync::Arc;
 
 use crate::error::Result;
-use fpush_ratelimit::{FpushTokenRateLimit, RatelimitSettings};
-use fpush_tokenblocker::BlacklistSettings;
+use crate::error_policy::ErrorPolicy;
+use crate::filter::PushFilter;
+use crate::fpush_config::PushConfig;
+use crate::retry::RetryPolicy;
+use crate::secret::PushSecretValidator;
+use fpush_ratelimit::FpushTokenRateLimit;
 use fpush_tokenblocker::FpushBlocklist;
 
-use fpush_traits::push::PushResult;
+use fpush_traits::push::{PushError, PushResult};
+use fpush_traits::request::PushRequest;
 
 use dashmap::DashMap;
 use fpush_traits::push::PushTrait;
+use tokio::task::JoinHandle;
 
-pub type PushModuleMapArc = Arc<DashMap<String, PushModuleEnum>>;
+pub type PushModuleMapArc = Arc<DashMap<String, Arc<PushModuleEnum>>>;
 
 pub enum PushModuleEnum {
     #[cfg(feature = "enable_apns_support")]
@@ -24,14 +30,14 @@ pub enum PushModuleEnum {
 impl PushModuleEnum {
     /// dispatch
     #[inline(always)]
-    pub async fn send(&self, token: String) -> PushResult<()> {
+    pub async fn send(&self, request: &PushRequest) -> PushResult<()> {
         match self {
             #[cfg(feature = "enable_apns_support")]
-            PushModuleEnum::Apple(push_module) => push_module.send(token).await,
+            PushModuleEnum::Apple(push_module) => push_module.send(request).await,
             #[cfg(feature = "enable_fcm_support")]
-            PushModuleEnum::Google(push_module) => push_module.send(token).await,
+            PushModuleEnum::Google(push_module) => push_module.send(request).await,
             #[cfg(feature = "enable_demo_support")]
-            PushModuleEnum::Demo(push_module) => push_module.send(token).await,
+            PushModuleEnum::Demo(push_module) => push_module.send(request).await,
         }
     }
 
@@ -59,6 +65,54 @@ impl PushModuleEnum {
         }
     }
 
+    #[inline(always)]
+    pub fn secret_validator(&self) -> Option<&PushSecretValidator> {
+        match self {
+            #[cfg(feature = "enable_apns_support")]
+            PushModuleEnum::Apple(push_module) => push_module.secret_validator(),
+            #[cfg(feature = "enable_fcm_support")]
+            PushModuleEnum::Google(push_module) => push_module.secret_validator(),
+            #[cfg(feature = "enable_demo_support")]
+            PushModuleEnum::Demo(push_module) => push_module.secret_validator(),
+        }
+    }
+
+    #[inline(always)]
+    pub fn filter(&self) -> &PushFilter {
+        match self {
+            #[cfg(feature = "enable_apns_support")]
+            PushModuleEnum::Apple(push_module) => push_module.filter(),
+            #[cfg(feature = "enable_fcm_support")]
+            PushModuleEnum::Google(push_module) => push_module.filter(),
+            #[cfg(feature = "enable_demo_support")]
+            PushModuleEnum::Demo(push_module) => push_module.filter(),
+        }
+    }
+
+    #[inline(always)]
+    pub fn error_policy(&self) -> &ErrorPolicy {
+        match self {
+            #[cfg(feature = "enable_apns_support")]
+            PushModuleEnum::Apple(push_module) => push_module.error_policy(),
+            #[cfg(feature = "enable_fcm_support")]
+            PushModuleEnum::Google(push_module) => push_module.error_policy(),
+            #[cfg(feature = "enable_demo_support")]
+            PushModuleEnum::Demo(push_module) => push_module.error_policy(),
+        }
+    }
+
+    #[inline(always)]
+    pub fn retry_policy(&self) -> &RetryPolicy {
+        match self {
+            #[cfg(feature = "enable_apns_support")]
+            PushModuleEnum::Apple(push_module) => push_module.retry_policy(),
+            #[cfg(feature = "enable_fcm_support")]
+            PushModuleEnum::Google(push_module) => push_module.retry_policy(),
+            #[cfg(feature = "enable_demo_support")]
+            PushModuleEnum::Demo(push_module) => push_module.retry_policy(),
+        }
+    }
+
     #[inline(always)]
     pub fn identifier(&self) -> &str {
         match self {
@@ -72,14 +126,52 @@ impl PushModuleEnum {
     }
 }
 
+/// Blocklist and ratelimit of the tokens of a push module
+///
+/// Kept when the push module is replaced on reload, so blocked and ratelimited tokens stay so.
+#[derive(Clone)]
+pub(crate) struct TokenState {
+    blocklist: Arc<FpushBlocklist>,
+    token_ratelimit: Arc<FpushTokenRateLimit>,
+}
+
+impl TokenState {
+    pub(crate) fn new(module_config: &PushConfig) -> Self {
+        Self {
+            blocklist: Arc::new(FpushBlocklist::new(module_config.blacklist())),
+            token_ratelimit: Arc::new(FpushTokenRateLimit::new(module_config.ratelimit())),
+        }
+    }
+
+    /// Return the state of an already loaded push module
+    pub(crate) fn of(push_module: &PushModuleEnum) -> Self {
+        Self {
+            blocklist: push_module.blocklist().clone(),
+            token_ratelimit: push_module.ratelimit().clone(),
+        }
+    }
+
+    /// Apply changed blocklist and ratelimit settings without losing any token
+    pub(crate) fn update_settings(&self, module_config: &PushConfig) {
+        self.blocklist.update_settings(module_config.blacklist());
+        self.token_ratelimit
+            .update_settings(module_config.ratelimit());
+    }
+}
+
 pub struct PushModule<T>
 where
     T: PushTrait,
 {
     blocklist: Arc<FpushBlocklist>,
     token_ratelimit: Arc<FpushTokenRateLimit>,
+    secret_validator: Option<PushSecretValidator>,
+    error_policy: ErrorPolicy,
+    retry_policy: RetryPolicy,
+    filter: Arc<PushFilter>,
     push: Arc<T>,
     identifier: String,
+    cleanup_tasks: Vec<JoinHandle<()>>,
 }
 
 #[cfg(feature = "enable_apns_support")]
@@ -87,16 +179,11 @@ impl PushModule<fpush_apns::FpushApns> {
     pub(crate) fn new_apple_module(
         identifier: String,
         apns_conf: &fpush_apns::AppleApnsConfig,
-        blocklist_config: &BlacklistSettings,
-        ratelimit_config: &RatelimitSettings,
+        module_config: &PushConfig,
+        token_state: TokenState,
     ) -> Result<PushModule<fpush_apns::FpushApns>> {
         let apple_push = fpush_apns::FpushApns::init(apns_conf)?;
-        Self::new(
-            identifier,
-            blocklist_config,
-            ratelimit_config,
-            Arc::new(apple_push),
-        )
+        Self::new(identifier, module_config, token_state, Arc::new(apple_push))
     }
 }
 
@@ -105,16 +192,11 @@ impl PushModule<fpush_fcm::FpushFcm> {
     pub(crate) async fn new_fcm_module(
         identifier: String,
         fcm_conf: &fpush_fcm::GoogleFcmConfig,
-        blocklist_config: &BlacklistSettings,
-        ratelimit_config: &RatelimitSettings,
+        module_config: &PushConfig,
+        token_state: TokenState,
     ) -> Result<PushModule<fpush_fcm::FpushFcm>> {
         let fcm_push = fpush_fcm::FpushFcm::init(fcm_conf).await?;
-        Self::new(
-            identifier,
-            blocklist_config,
-            ratelimit_config,
-            Arc::new(fcm_push),
-        )
+        Self::new(identifier, module_config, token_state, Arc::new(fcm_push))
     }
 }
 
@@ -122,14 +204,14 @@ impl PushModule<fpush_fcm::FpushFcm> {
 impl PushModule<fpush_demopush::FpushDemoPush> {
     pub(crate) async fn new_demo_module(
         identifier: String,
-        blocklist_config: &BlacklistSettings,
-        ratelimit_config: &RatelimitSettings,
+        module_config: &PushConfig,
+        token_state: TokenState,
     ) -> Result<PushModule<fpush_demopush::FpushDemoPush>> {
         let demo_module = fpush_demopush::FpushDemoPush::init()?;
         Self::new(
             identifier,
-            blocklist_config,
-            ratelimit_config,
+            module_config,
+            token_state,
             Arc::new(demo_module),
         )
     }
@@ -142,33 +224,44 @@ where
     /// Create new push module of type <T>
     pub(crate) fn new(
         identifier: String,
-        blocklist_config: &BlacklistSettings,
-        ratelimit_config: &RatelimitSettings,
+        module_config: &PushConfig,
+        token_state: TokenState,
         push: Arc<T>,
     ) -> Result<Self> {
-        let blocklist = fpush_tokenblocker::FpushBlocklist::new(blocklist_config);
-
-        let token_ratelimit = fpush_ratelimit::FpushTokenRateLimit::new(ratelimit_config);
-
-        let module = Self {
-            blocklist: Arc::new(blocklist),
-            token_ratelimit: Arc::new(token_ratelimit),
+        let secret_validator = match module_config.secret() {
+            Some(secret_settings) => Some(
+                PushSecretValidator::new(secret_settings)
+                    .map_err(|e| PushError::Config(format!("secret: {}", e)))?,
+            ),
+            None => None,
+        };
+        let mut module = Self {
+            blocklist: token_state.blocklist,
+            token_ratelimit: token_state.token_ratelimit,
+            secret_validator,
+            error_policy: module_config.error_policy().clone(),
+            retry_policy: module_config.retry().clone(),
+            filter: Arc::new(PushFilter::new(module_config.filters())),
             push,
             identifier,
+            cleanup_tasks: Vec::with_capacity(3),
         };
-        module.spawn_blocklist_cleanup();
-        module.spawn_token_cleanup();
+        module.cleanup_tasks = vec![
+            module.spawn_blocklist_cleanup(),
+            module.spawn_token_cleanup(),
+            module.spawn_filter_report(),
+        ];
 
         Ok(module)
     }
 
     /// trigger push event got provided token
     #[inline(always)]
-    async fn send(&self, token: String) -> PushResult<()> {
-        self.push.send(token).await
+    async fn send(&self, request: &PushRequest) -> PushResult<()> {
+        self.push.send(request).await
     }
 
-    fn spawn_blocklist_cleanup(&self) {
+    fn spawn_blocklist_cleanup(&self) -> JoinHandle<()> {
         let blocklist = self.blocklist.clone();
         tokio::spawn(async move {
             let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(60));
@@ -176,10 +269,10 @@ where
                 interval.tick().await;
                 blocklist.cleanup();
             }
-        });
+        })
     }
 
-    fn spawn_token_cleanup(&self) {
+    fn spawn_token_cleanup(&self) -> JoinHandle<()> {
         let token_ratelimit = self.token_ratelimit.clone();
         tokio::spawn(async move {
             let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(300));
@@ -187,7 +280,19 @@ where
                 interval.tick().await;
                 token_ratelimit.cleanup();
             }
-        });
+        })
+    }
+
+    fn spawn_filter_report(&self) -> JoinHandle<()> {
+        let filter = self.filter.clone();
+        let identifier = self.identifier.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(300));
+            loop {
+                interval.tick().await;
+                filter.report(&identifier);
+            }
+        })
     }
 
     #[inline(always)]
@@ -200,8 +305,40 @@ where
         &self.token_ratelimit
     }
 
+    #[inline(always)]
+    pub fn secret_validator(&self) -> Option<&PushSecretValidator> {
+        self.secret_validator.as_ref()
+    }
+
+    #[inline(always)]
+    pub fn filter(&self) -> &PushFilter {
+        &self.filter
+    }
+
+    #[inline(always)]
+    pub fn error_policy(&self) -> &ErrorPolicy {
+        &self.error_policy
+    }
+
+    #[inline(always)]
+    pub fn retry_policy(&self) -> &RetryPolicy {
+        &self.retry_policy
+    }
+
     #[inline(always)]
     pub fn identifier(&self) -> &str {
         &self.identifier
     }
 }
+
+impl<T> Drop for PushModule<T>
+where
+    T: PushTrait,
+{
+    /// stop the cleanup tasks together with the module
+    fn drop(&mut self) {
+        for cleanup_task in &self.cleanup_tasks {
+            cleanup_task.abort();
+        }
+    }
+}
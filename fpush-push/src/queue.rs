@@ -0,0 +1,620 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufRead, BufWriter, Write},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc, Mutex,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::push_handler::replay_push;
+use crate::push_module::PushModuleMapArc;
+use fpush_traits::request::{PushPriority, PushRequest};
+use fpush_traits::summary::PushSummary;
+
+use derive_getters::Getters;
+use futures::StreamExt;
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::{sync::oneshot, task::JoinHandle};
+
+/// Number of queued push requests of a push module replayed at the same time
+const REPLAY_CONCURRENCY: usize = 32;
+
+/// Persistent queue of push requests that failed due to an outage of the push service
+#[derive(Debug, Deserialize, Clone, Getters)]
+#[serde(rename_all = "camelCase")]
+pub struct PushQueueSettings {
+    /// file the queue is stored in, created if missing
+    path: PathBuf,
+    /// queued push requests older than this are dropped
+    #[serde(default = "default_max_age", deserialize_with = "serde_humantime")]
+    max_age: Duration,
+    /// maximal number of queued push requests, the oldest are dropped first
+    #[serde(default = "default_max_entries")]
+    max_entries: usize,
+    #[serde(
+        default = "default_replay_interval",
+        deserialize_with = "serde_humantime"
+    )]
+    replay_interval: Duration,
+}
+
+impl PushQueueSettings {
+    /// Check settings serde can not check on its own
+    pub fn validate(&self) -> Result<(), String> {
+        if self.max_entries == 0 {
+            return Err("maxEntries has to be at least 1".to_string());
+        }
+        Ok(())
+    }
+}
+
+fn default_max_age() -> Duration {
+    Duration::from_secs(3600)
+}
+
+fn default_max_entries() -> usize {
+    10000
+}
+
+fn default_replay_interval() -> Duration {
+    Duration::from_secs(30)
+}
+
+/// Single line of the queue file
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct QueuedPush {
+    module_id: String,
+    token: String,
+    /// seconds since the unix epoch the push request was queued at
+    queued_at: u64,
+    // missing in queue files of older versions
+    #[serde(default)]
+    priority: PushPriority,
+    #[serde(default)]
+    summary: PushSummary,
+}
+
+impl QueuedPush {
+    fn new(key: &(String, String), entry: &QueueEntry) -> Self {
+        Self {
+            module_id: key.0.to_string(),
+            token: key.1.to_string(),
+            queued_at: entry.queued_at,
+            priority: entry.priority,
+            summary: entry.summary.clone(),
+        }
+    }
+}
+
+/// Queued push request of a single token
+#[derive(Debug, Clone)]
+struct QueueEntry {
+    queued_at: u64,
+    /// distinguishes entries of a token queued again within the same second, not persisted
+    seq: u64,
+    priority: PushPriority,
+    summary: PushSummary,
+}
+
+/// Queued push requests by push module identifier and token
+///
+/// Each token is queued at most once per push module, as a single push wakes the device.
+type QueueEntries = HashMap<(String, String), QueueEntry>;
+
+struct QueueState {
+    entries: QueueEntries,
+    /// sequence number of the next queued entry
+    next_seq: u64,
+    /// the queue file contains entries that were already removed
+    needs_compaction: bool,
+}
+
+/// Change of the queue file, written by the writer thread
+enum QueueWrite {
+    Append(QueuedPush),
+    /// atomically replace the queue file with these entries
+    Rewrite(Vec<QueuedPush>),
+    /// answered once all previous writes are done
+    Flush(oneshot::Sender<()>),
+}
+
+/// Push requests that failed transiently, replayed once the push service recovers
+///
+/// New entries are appended to the queue file, which is rewritten after each replay, so the
+/// queue survives restarts. The file is written by a separate thread, so push requests never
+/// wait for the disk.
+pub struct PushQueue {
+    settings: PushQueueSettings,
+    state: Mutex<QueueState>,
+    writes: mpsc::Sender<QueueWrite>,
+    /// the last rewrite failed, so the queue file still contains removed entries
+    compaction_failed: Arc<AtomicBool>,
+}
+
+impl PushQueue {
+    /// Open the queue, loading entries from the queue file if it exists
+    ///
+    /// The file is not written before the first push request is queued or replayed.
+    pub fn open(settings: &PushQueueSettings) -> Result<Self, String> {
+        settings.validate()?;
+        let mut entries = QueueEntries::new();
+        let mut next_seq = 0;
+        let mut needs_compaction = false;
+        match File::open(&settings.path) {
+            Ok(queue_file) => {
+                for line in std::io::BufReader::new(queue_file).lines() {
+                    let line = line.map_err(|e| {
+                        format!("could not read {}: {}", settings.path.display(), e)
+                    })?;
+                    match serde_json::from_str::<QueuedPush>(&line) {
+                        Ok(queued) => {
+                            let key = (queued.module_id, queued.token);
+                            let entry = QueueEntry {
+                                queued_at: queued.queued_at,
+                                seq: next_seq,
+                                priority: queued.priority,
+                                summary: queued.summary,
+                            };
+                            next_seq += 1;
+                            if let Some(previous) = entries.insert(key.clone(), entry) {
+                                // the token was queued again, keep the latest push request
+                                if previous.queued_at > entries[&key].queued_at {
+                                    entries.insert(key, previous);
+                                }
+                                needs_compaction = true;
+                            }
+                        }
+                        // e.g. a partially written line after a crash
+                        Err(e) => {
+                            warn!(
+                                "Skipping invalid line in push queue {}: {}",
+                                settings.path.display(),
+                                e
+                            );
+                            needs_compaction = true;
+                        }
+                    }
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(format!("could not open {}: {}", settings.path.display(), e)),
+        }
+        let (writes, pending_writes) = mpsc::channel();
+        let compaction_failed = Arc::new(AtomicBool::new(false));
+        let writer = QueueWriter {
+            path: settings.path.clone(),
+            file: None,
+            compaction_failed: compaction_failed.clone(),
+        };
+        std::thread::Builder::new()
+            .name("push-queue-writer".to_string())
+            .spawn(move || writer.run(pending_writes))
+            .map_err(|e| format!("could not start push queue writer: {}", e))?;
+        let queue = Self {
+            settings: settings.clone(),
+            state: Mutex::new(QueueState {
+                entries,
+                next_seq,
+                needs_compaction,
+            }),
+            writes,
+            compaction_failed,
+        };
+        {
+            let mut state = queue.state.lock().unwrap();
+            queue.apply_limits(&mut state, settings.max_entries);
+            info!(
+                "Loaded {} queued push requests from {}",
+                state.entries.len(),
+                settings.path.display()
+            );
+        }
+        Ok(queue)
+    }
+
+    /// Queue a push request that failed due to a transient error of the push service
+    ///
+    /// The token, priority and summary of the push request are kept.
+    pub fn enqueue(&self, module_id: &str, request: &PushRequest) {
+        let key = (module_id.to_string(), request.token().to_string());
+        let mut state = self.state.lock().unwrap();
+        let entry = QueueEntry {
+            queued_at: unix_timestamp(),
+            seq: state.next_seq,
+            priority: request.priority(),
+            summary: request.summary().clone(),
+        };
+        state.next_seq += 1;
+        if state.entries.remove(&key).is_some() {
+            state.needs_compaction = true;
+        }
+        // make room for the new entry
+        self.apply_limits(&mut state, self.settings.max_entries - 1);
+        debug!(
+            "{}: Queued push request for token {}",
+            module_id,
+            request.token()
+        );
+        // written while locked, so the writes are in the order of the changes
+        self.write(QueueWrite::Append(QueuedPush::new(&key, &entry)));
+        state.entries.insert(key, entry);
+    }
+
+    /// Return the number of queued push requests
+    pub fn len(&self) -> usize {
+        self.state.lock().unwrap().entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Drop entries older than `max_age` and the oldest entries exceeding `max_entries`
+    fn apply_limits(&self, state: &mut QueueState, max_entries: usize) {
+        let oldest_allowed = unix_timestamp().saturating_sub(self.settings.max_age.as_secs());
+        let before = state.entries.len();
+        state
+            .entries
+            .retain(|_, entry| entry.queued_at >= oldest_allowed);
+        if state.entries.len() > max_entries {
+            let mut by_age: Vec<(u64, (String, String))> = state
+                .entries
+                .iter()
+                .map(|(key, entry)| (entry.queued_at, key.clone()))
+                .collect();
+            by_age.sort_unstable();
+            let excess = state.entries.len() - max_entries;
+            for (_, key) in by_age.into_iter().take(excess) {
+                state.entries.remove(&key);
+            }
+        }
+        let dropped = before - state.entries.len();
+        if dropped > 0 {
+            info!("Dropped {} stale queued push requests", dropped);
+            state.needs_compaction = true;
+        }
+    }
+
+    fn write(&self, write: QueueWrite) {
+        if self.writes.send(write).is_err() {
+            error!(
+                "Could not write push queue {}: writer stopped",
+                self.settings.path.display()
+            );
+        }
+    }
+
+    /// Rewrite the queue file with the current entries if entries were removed
+    pub fn compact(&self) {
+        let mut state = self.state.lock().unwrap();
+        let compaction_failed = self.compaction_failed.swap(false, Ordering::Relaxed);
+        if !state.needs_compaction && !compaction_failed {
+            return;
+        }
+        let entries = state
+            .entries
+            .iter()
+            .map(|(key, entry)| QueuedPush::new(key, entry))
+            .collect();
+        state.needs_compaction = false;
+        self.write(QueueWrite::Rewrite(entries));
+    }
+
+    /// Wait until all changes of the queue are written to the queue file
+    pub async fn flush(&self) {
+        let (done, written) = oneshot::channel();
+        self.write(QueueWrite::Flush(done));
+        let _ = written.await;
+    }
+
+    /// Remove an entry unless the token was queued again in the meantime
+    fn remove(&self, key: &(String, String), seq: u64) {
+        let mut state = self.state.lock().unwrap();
+        if state.entries.get(key).map(|entry| entry.seq) == Some(seq) {
+            state.entries.remove(key);
+            state.needs_compaction = true;
+        }
+    }
+
+    /// Replay all queued push requests of push modules whose push service recovered
+    ///
+    /// The oldest entry of each push module is sent first. If it still fails transiently, the
+    /// push module is skipped until the next replay.
+    pub(crate) async fn replay(&self, push_modules: &PushModuleMapArc) {
+        let mut by_module: HashMap<String, Vec<(String, QueueEntry)>> = HashMap::new();
+        {
+            let mut state = self.state.lock().unwrap();
+            self.apply_limits(&mut state, self.settings.max_entries);
+            for ((module_id, token), entry) in &state.entries {
+                by_module
+                    .entry(module_id.to_string())
+                    .or_default()
+                    .push((token.to_string(), entry.clone()));
+            }
+        }
+        for (module_id, mut queued) in by_module {
+            queued.sort_by_key(|(_, entry)| entry.queued_at);
+            let push_module = match push_modules.get(&module_id) {
+                Some(push_module) => push_module.value().clone(),
+                None => {
+                    info!(
+                        "Dropping {} queued push requests of removed push module {}",
+                        queued.len(),
+                        module_id
+                    );
+                    for (token, entry) in queued {
+                        self.remove(&(module_id.clone(), token), entry.seq);
+                    }
+                    continue;
+                }
+            };
+
+            let mut queued = queued.into_iter();
+            let (probe_token, probe_entry) = match queued.next() {
+                Some(probe) => probe,
+                None => continue,
+            };
+            match replay_push(&push_module, &probe_entry.request(&probe_token)).await {
+                Some(_) => self.remove(&(module_id.clone(), probe_token), probe_entry.seq),
+                None => {
+                    debug!("{}: Push service still unavailable", module_id);
+                    continue;
+                }
+            }
+
+            let remaining = queued.len();
+            let still_failing: usize = futures::stream::iter(queued)
+                .map(|(token, entry)| {
+                    let push_module = push_module.clone();
+                    let module_id = module_id.clone();
+                    async move {
+                        match replay_push(&push_module, &entry.request(&token)).await {
+                            Some(_) => {
+                                self.remove(&(module_id, token), entry.seq);
+                                0
+                            }
+                            None => 1,
+                        }
+                    }
+                })
+                .buffer_unordered(REPLAY_CONCURRENCY)
+                .fold(0, |sum, failed| async move { sum + failed })
+                .await;
+            info!(
+                "{}: Replayed {} queued push requests, {} still failing",
+                module_id,
+                remaining + 1 - still_failing,
+                still_failing
+            );
+        }
+        self.compact();
+    }
+
+    /// Replay queued push requests every `replay_interval`
+    pub(crate) fn spawn_replay(self: &Arc<Self>, push_modules: PushModuleMapArc) -> JoinHandle<()> {
+        let queue = self.clone();
+        tokio::spawn(async move {
+            loop {
+                // give the push services time to recover after a restart
+                tokio::time::sleep(queue.settings.replay_interval).await;
+                if !queue.is_empty() {
+                    queue.replay(&push_modules).await;
+                }
+            }
+        })
+    }
+}
+
+impl QueueEntry {
+    /// Rebuild the queued push request
+    fn request(&self, token: &str) -> PushRequest {
+        PushRequest::new(token.to_string())
+            .with_priority(self.priority)
+            .with_summary(self.summary.clone())
+    }
+}
+
+/// Owner of the queue file, applying the writes of a queue in order
+struct QueueWriter {
+    path: PathBuf,
+    /// append handle of the queue file, opened on the first write
+    file: Option<File>,
+    compaction_failed: Arc<AtomicBool>,
+}
+
+impl QueueWriter {
+    /// Apply writes until the queue is dropped
+    fn run(mut self, writes: mpsc::Receiver<QueueWrite>) {
+        for write in writes {
+            match write {
+                QueueWrite::Append(queued) => {
+                    if let Err(e) = self.append(&queued) {
+                        error!("Could not write push queue {}: {}", self.path.display(), e);
+                    }
+                }
+                QueueWrite::Rewrite(entries) => match self.write_entries(&entries) {
+                    // reopen the append handle, it still refers to the replaced file
+                    Ok(()) => self.file = None,
+                    Err(e) => {
+                        error!("Could not write push queue {}: {}", self.path.display(), e);
+                        self.compaction_failed.store(true, Ordering::Relaxed);
+                    }
+                },
+                QueueWrite::Flush(done) => {
+                    let _ = done.send(());
+                }
+            }
+        }
+    }
+
+    fn append(&mut self, queued: &QueuedPush) -> std::io::Result<()> {
+        if self.file.is_none() {
+            self.file = Some(
+                std::fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&self.path)?,
+            );
+        }
+        if let Some(file) = self.file.as_mut() {
+            let mut line = serde_json::to_vec(queued)?;
+            line.push(b'\n');
+            file.write_all(&line)?;
+        }
+        Ok(())
+    }
+
+    /// Write all entries to a temporary file and atomically replace the queue file
+    fn write_entries(&self, entries: &[QueuedPush]) -> std::io::Result<()> {
+        let tmp_path = self.path.with_extension("tmp");
+        {
+            let mut tmp_writer = BufWriter::new(File::create(&tmp_path)?);
+            for queued in entries {
+                serde_json::to_writer(&mut tmp_writer, queued)?;
+                tmp_writer.write_all(b"\n")?;
+            }
+            tmp_writer.flush()?;
+        }
+        std::fs::rename(&tmp_path, &self.path)
+    }
+}
+
+fn unix_timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since_epoch| since_epoch.as_secs())
+        .unwrap_or_default()
+}
+
+fn serde_humantime<'de, D>(deserializer: D) -> std::result::Result<Duration, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    serde_humantime::De::<Duration>::deserialize(deserializer)
+        .map(|wrapped_de: serde_humantime::De<Duration>| wrapped_de.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn queue_settings(name: &str, max_entries: usize) -> PushQueueSettings {
+        let path =
+            std::env::temp_dir().join(format!("fpush-queue-{}-{}.jsonl", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        serde_json::from_value(serde_json::json!({
+            "path": path,
+            "maxAge": "1h",
+            "maxEntries": max_entries,
+        }))
+        .unwrap()
+    }
+
+    fn request(token: &str) -> PushRequest {
+        PushRequest::new(token.to_string())
+    }
+
+    #[tokio::test]
+    async fn test_queue_survives_reopen() {
+        let settings = queue_settings("reopen", 10);
+        let queue = PushQueue::open(&settings).unwrap();
+        queue.enqueue("apple", &request("token1"));
+        queue.enqueue("apple", &request("token2"));
+        queue.enqueue("apple", &request("token1"));
+        queue.enqueue("google", &request("token1"));
+        assert_eq!(queue.len(), 3);
+        queue.flush().await;
+        drop(queue);
+
+        let queue = PushQueue::open(&settings).unwrap();
+        assert_eq!(queue.len(), 3);
+        queue.compact();
+        queue.flush().await;
+        let lines = std::fs::read_to_string(settings.path()).unwrap();
+        assert_eq!(lines.lines().count(), 3);
+        std::fs::remove_file(settings.path()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_queue_keeps_priority_and_summary() {
+        let settings = queue_settings("summary", 10);
+        let summary = PushSummary::new(Some(2), Some("juliet@example.org".to_string()), None, None);
+        let queue = PushQueue::open(&settings).unwrap();
+        queue.enqueue(
+            "apple",
+            &request("token1")
+                .with_priority(PushPriority::Normal)
+                .with_summary(summary.clone()),
+        );
+        queue.enqueue("apple", &request("token2"));
+        queue.flush().await;
+        drop(queue);
+
+        let queue = PushQueue::open(&settings).unwrap();
+        let state = queue.state.lock().unwrap();
+        let queued = state.entries[&("apple".to_string(), "token1".to_string())].request("token1");
+        assert_eq!(queued.token(), "token1");
+        assert_eq!(queued.priority(), PushPriority::Normal);
+        assert_eq!(queued.summary(), &summary);
+        let queued = state.entries[&("apple".to_string(), "token2".to_string())].request("token2");
+        assert_eq!(queued.priority(), PushPriority::High);
+        assert!(queued.summary().is_empty());
+        std::fs::remove_file(settings.path()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_queue_keeps_token_queued_again() {
+        let settings = queue_settings("requeue", 10);
+        let queue = PushQueue::open(&settings).unwrap();
+        let key = ("apple".to_string(), "token1".to_string());
+        queue.enqueue("apple", &request("token1"));
+        let replayed = queue.state.lock().unwrap().entries[&key].clone();
+        // queued again within the same second while the first entry was replayed
+        queue.enqueue("apple", &request("token1"));
+        queue.remove(&key, replayed.seq);
+        assert_eq!(queue.len(), 1);
+
+        let queued = queue.state.lock().unwrap().entries[&key].clone();
+        queue.remove(&key, queued.seq);
+        assert!(queue.is_empty());
+        queue.flush().await;
+        std::fs::remove_file(settings.path()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_queue_limits() {
+        let settings = queue_settings("limits", 2);
+        // written by an older version without priority and summary
+        let expired = serde_json::json!({
+            "moduleId": "apple",
+            "token": "expired",
+            "queuedAt": unix_timestamp() - 7200,
+        });
+        std::fs::write(
+            settings.path(),
+            format!("{}\n{{\"moduleId\": \"apple\", \"tok", expired),
+        )
+        .unwrap();
+        let queue = PushQueue::open(&settings).unwrap();
+        assert!(queue.is_empty());
+
+        queue.enqueue("apple", &request("token1"));
+        queue.enqueue("apple", &request("token2"));
+        queue.enqueue("apple", &request("token3"));
+        assert_eq!(queue.len(), 2);
+        assert!(queue
+            .state
+            .lock()
+            .unwrap()
+            .entries
+            .contains_key(&("apple".to_string(), "token3".to_string())));
+        queue.flush().await;
+        std::fs::remove_file(settings.path()).unwrap();
+    }
+}
@@ -0,0 +1,296 @@
+use std::{
+    future::Future,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use crate::queue::PushQueue;
+use fpush_traits::request::PushRequest;
+
+use dashmap::DashMap;
+use derive_getters::Getters;
+use log::{info, warn};
+use serde::Deserialize;
+use tokio::task::JoinHandle;
+
+/// Maximal number of push requests retried in background at the same time
+const MAX_BACKGROUND_RETRIES: usize = 4096;
+
+/// Retries of push requests that failed with a transient error of the push service
+///
+/// The delay before the n-th retry is `initialBackoff * multiplier^(n-1)`, at most
+/// `maxBackoff`. No retry is started after `deadline` has passed since the first attempt.
+#[derive(Debug, Deserialize, Clone, PartialEq, Getters)]
+#[serde(rename_all = "camelCase", default)]
+pub struct RetryPolicy {
+    /// maximal number of attempts including the first one, 1 disables retries
+    max_attempts: u32,
+    #[serde(deserialize_with = "serde_humantime")]
+    initial_backoff: Duration,
+    multiplier: f64,
+    #[serde(deserialize_with = "serde_humantime")]
+    max_backoff: Duration,
+    #[serde(deserialize_with = "serde_humantime")]
+    deadline: Duration,
+    mode: RetryMode,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum RetryMode {
+    /// reply to the push request after the last attempt
+    Blocking,
+    /// acknowledge the push request after the first transient error and retry in background
+    Background,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 1,
+            initial_backoff: Duration::from_secs(1),
+            multiplier: 2.0,
+            max_backoff: Duration::from_secs(10),
+            deadline: Duration::from_secs(30),
+            mode: RetryMode::Blocking,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Return true if failed push requests are retried at all
+    pub fn is_enabled(&self) -> bool {
+        self.max_attempts > 1
+    }
+
+    /// Delay before the given retry, starting at 1 for the second attempt
+    pub fn backoff(&self, retry: u32) -> Duration {
+        let exponent = retry.saturating_sub(1).min(i32::MAX as u32) as i32;
+        let backoff = self.initial_backoff.as_secs_f64() * self.multiplier.powi(exponent);
+        // also catches an infinite backoff after many retries
+        if backoff < self.max_backoff.as_secs_f64() {
+            Duration::from_secs_f64(backoff)
+        } else {
+            self.max_backoff
+        }
+    }
+
+    /// Delay before the given retry, or `None` if the retry would start after the deadline
+    ///
+    /// The retry waits at least as long as the token ratelimit suppresses pushes to the token.
+    pub(crate) fn retry_delay(
+        &self,
+        retry: u32,
+        suppressed_for: Duration,
+        until_deadline: Duration,
+    ) -> Option<Duration> {
+        let delay = self.backoff(retry).max(suppressed_for);
+        if delay < until_deadline {
+            Some(delay)
+        } else {
+            None
+        }
+    }
+
+    /// Check settings serde can not check on its own
+    pub(crate) fn validate(&self) -> Result<(), String> {
+        if self.max_attempts == 0 {
+            return Err("maxAttempts has to be at least 1".to_string());
+        }
+        if !(self.multiplier >= 1.0 && self.multiplier <= 100.0) {
+            return Err("multiplier has to be between 1 and 100".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// Push requests retried in background after they were acknowledged
+///
+/// Retries still running on shutdown are handed to the push queue, if one is configured.
+#[derive(Default)]
+pub struct BackgroundRetries {
+    next_id: AtomicU64,
+    running: DashMap<u64, BackgroundRetry>,
+}
+
+struct BackgroundRetry {
+    module_id: String,
+    request: PushRequest,
+    /// unset until the task was spawned
+    task: Option<JoinHandle<()>>,
+}
+
+impl BackgroundRetries {
+    /// Run `retry` of the push request in background
+    ///
+    /// Returns false without running `retry` if too many push requests are retried already.
+    pub(crate) fn spawn<F>(
+        self: &Arc<Self>,
+        module_id: &str,
+        request: &PushRequest,
+        retry: F,
+    ) -> bool
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        if self.running.len() >= MAX_BACKGROUND_RETRIES {
+            return false;
+        }
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.running.insert(
+            id,
+            BackgroundRetry {
+                module_id: module_id.to_string(),
+                request: request.clone(),
+                task: None,
+            },
+        );
+        let retries = self.clone();
+        let task = tokio::spawn(async move {
+            retry.await;
+            retries.running.remove(&id);
+        });
+        // the retry may already be done
+        if let Some(mut running) = self.running.get_mut(&id) {
+            running.task = Some(task);
+        }
+        true
+    }
+
+    /// Return the number of push requests retried in background
+    pub fn len(&self) -> usize {
+        self.running.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.running.is_empty()
+    }
+
+    /// Stop all retries, queueing their push requests if a push queue is configured
+    pub(crate) fn shutdown(&self, queue: Option<&PushQueue>) {
+        let ids: Vec<u64> = self.running.iter().map(|running| *running.key()).collect();
+        let mut dropped = 0;
+        for id in ids {
+            let (_, retry) = match self.running.remove(&id) {
+                Some(retry) => retry,
+                None => continue,
+            };
+            if let Some(task) = retry.task {
+                task.abort();
+            }
+            match queue {
+                Some(queue) => queue.enqueue(&retry.module_id, &retry.request),
+                None => dropped += 1,
+            }
+        }
+        if dropped > 0 {
+            warn!("Dropped {} push requests retried in background", dropped);
+        } else if queue.is_some() {
+            info!("Queued all push requests retried in background");
+        }
+    }
+}
+
+fn serde_humantime<'de, D>(deserializer: D) -> std::result::Result<Duration, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    serde_humantime::De::<Duration>::deserialize(deserializer)
+        .map(|wrapped_de: serde_humantime::De<Duration>| wrapped_de.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff_is_capped() {
+        let policy: RetryPolicy = serde_json::from_str(
+            r#"{ "maxAttempts": 5, "initialBackoff": "500ms", "maxBackoff": "3s" }"#,
+        )
+        .unwrap();
+        assert!(policy.is_enabled());
+        assert_eq!(policy.backoff(1), Duration::from_millis(500));
+        assert_eq!(policy.backoff(2), Duration::from_secs(1));
+        assert_eq!(policy.backoff(3), Duration::from_secs(2));
+        assert_eq!(policy.backoff(4), Duration::from_secs(3));
+        assert_eq!(policy.backoff(30), Duration::from_secs(3));
+    }
+
+    #[test]
+    fn test_retry_delay_respects_deadline() {
+        let policy: RetryPolicy = serde_json::from_str(
+            r#"{ "maxAttempts": 5, "initialBackoff": "1s", "maxBackoff": "10s" }"#,
+        )
+        .unwrap();
+        let until_deadline = Duration::from_secs(5);
+        assert_eq!(
+            policy.retry_delay(1, Duration::ZERO, until_deadline),
+            Some(Duration::from_secs(1))
+        );
+        // the token ratelimit delays the retry
+        assert_eq!(
+            policy.retry_delay(1, Duration::from_secs(3), until_deadline),
+            Some(Duration::from_secs(3))
+        );
+        // e.g. a hard ratelimit ending after the deadline
+        assert_eq!(
+            policy.retry_delay(1, Duration::from_secs(600), until_deadline),
+            None
+        );
+        assert_eq!(policy.retry_delay(4, Duration::ZERO, until_deadline), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_background_retries_are_tracked() {
+        let retries = Arc::new(BackgroundRetries::default());
+        let request = PushRequest::new("token".to_string());
+        assert!(retries.spawn("apple", &request, async {}));
+        assert!(retries.spawn(
+            "apple",
+            &request,
+            tokio::time::sleep(Duration::from_secs(60))
+        ));
+        assert_eq!(retries.len(), 2);
+        // let the first retry finish
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert_eq!(retries.len(), 1);
+
+        retries.shutdown(None);
+        assert!(retries.is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_background_retries_are_queued_on_shutdown() {
+        let path = std::env::temp_dir().join(format!(
+            "fpush-background-retries-{}.jsonl",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        let queue =
+            PushQueue::open(&serde_json::from_value(serde_json::json!({ "path": path })).unwrap())
+                .unwrap();
+        let retries = Arc::new(BackgroundRetries::default());
+        let request = PushRequest::new("token".to_string());
+        assert!(retries.spawn(
+            "apple",
+            &request,
+            tokio::time::sleep(Duration::from_secs(60))
+        ));
+
+        retries.shutdown(Some(&queue));
+        assert!(retries.is_empty());
+        assert_eq!(queue.len(), 1);
+        queue.flush().await;
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_default_policy_does_not_retry() {
+        assert!(!RetryPolicy::default().is_enabled());
+        assert!(RetryPolicy::default().validate().is_ok());
+    }
+}
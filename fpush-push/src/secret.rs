@@ -0,0 +1,107 @@
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::fpush_config::SecretSettings;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Expected secret or HMAC key, resolved when the push module is loaded
+enum ResolvedSecret {
+    Static(String),
+    Hmac(String),
+}
+
+/// Verifies the `secret` publish-option of push requests
+pub struct PushSecretValidator {
+    secret: ResolvedSecret,
+}
+
+impl PushSecretValidator {
+    /// Read the configured secret, which may be a file or environment variable reference
+    pub fn new(settings: &SecretSettings) -> Result<Self, String> {
+        let secret = match settings {
+            SecretSettings::Static { secret } => ResolvedSecret::Static(secret.resolve()?),
+            SecretSettings::Hmac { key } => ResolvedSecret::Hmac(key.resolve()?),
+        };
+        Ok(Self { secret })
+    }
+
+    /// returns true if the provided secret is valid for the token
+    pub fn verify(&self, token: &str, secret: Option<&str>) -> bool {
+        let secret = match secret {
+            Some(secret) => secret,
+            None => return false,
+        };
+        match &self.secret {
+            ResolvedSecret::Static(expected_secret) => {
+                constant_time_eq(secret.as_bytes(), expected_secret.as_bytes())
+            }
+            ResolvedSecret::Hmac(key) => {
+                let provided_mac = match hex::decode(secret) {
+                    Ok(provided_mac) => provided_mac,
+                    Err(_) => return false,
+                };
+                let mut mac = match HmacSha256::new_from_slice(key.as_bytes()) {
+                    Ok(mac) => mac,
+                    Err(_) => return false,
+                };
+                mac.update(token.as_bytes());
+                mac.verify_slice(&provided_mac).is_ok()
+            }
+        }
+    }
+}
+
+#[inline(always)]
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b.iter()).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::fpush_config::SecretSettings;
+    use crate::secret::PushSecretValidator;
+    use fpush_traits::secret::SecretValue;
+
+    #[test]
+    fn static_secret() {
+        let validator = PushSecretValidator::new(&SecretSettings::Static {
+            secret: SecretValue::Inline("s3cr3t".to_string()),
+        })
+        .unwrap();
+        assert!(validator.verify("some-token", Some("s3cr3t")));
+        assert!(!validator.verify("some-token", Some("s3cr3")));
+        assert!(!validator.verify("some-token", None));
+    }
+
+    #[test]
+    fn hmac_secret() {
+        let validator = PushSecretValidator::new(&SecretSettings::Hmac {
+            key: SecretValue::Inline("key".to_string()),
+        })
+        .unwrap();
+        // HMAC-SHA256("key", "The quick brown fox jumps over the lazy dog")
+        let token = "The quick brown fox jumps over the lazy dog";
+        let secret = "f7bc83f430538424b13298e6aa6fb143ef4d59a14946175997479dbc2d1a3cd8";
+        assert!(validator.verify(token, Some(secret)));
+        assert!(validator.verify(token, Some(&secret.to_uppercase())));
+        assert!(!validator.verify("some-token", Some(secret)));
+        assert!(!validator.verify(token, Some("not-hex")));
+        assert!(!validator.verify(token, None));
+    }
+
+    #[test]
+    fn secret_settings_are_redacted() {
+        let settings: SecretSettings =
+            serde_json::from_str(r#"{ "mode": "static", "secret": "s3cr3t" }"#).unwrap();
+        assert!(!settings.is_reference());
+        assert!(!format!("{:?}", settings).contains("s3cr3t"));
+        let settings: SecretSettings =
+            serde_json::from_str(r#"{ "mode": "hmac", "key": { "env": "FPUSH_HMAC_KEY" } }"#)
+                .unwrap();
+        assert!(settings.is_reference());
+    }
+}
@@ -1,14 +1,23 @@
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use std::time::Duration;
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
 #[serde(rename_all = "camelCase")]
 pub struct RatelimitSettings {
-    #[serde(deserialize_with = "serde_humantime")]
+    #[serde(
+        deserialize_with = "serde_humantime",
+        serialize_with = "serialize_humantime"
+    )]
     pub hard_ratelimit_time: Duration,
-    #[serde(deserialize_with = "serde_humantime")]
+    #[serde(
+        deserialize_with = "serde_humantime",
+        serialize_with = "serialize_humantime"
+    )]
     pub ratelimit_time: Duration,
-    #[serde(deserialize_with = "serde_humantime")]
+    #[serde(
+        deserialize_with = "serde_humantime",
+        serialize_with = "serialize_humantime"
+    )]
     pub ratelimit_cleanup_interval: Duration,
     pub enabled: bool,
 }
@@ -42,6 +51,73 @@ impl RatelimitSettings {
     }
 }
 
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum DomainRatelimitPolicy {
+    /// reject requests as soon as the bucket of the domain is empty
+    Reject,
+    /// delay requests until the bucket refilled, rejecting them if this takes longer than `maxDelay`
+    Delay,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct DomainRatelimitSettings {
+    pub requests_per_second: f64,
+    pub burst: u32,
+    pub policy: DomainRatelimitPolicy,
+    #[serde(
+        deserialize_with = "serde_humantime",
+        serialize_with = "serialize_humantime"
+    )]
+    pub max_delay: Duration,
+    #[serde(
+        deserialize_with = "serde_humantime",
+        serialize_with = "serialize_humantime"
+    )]
+    pub cleanup_interval: Duration,
+    pub enabled: bool,
+}
+
+impl Default for DomainRatelimitSettings {
+    fn default() -> Self {
+        Self {
+            requests_per_second: 50.0,
+            burst: 500,
+            policy: DomainRatelimitPolicy::Reject,
+            max_delay: Duration::from_secs(2),
+            cleanup_interval: Duration::from_secs(300),
+            enabled: false,
+        }
+    }
+}
+
+impl DomainRatelimitSettings {
+    pub fn requests_per_second(&self) -> f64 {
+        self.requests_per_second
+    }
+
+    pub fn burst(&self) -> u32 {
+        self.burst
+    }
+
+    pub fn policy(&self) -> DomainRatelimitPolicy {
+        self.policy
+    }
+
+    pub fn max_delay(&self) -> Duration {
+        self.max_delay
+    }
+
+    pub fn cleanup_interval(&self) -> Duration {
+        self.cleanup_interval
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+}
+
 pub fn serde_humantime<'de, D>(deserializer: D) -> std::result::Result<Duration, D::Error>
 where
     D: serde::Deserializer<'de>,
@@ -49,3 +125,17 @@ where
     serde_humantime::De::<Duration>::deserialize(deserializer)
         .map(|wrapped_de: serde_humantime::De<Duration>| wrapped_de.into_inner())
 }
+
+pub fn serialize_humantime<S>(
+    duration: &Duration,
+    serializer: S,
+) -> std::result::Result<S::Ok, S::Error>
+where
+    S: serde::Serializer,
+{
+    if duration.subsec_nanos() == 0 {
+        serializer.serialize_str(&format!("{}s", duration.as_secs()))
+    } else {
+        serializer.serialize_str(&format!("{}ms", duration.as_millis()))
+    }
+}
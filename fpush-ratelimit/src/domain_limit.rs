@@ -1 +1,191 @@
+use dashmap::DashMap;
+use log::debug;
+use std::time::{Duration, Instant};
 
+use crate::{DomainRatelimitPolicy, DomainRatelimitSettings};
+
+/// Token bucket based ratelimit of push requests per sending XMPP domain
+pub struct FpushDomainRateLimit {
+    bucket_map: DashMap<String, DomainBucket>,
+    requests_per_second: f64,
+    burst: f64,
+    policy: DomainRatelimitPolicy,
+    max_delay: Duration,
+    enabled: bool,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum DomainRatelimitResult {
+    Allowed,
+    Delayed(Duration),
+    Rejected,
+}
+
+struct DomainBucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl DomainBucket {
+    #[inline(always)]
+    fn new(burst: f64) -> Self {
+        Self {
+            tokens: burst,
+            last_refill: Instant::now(),
+        }
+    }
+
+    #[inline(always)]
+    fn refill(&mut self, requests_per_second: f64, burst: f64) {
+        let now = Instant::now();
+        let elapsed = now.saturating_duration_since(self.last_refill);
+        self.tokens = (self.tokens + elapsed.as_secs_f64() * requests_per_second).min(burst);
+        self.last_refill = now;
+    }
+}
+
+impl FpushDomainRateLimit {
+    pub fn new(config: &DomainRatelimitSettings) -> Self {
+        Self {
+            bucket_map: DashMap::new(),
+            requests_per_second: config.requests_per_second(),
+            burst: f64::from(config.burst().max(1)),
+            policy: config.policy(),
+            max_delay: config.max_delay(),
+            enabled: config.is_enabled() && config.requests_per_second() > 0.0,
+        }
+    }
+
+    /// returns true if the request of the domain may be handled, after waiting if required
+    #[inline(always)]
+    pub async fn lookup_ratelimit(&self, domain: &str) -> bool {
+        match self.check(domain) {
+            DomainRatelimitResult::Allowed => true,
+            DomainRatelimitResult::Delayed(wait_duration) => {
+                debug!(
+                    "Domain ratelimit: delaying request of {} by {}ms",
+                    domain,
+                    wait_duration.as_millis()
+                );
+                tokio::time::sleep(wait_duration).await;
+                true
+            }
+            DomainRatelimitResult::Rejected => false,
+        }
+    }
+
+    #[inline(always)]
+    pub fn check(&self, domain: &str) -> DomainRatelimitResult {
+        if !self.enabled {
+            return DomainRatelimitResult::Allowed;
+        }
+        let mut bucket = self
+            .bucket_map
+            .entry(domain.to_string())
+            .or_insert_with(|| DomainBucket::new(self.burst));
+        bucket.refill(self.requests_per_second, self.burst);
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return DomainRatelimitResult::Allowed;
+        }
+        match self.policy {
+            DomainRatelimitPolicy::Reject => DomainRatelimitResult::Rejected,
+            DomainRatelimitPolicy::Delay => {
+                let wait_duration =
+                    Duration::from_secs_f64((1.0 - bucket.tokens) / self.requests_per_second);
+                if wait_duration > self.max_delay {
+                    DomainRatelimitResult::Rejected
+                } else {
+                    // reserve the token that becomes available after waiting
+                    bucket.tokens -= 1.0;
+                    DomainRatelimitResult::Delayed(wait_duration)
+                }
+            }
+        }
+    }
+
+    /// remove all domains whose bucket is completely refilled
+    pub fn cleanup(&self) {
+        self.bucket_map.retain(|_, bucket| {
+            bucket.refill(self.requests_per_second, self.burst);
+            bucket.tokens < self.burst
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        DomainRatelimitPolicy, DomainRatelimitResult, DomainRatelimitSettings, FpushDomainRateLimit,
+    };
+
+    use std::time::Duration;
+
+    fn settings(policy: DomainRatelimitPolicy) -> DomainRatelimitSettings {
+        DomainRatelimitSettings {
+            requests_per_second: 10.0,
+            burst: 5,
+            policy,
+            max_delay: Duration::from_millis(250),
+            enabled: true,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn disabled() {
+        let dr = FpushDomainRateLimit::new(&DomainRatelimitSettings::default());
+        for _i in 0..10000 {
+            assert_eq!(dr.check("example.com"), DomainRatelimitResult::Allowed);
+        }
+    }
+
+    #[test]
+    fn burst_and_reject() {
+        let dr = FpushDomainRateLimit::new(&settings(DomainRatelimitPolicy::Reject));
+        for _i in 0..5 {
+            assert_eq!(dr.check("example.com"), DomainRatelimitResult::Allowed);
+        }
+        assert_eq!(dr.check("example.com"), DomainRatelimitResult::Rejected);
+        // other domains have their own bucket
+        assert_eq!(dr.check("example.org"), DomainRatelimitResult::Allowed);
+    }
+
+    #[test]
+    fn refill() {
+        let dr = FpushDomainRateLimit::new(&settings(DomainRatelimitPolicy::Reject));
+        for _i in 0..5 {
+            assert_eq!(dr.check("example.com"), DomainRatelimitResult::Allowed);
+        }
+        assert_eq!(dr.check("example.com"), DomainRatelimitResult::Rejected);
+        std::thread::sleep(Duration::from_millis(150));
+        assert_eq!(dr.check("example.com"), DomainRatelimitResult::Allowed);
+    }
+
+    #[test]
+    fn delay() {
+        let dr = FpushDomainRateLimit::new(&settings(DomainRatelimitPolicy::Delay));
+        for _i in 0..5 {
+            assert_eq!(dr.check("example.com"), DomainRatelimitResult::Allowed);
+        }
+        // each further request has to wait one more refill period until max_delay is reached
+        assert!(matches!(
+            dr.check("example.com"),
+            DomainRatelimitResult::Delayed(d) if d <= Duration::from_millis(100)
+        ));
+        assert!(matches!(
+            dr.check("example.com"),
+            DomainRatelimitResult::Delayed(d) if d > Duration::from_millis(100)
+        ));
+        assert_eq!(dr.check("example.com"), DomainRatelimitResult::Rejected);
+    }
+
+    #[tokio::test]
+    async fn cleanup() {
+        let dr = FpushDomainRateLimit::new(&settings(DomainRatelimitPolicy::Reject));
+        assert!(dr.lookup_ratelimit("example.com").await);
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        dr.cleanup();
+        assert!(dr.bucket_map.is_empty());
+    }
+}
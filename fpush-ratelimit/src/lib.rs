@@ -1,7 +1,8 @@
 mod domain_limit;
+pub use domain_limit::{DomainRatelimitResult, FpushDomainRateLimit};
 
 mod token_ratelimit;
 pub use token_ratelimit::FpushTokenRateLimit;
 
 mod config;
-pub use config::RatelimitSettings;
+pub use config::{DomainRatelimitPolicy, DomainRatelimitSettings, RatelimitSettings};
@@ -1,13 +1,12 @@
 use dashmap::DashMap;
 use log::debug;
-use std::time::Duration;
+use std::{sync::RwLock, time::Duration};
+
+use crate::RatelimitSettings;
 
 pub struct FpushTokenRateLimit {
     ratelimit_map: DashMap<String, TokenRateLimitValue>,
-    time_between_pushes: Duration,
-    time_till_cleanup: Duration,
-    hard_ratelimit_time: Duration,
-    enabled: bool,
+    settings: RwLock<RatelimitSettings>,
 }
 
 struct TokenRateLimitValue {
@@ -38,6 +37,12 @@ impl TokenRateLimitValue {
         }
     }
 
+    #[inline(always)]
+    pub(crate) fn time_until_next_push(&self) -> Duration {
+        self.last_push
+            .saturating_duration_since(std::time::Instant::now())
+    }
+
     #[inline(always)]
     pub(crate) fn reset_to_now(&mut self) {
         self.last_push = std::time::Instant::now();
@@ -58,13 +63,20 @@ impl FpushTokenRateLimit {
     pub fn new(config: &crate::RatelimitSettings) -> Self {
         Self {
             ratelimit_map: DashMap::new(),
-            time_between_pushes: config.ratelimit_time(),
-            time_till_cleanup: config.ratelimit_cleanup_interval(),
-            hard_ratelimit_time: config.hard_ratelimit_time(),
-            enabled: config.is_enabled(),
+            settings: RwLock::new(*config),
         }
     }
 
+    /// Apply new settings, keeping the ratelimit of all tokens
+    pub fn update_settings(&self, config: &crate::RatelimitSettings) {
+        *self.settings.write().unwrap() = *config;
+    }
+
+    #[inline(always)]
+    fn settings(&self) -> RatelimitSettings {
+        *self.settings.read().unwrap()
+    }
+
     #[inline(always)]
     pub async fn lookup_ratelimit(&self, token: String) -> bool {
         let (sendpush, wait_duration_opt) = self.internal_ratelimit_check(&token);
@@ -83,7 +95,8 @@ impl FpushTokenRateLimit {
     // bool -> send push
     #[inline(always)]
     pub fn internal_ratelimit_check(&self, token: &str) -> (bool, Option<Duration>) {
-        if !self.enabled {
+        let settings = self.settings();
+        if !settings.is_enabled() {
             return (true, None);
         }
         if token.len() < 64 || token.len() > 512 {
@@ -97,7 +110,7 @@ impl FpushTokenRateLimit {
             } else {
                 // no active timer
                 let duration_since_last_push = ratelimit_entry.time_since_last_push();
-                if duration_since_last_push >= self.time_between_pushes {
+                if duration_since_last_push >= settings.ratelimit_time() {
                     debug!(
                         "Ignoring existing rate limit for token {}, as it is to old: {}s",
                         token,
@@ -107,7 +120,7 @@ impl FpushTokenRateLimit {
                     ratelimit_entry.reset_to_now();
                     (true, None)
                 } else {
-                    let timeout = self.time_between_pushes - duration_since_last_push;
+                    let timeout = settings.ratelimit_time() - duration_since_last_push;
                     ratelimit_entry.toggle_timer(timeout);
                     (true, Some(timeout))
                 }
@@ -122,23 +135,70 @@ impl FpushTokenRateLimit {
         }
     }
 
+    /// Use up the ratelimit slot of the token if it is free, without waiting for it
+    ///
+    /// Returns false, leaving the ratelimit of the token unchanged, if a push to the token was
+    /// sent within the `ratelimitTime` or pushes to it are suppressed.
+    pub fn try_lookup_ratelimit(&self, token: &str) -> bool {
+        let settings = self.settings();
+        if !settings.is_enabled() {
+            return true;
+        }
+        if token.len() < 64 || token.len() > 512 {
+            return false;
+        }
+        if let Some(mut ratelimit_entry) = self.ratelimit_map.get_mut(token) {
+            let duration_since_last_push = ratelimit_entry.time_since_last_push();
+            // a zero duration means the timer is active
+            if duration_since_last_push.is_zero()
+                || duration_since_last_push < settings.ratelimit_time()
+            {
+                false
+            } else {
+                ratelimit_entry.reset_to_now();
+                true
+            }
+        } else {
+            self.ratelimit_map
+                .insert(token.to_string(), TokenRateLimitValue::new());
+            debug!("Inserting rate limit entry for token {}", token);
+            true
+        }
+    }
+
+    /// Return how long pushes to the token are suppressed, without using up a ratelimit slot
+    ///
+    /// Pushes are suppressed after a hard ratelimit and while another push request for the token
+    /// waits for its slot.
+    pub fn suppressed_for(&self, token: &str) -> Duration {
+        if !self.settings().is_enabled() {
+            return Duration::ZERO;
+        }
+        self.ratelimit_map
+            .get(token)
+            .map(|ratelimit_entry| ratelimit_entry.time_until_next_push())
+            .unwrap_or_default()
+    }
+
     #[inline(always)]
     pub fn hard_ratelimit(&self, token: String) {
         debug!("Adding hard rate limit for token {}", token);
+        let hard_ratelimit_time = self.settings().hard_ratelimit_time();
         if let Some(mut ratelimit_entry) = self.ratelimit_map.get_mut(&token) {
-            ratelimit_entry.hard_ratelimit(self.hard_ratelimit_time)
+            ratelimit_entry.hard_ratelimit(hard_ratelimit_time)
         } else {
             // no entry exists -> create new entry
             self.ratelimit_map.insert(
                 token.to_string(),
-                TokenRateLimitValue::new_with_duration(self.hard_ratelimit_time),
+                TokenRateLimitValue::new_with_duration(hard_ratelimit_time),
             );
         };
     }
 
     pub fn cleanup(&self) {
+        let time_till_cleanup = self.settings().ratelimit_cleanup_interval();
         self.ratelimit_map
-            .retain(|_, v| v.time_since_last_push() >= self.time_till_cleanup);
+            .retain(|_, v| v.time_since_last_push() >= time_till_cleanup);
     }
 }
 
@@ -244,6 +304,90 @@ mod tests {
         tr.cleanup();
     }
 
+    #[tokio::test]
+    async fn update_settings_keeps_ratelimit() {
+        let token = "abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789abcdefghijklmnopqrstuvwxyz".to_string();
+        let tr = FpushTokenRateLimit::new(&RatelimitSettings {
+            hard_ratelimit_time: Duration::from_secs(40),
+            ratelimit_time: Duration::from_secs(20),
+            ratelimit_cleanup_interval: Duration::from_secs(180),
+            ..Default::default()
+        });
+        tr.hard_ratelimit(token.clone());
+        tr.update_settings(&RatelimitSettings {
+            hard_ratelimit_time: Duration::from_secs(10),
+            ratelimit_time: Duration::from_secs(1),
+            ratelimit_cleanup_interval: Duration::from_secs(180),
+            ..Default::default()
+        });
+        // the hard ratelimit added before the update is still active
+        check_lookup(
+            &tr,
+            token.clone(),
+            false,
+            Duration::from_secs(0),
+            Duration::from_millis(100),
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn suppressed_for_uses_no_slot() {
+        let token = "abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789abcdefghijklmnopqrstuvwxyz".to_string();
+        let tr = FpushTokenRateLimit::new(&RatelimitSettings {
+            hard_ratelimit_time: Duration::from_secs(40),
+            ratelimit_time: Duration::from_secs(20),
+            ratelimit_cleanup_interval: Duration::from_secs(180),
+            ..Default::default()
+        });
+        assert_eq!(tr.suppressed_for(&token), Duration::ZERO);
+        // checking did not use up the slot of the first push
+        check_lookup(
+            &tr,
+            token.clone(),
+            true,
+            Duration::from_secs(0),
+            Duration::from_millis(100),
+        )
+        .await;
+        // the slot of the first push is used, but no further push is waiting
+        assert_eq!(tr.suppressed_for(&token), Duration::ZERO);
+
+        tr.hard_ratelimit(token.clone());
+        let suppressed_for = tr.suppressed_for(&token);
+        assert!(
+            suppressed_for > Duration::from_secs(39) && suppressed_for <= Duration::from_secs(40)
+        );
+        // checking did not extend the hard ratelimit
+        assert!(tr.suppressed_for(&token) <= suppressed_for);
+
+        tr.update_settings(&RatelimitSettings {
+            enabled: false,
+            ..Default::default()
+        });
+        assert_eq!(tr.suppressed_for(&token), Duration::ZERO);
+    }
+
+    #[test]
+    fn try_lookup_ratelimit_does_not_wait() {
+        let token = "abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789abcdefghijklmnopqrstuvwxyz".to_string();
+        let tr = FpushTokenRateLimit::new(&RatelimitSettings {
+            hard_ratelimit_time: Duration::from_secs(40),
+            ratelimit_time: Duration::from_secs(20),
+            ratelimit_cleanup_interval: Duration::from_secs(180),
+            ..Default::default()
+        });
+        assert!(!tr.try_lookup_ratelimit("shortToken"));
+        assert!(tr.try_lookup_ratelimit(&token));
+        // the slot is used up, but refusing did not queue a push
+        assert!(!tr.try_lookup_ratelimit(&token));
+        assert_eq!(tr.suppressed_for(&token), Duration::ZERO);
+
+        let other_token = format!("{}0", token);
+        tr.hard_ratelimit(other_token.clone());
+        assert!(!tr.try_lookup_ratelimit(&other_token));
+    }
+
     #[tokio::test]
     async fn ratelimit_sequential() {
         let token = "abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789abcdefghijklmnopqrstuvwxyz".to_string();
@@ -1,12 +1,15 @@
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use std::time::Duration;
 
-#[derive(Debug, Deserialize, Copy, Clone)]
+#[derive(Debug, Deserialize, Serialize, Copy, Clone, PartialEq, Eq)]
 #[serde(rename_all = "camelCase")]
 pub struct BlacklistSettings {
     invalid_token: BlacklistBlockingTimes,
     push_error: BlacklistBlockingTimes,
-    #[serde(deserialize_with = "serde_humantime")]
+    #[serde(
+        deserialize_with = "serde_humantime",
+        serialize_with = "serialize_humantime"
+    )]
     block_extension: Duration,
 }
 
@@ -53,12 +56,18 @@ impl BlacklistSettings {
     }
 }
 
-#[derive(Debug, Deserialize, Copy, Clone)]
+#[derive(Debug, Deserialize, Serialize, Copy, Clone, PartialEq, Eq)]
 #[serde(rename_all = "camelCase")]
 pub struct BlacklistBlockingTimes {
-    #[serde(deserialize_with = "serde_humantime")]
+    #[serde(
+        deserialize_with = "serde_humantime",
+        serialize_with = "serialize_humantime"
+    )]
     inital_blocking: Duration,
-    #[serde(deserialize_with = "serde_humantime")]
+    #[serde(
+        deserialize_with = "serde_humantime",
+        serialize_with = "serialize_humantime"
+    )]
     extended_blocking: Duration,
 }
 
@@ -95,3 +104,17 @@ where
     serde_humantime::De::<Duration>::deserialize(deserializer)
         .map(|wrapped_de: serde_humantime::De<Duration>| wrapped_de.into_inner())
 }
+
+pub fn serialize_humantime<S>(
+    duration: &Duration,
+    serializer: S,
+) -> std::result::Result<S::Ok, S::Error>
+where
+    S: serde::Serializer,
+{
+    if duration.subsec_nanos() == 0 {
+        serializer.serialize_str(&format!("{}s", duration.as_secs()))
+    } else {
+        serializer.serialize_str(&format!("{}ms", duration.as_millis()))
+    }
+}
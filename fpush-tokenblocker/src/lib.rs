@@ -3,17 +3,32 @@ pub use config::BlacklistSettings;
 
 use dashmap::DashMap;
 use log::{error, info};
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::{
+    sync::RwLock,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// Reason a token was put on the blocklist
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockReason {
+    /// the push service reported the token as permanently invalid
+    InvalidToken,
+    /// sending a push to the token failed with an unhandled error
+    PushError,
+}
+
 pub struct FpushBlocklistValue {
     blocking_start: u64,
     blocking_end: u64,
+    reason: BlockReason,
 }
 
 impl FpushBlocklistValue {
-    pub fn new(current_timestamp: &Duration, block_time: &Duration) -> Self {
+    pub fn new(current_timestamp: &Duration, block_time: &Duration, reason: BlockReason) -> Self {
         Self {
             blocking_start: current_timestamp.as_secs(),
             blocking_end: current_timestamp.as_secs() + block_time.as_secs(),
+            reason,
         }
     }
 
@@ -45,34 +60,50 @@ impl FpushBlocklistValue {
 
 pub struct FpushBlocklist {
     token_blocklist: DashMap<String, FpushBlocklistValue>,
-    blacklist_config: BlacklistSettings,
+    blacklist_config: RwLock<BlacklistSettings>,
 }
 
 impl FpushBlocklist {
     pub fn new(blacklist_config: &BlacklistSettings) -> Self {
         Self {
             token_blocklist: DashMap::new(),
-            blacklist_config: *blacklist_config,
+            blacklist_config: RwLock::new(*blacklist_config),
         }
     }
 
+    /// Apply new blocking times, keeping all blocked tokens
+    ///
+    /// Tokens that are already blocked keep their current blocking end.
+    pub fn update_settings(&self, blacklist_config: &BlacklistSettings) {
+        *self.blacklist_config.write().unwrap() = *blacklist_config;
+    }
+
+    #[inline(always)]
+    fn settings(&self) -> BlacklistSettings {
+        *self.blacklist_config.read().unwrap()
+    }
+
     #[inline(always)]
     fn is_blocked_token(&self, token: &str) -> bool {
+        self.blocked_token_reason(token).is_some()
+    }
+
+    #[inline(always)]
+    fn blocked_token_reason(&self, token: &str) -> Option<BlockReason> {
         if let Some(mut blocklist_entry) = self.token_blocklist.get_mut(token) {
             if let Ok(timestamp) = SystemTime::now().duration_since(UNIX_EPOCH) {
                 if blocklist_entry.is_blocked(&timestamp) {
-                    blocklist_entry
-                        .extend_block(&timestamp, self.blacklist_config.block_extension());
-                    true
+                    blocklist_entry.extend_block(&timestamp, self.settings().block_extension());
+                    Some(blocklist_entry.reason)
                 } else {
-                    false
+                    None
                 }
             } else {
                 error!("Could not get current SystemTime");
-                false
+                None
             }
         } else {
-            false
+            None
         }
     }
 
@@ -80,23 +111,38 @@ impl FpushBlocklist {
         self.is_blocked_token(token)
     }
 
+    /// Return why the token is blocked, `None` if it is not blocked
+    pub fn blocked_reason(&self, token: &str) -> Option<BlockReason> {
+        self.blocked_token_reason(token)
+    }
+
     pub fn block_invalid_token(&self, token: String) {
+        let settings = self.settings();
         self.block_internal(
             token,
-            &self.blacklist_config.invalid_token().inital_blocking(),
-            &self.blacklist_config.invalid_token().extended_blocking(),
+            &settings.invalid_token().inital_blocking(),
+            &settings.invalid_token().extended_blocking(),
+            BlockReason::InvalidToken,
         )
     }
 
     pub fn block_after_unhandled_push_error(&self, token: String) {
+        let settings = self.settings();
         self.block_internal(
             token,
-            &self.blacklist_config.push_error().inital_blocking(),
-            &self.blacklist_config.push_error().extended_blocking(),
+            &settings.push_error().inital_blocking(),
+            &settings.push_error().extended_blocking(),
+            BlockReason::PushError,
         )
     }
 
-    fn block_internal(&self, token: String, block_time: &Duration, extended_block_time: &Duration) {
+    fn block_internal(
+        &self,
+        token: String,
+        block_time: &Duration,
+        extended_block_time: &Duration,
+        reason: BlockReason,
+    ) {
         if let Ok(timestamp) = SystemTime::now().duration_since(UNIX_EPOCH) {
             if let Some(mut blocklist_entry) = self.token_blocklist.get_mut(&token) {
                 if blocklist_entry.is_blocked(&timestamp) {
@@ -106,10 +152,13 @@ impl FpushBlocklist {
                     info!("Reblocking token {}", token);
                     blocklist_entry.block_and_reset(&timestamp, block_time);
                 }
+                blocklist_entry.reason = reason;
             } else {
                 info!("Blocking token {}", token);
-                self.token_blocklist
-                    .insert(token, FpushBlocklistValue::new(&timestamp, block_time));
+                self.token_blocklist.insert(
+                    token,
+                    FpushBlocklistValue::new(&timestamp, block_time, reason),
+                );
             }
         } else {
             error!("Could not get current SystemTime");
@@ -130,7 +179,7 @@ impl FpushBlocklist {
 mod tests {
     use std::{thread::sleep, time::Duration};
 
-    use crate::{config::BlacklistBlockingTimes, BlacklistSettings, FpushBlocklist};
+    use crate::{config::BlacklistBlockingTimes, BlacklistSettings, BlockReason, FpushBlocklist};
 
     #[test]
     fn extended_blocking() {
@@ -176,4 +225,44 @@ mod tests {
         assert!(!blocklist.is_blocked("some-token"));
         assert!(!blocklist.is_blocked("some-token"));
     }
+
+    #[test]
+    fn blocked_reason() {
+        let blocklist = FpushBlocklist::new(&BlacklistSettings::default());
+
+        assert_eq!(blocklist.blocked_reason("some-token"), None);
+
+        blocklist.block_after_unhandled_push_error("some-token".to_string());
+        assert_eq!(
+            blocklist.blocked_reason("some-token"),
+            Some(BlockReason::PushError)
+        );
+
+        // the push service reporting the token as invalid overrides the reason
+        blocklist.block_invalid_token("some-token".to_string());
+        assert_eq!(
+            blocklist.blocked_reason("some-token"),
+            Some(BlockReason::InvalidToken)
+        );
+    }
+
+    #[test]
+    fn update_settings_keeps_blocked_tokens() {
+        let blocklist = FpushBlocklist::new(&BlacklistSettings::default());
+        blocklist.block_invalid_token("some-token".to_string());
+
+        let settings = BlacklistSettings::new_debug_config(
+            BlacklistBlockingTimes::new(Duration::from_secs(1), Duration::from_secs(1)),
+            BlacklistBlockingTimes::new(Duration::from_secs(1), Duration::from_secs(1)),
+            Duration::from_secs(1),
+        );
+        blocklist.update_settings(&settings);
+        assert!(blocklist.is_blocked("some-token"));
+
+        // new blocks use the updated blocking times
+        blocklist.block_after_unhandled_push_error("other-token".to_string());
+        sleep(Duration::from_secs(3));
+        assert!(!blocklist.is_blocked("other-token"));
+        assert!(blocklist.is_blocked("some-token"));
+    }
 }
@@ -1 +1,4 @@
 pub mod push;
+pub mod request;
+pub mod secret;
+pub mod summary;
@@ -1,11 +1,14 @@
 use async_trait::async_trait;
 use derive_more::{Display, From};
 
+use crate::request::PushRequest;
+
 pub type PushResult<T> = std::result::Result<T, PushError>;
 
 #[derive(Debug, From, Display)]
 pub enum PushError {
-    CertLoading,
+    /// the push module could not be initialized with its configuration
+    Config(String),
     PushEndpointTmp,
     PushEndpointPersistent,
     TokenRateLimited,
@@ -16,5 +19,5 @@ pub enum PushError {
 #[async_trait]
 pub trait PushTrait {
     /// returns false if the token should be blocked
-    async fn send(&self, token: String) -> PushResult<()>;
+    async fn send(&self, request: &PushRequest) -> PushResult<()>;
 }
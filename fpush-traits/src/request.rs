@@ -0,0 +1,130 @@
+use std::collections::HashMap;
+
+use crate::summary::PushSummary;
+
+use serde::{Deserialize, Serialize};
+
+/// Urgency of a push notification as requested by the XMPP server
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum PushPriority {
+    #[default]
+    High,
+    Normal,
+}
+
+impl PushPriority {
+    /// Parse the value of a `priority` publish-option
+    pub fn from_publish_option(value: &str) -> Option<Self> {
+        match value {
+            "high" => Some(Self::High),
+            "normal" | "low" => Some(Self::Normal),
+            _ => None,
+        }
+    }
+}
+
+/// All information about a single push request that is handed to a push backend
+#[derive(Debug, Clone, Default)]
+pub struct PushRequest {
+    token: String,
+    from: Option<String>,
+    domain: Option<String>,
+    item_id: Option<String>,
+    publish_options: HashMap<String, Vec<String>>,
+    priority: PushPriority,
+    summary: PushSummary,
+    secret_verified: bool,
+}
+
+impl PushRequest {
+    pub fn new(token: String) -> Self {
+        Self {
+            token,
+            ..Default::default()
+        }
+    }
+
+    /// Replace the token, e.g. after resolving an opaque pubsub node to the device token
+    pub fn with_token(mut self, token: String) -> Self {
+        self.token = token;
+        self
+    }
+
+    /// Set the JID and XMPP domain that requested the push
+    pub fn with_sender(mut self, from: String, domain: String) -> Self {
+        self.from = Some(from);
+        self.domain = Some(domain);
+        self
+    }
+
+    pub fn with_item_id(mut self, item_id: Option<String>) -> Self {
+        self.item_id = item_id;
+        self
+    }
+
+    pub fn with_publish_options(mut self, publish_options: HashMap<String, Vec<String>>) -> Self {
+        self.publish_options = publish_options;
+        self
+    }
+
+    pub fn with_priority(mut self, priority: PushPriority) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    pub fn with_summary(mut self, summary: PushSummary) -> Self {
+        self.summary = summary;
+        self
+    }
+
+    /// Mark the sender as already authenticated, e.g. by the secret of a device registration
+    ///
+    /// Removes the `secret` publish-option, so the secret of the push module is not checked
+    /// against it and it is never handed to a push backend.
+    pub fn with_verified_secret(mut self) -> Self {
+        self.publish_options.remove("secret");
+        self.secret_verified = true;
+        self
+    }
+
+    pub fn token(&self) -> &str {
+        &self.token
+    }
+
+    pub fn from(&self) -> Option<&str> {
+        self.from.as_deref()
+    }
+
+    pub fn domain(&self) -> Option<&str> {
+        self.domain.as_deref()
+    }
+
+    pub fn item_id(&self) -> Option<&str> {
+        self.item_id.as_deref()
+    }
+
+    pub fn publish_options(&self) -> &HashMap<String, Vec<String>> {
+        &self.publish_options
+    }
+
+    /// Return the first value of a publish-option
+    pub fn publish_option(&self, var: &str) -> Option<&str> {
+        self.publish_options
+            .get(var)
+            .and_then(|values| values.first())
+            .map(|value| value.as_str())
+    }
+
+    pub fn priority(&self) -> PushPriority {
+        self.priority
+    }
+
+    pub fn summary(&self) -> &PushSummary {
+        &self.summary
+    }
+
+    pub fn secret_verified(&self) -> bool {
+        self.secret_verified
+    }
+}
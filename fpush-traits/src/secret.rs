@@ -0,0 +1,126 @@
+use std::{fmt, path::PathBuf};
+
+use serde::Deserialize;
+
+/// Secret setting given inline or as reference to a file or environment variable
+///
+/// References are resolved each time the secret is used, so a push module reads changed
+/// secret files when it is loaded again on reload.
+#[derive(Deserialize, Clone, PartialEq, Eq)]
+#[serde(
+    untagged,
+    expecting = "invalid secret, expected a string, {\"file\": <path>} or {\"env\": <environment variable>}"
+)]
+pub enum SecretValue {
+    /// `{"file": "/run/secrets/key"}`, a single trailing newline is removed
+    File {
+        file: PathBuf,
+    },
+    /// `{"env": "XMPP_COMPONENT_KEY"}`
+    Env {
+        env: String,
+    },
+    Inline(String),
+}
+
+impl SecretValue {
+    /// Return the secret, reading referenced files and environment variables
+    pub fn resolve(&self) -> Result<String, String> {
+        match self {
+            SecretValue::File { file } => match std::fs::read_to_string(file) {
+                Ok(secret) => {
+                    let secret = secret.strip_suffix('\n').unwrap_or(&secret);
+                    Ok(secret.strip_suffix('\r').unwrap_or(secret).to_string())
+                }
+                Err(e) => Err(format!("could not read {}: {}", self, e)),
+            },
+            SecretValue::Env { env } => {
+                std::env::var(env).map_err(|e| format!("could not read {}: {}", self, e))
+            }
+            SecretValue::Inline(secret) => Ok(secret.clone()),
+        }
+    }
+
+    /// Return true if the secret is read from a file or environment variable
+    pub fn is_reference(&self) -> bool {
+        !matches!(self, SecretValue::Inline(_))
+    }
+}
+
+/// Describes where the secret is read from without revealing it
+impl fmt::Display for SecretValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SecretValue::File { file } => write!(f, "secret file {}", file.display()),
+            SecretValue::Env { env } => write!(f, "environment variable {}", env),
+            SecretValue::Inline(_) => write!(f, "inline secret"),
+        }
+    }
+}
+
+impl fmt::Debug for SecretValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SecretValue::File { file } => f.debug_struct("File").field("file", file).finish(),
+            SecretValue::Env { env } => f.debug_struct("Env").field("env", env).finish(),
+            SecretValue::Inline(_) => write!(f, "Inline(<redacted>)"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn secret(settings: serde_json::Value) -> SecretValue {
+        serde_json::from_value(settings).unwrap()
+    }
+
+    #[test]
+    fn test_resolve_secrets() {
+        assert_eq!(
+            secret(serde_json::json!("inline")).resolve().unwrap(),
+            "inline"
+        );
+
+        let path = std::env::temp_dir().join(format!("fpush-secret-{}", std::process::id()));
+        std::fs::write(&path, "from file\r\n").unwrap();
+        let file_secret = secret(serde_json::json!({ "file": path }));
+        assert!(file_secret.is_reference());
+        assert_eq!(file_secret.resolve().unwrap(), "from file");
+        std::fs::remove_file(&path).unwrap();
+        assert!(file_secret
+            .resolve()
+            .unwrap_err()
+            .starts_with("could not read"));
+
+        std::env::set_var("FPUSH_TEST_SECRET_VALUE", "from env");
+        let env_secret = secret(serde_json::json!({ "env": "FPUSH_TEST_SECRET_VALUE" }));
+        assert!(env_secret.is_reference());
+        assert_eq!(env_secret.resolve().unwrap(), "from env");
+        let missing_env = secret(serde_json::json!({ "env": "FPUSH_TEST_SECRET_MISSING" }));
+        assert_eq!(
+            missing_env.resolve().unwrap_err(),
+            "could not read environment variable FPUSH_TEST_SECRET_MISSING: environment variable not found"
+        );
+    }
+
+    #[test]
+    fn test_secret_is_redacted() {
+        let inline = secret(serde_json::json!("hunter2"));
+        assert!(!inline.is_reference());
+        assert_eq!(inline.to_string(), "inline secret");
+        assert!(!format!("{:?}", inline).contains("hunter2"));
+        assert_eq!(
+            secret(serde_json::json!({ "env": "FPUSH_KEY" })).to_string(),
+            "environment variable FPUSH_KEY"
+        );
+    }
+
+    #[test]
+    fn test_invalid_secret() {
+        let e = serde_json::from_value::<SecretValue>(serde_json::json!({ "path": "/tmp" }))
+            .unwrap_err();
+        assert!(e.to_string().starts_with("invalid secret"));
+    }
+}
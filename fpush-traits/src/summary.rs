@@ -0,0 +1,63 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+/// Notification summary of a XEP-0357 push as sent inside the `urn:xmpp:push:summary` form
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct PushSummary {
+    message_count: Option<u64>,
+    last_message_sender: Option<String>,
+    last_message_body: Option<String>,
+    pending_subscription_count: Option<u64>,
+    /// all fields of the summary form as sent by the XMPP server
+    fields: HashMap<String, Vec<String>>,
+}
+
+impl PushSummary {
+    pub fn new(
+        message_count: Option<u64>,
+        last_message_sender: Option<String>,
+        last_message_body: Option<String>,
+        pending_subscription_count: Option<u64>,
+    ) -> Self {
+        Self {
+            message_count,
+            last_message_sender,
+            last_message_body,
+            pending_subscription_count,
+            fields: HashMap::new(),
+        }
+    }
+
+    pub fn with_fields(mut self, fields: HashMap<String, Vec<String>>) -> Self {
+        self.fields = fields;
+        self
+    }
+
+    pub fn message_count(&self) -> Option<u64> {
+        self.message_count
+    }
+
+    pub fn last_message_sender(&self) -> Option<&str> {
+        self.last_message_sender.as_deref()
+    }
+
+    pub fn last_message_body(&self) -> Option<&str> {
+        self.last_message_body.as_deref()
+    }
+
+    pub fn pending_subscription_count(&self) -> Option<u64> {
+        self.pending_subscription_count
+    }
+
+    /// Return the values of a summary field, `None` if the XMPP server did not include it
+    pub fn field(&self, var: &str) -> Option<&[String]> {
+        self.fields.get(var).map(|values| values.as_slice())
+    }
+
+    /// true if the XMPP server did not include any summary field
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
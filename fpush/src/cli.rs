@@ -0,0 +1,90 @@
+use clap::{Args, Parser, Subcommand};
+
+/// Push appserver for XMPP push notifications (XEP-0357)
+#[derive(Debug, Parser)]
+#[command(version, about, args_conflicts_with_subcommands = true)]
+pub(crate) struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// config file used if no subcommand is given, runs fpush like `run`
+    #[arg(default_value = "./settings.json")]
+    settings: String,
+    /// validate the config file like the `check-config` subcommand
+    #[arg(long, value_name = "SETTINGS", num_args = 0..=1, default_missing_value = "./settings.json")]
+    check_config: Option<String>,
+}
+
+#[derive(Debug, Subcommand)]
+pub(crate) enum Command {
+    /// Connect to the XMPP server and handle push requests
+    Run(ConfigArgs),
+    /// Validate the config file, including certificate and secret files, without connecting
+    CheckConfig(ConfigArgs),
+    /// Send a single push notification through the configured push modules without XMPP
+    SendTestPush(SendTestPushArgs),
+    /// Print a config file with placeholders for required settings and all default settings
+    PrintDefaultConfig,
+}
+
+#[derive(Debug, Args)]
+pub(crate) struct ConfigArgs {
+    /// path of the config file
+    #[arg(default_value = "./settings.json")]
+    pub(crate) settings: String,
+}
+
+#[derive(Debug, Args)]
+pub(crate) struct SendTestPushArgs {
+    #[command(flatten)]
+    pub(crate) config: ConfigArgs,
+    /// identifier of the push module, `default` for the default push module
+    #[arg(long)]
+    pub(crate) module: String,
+    /// push token of the device
+    #[arg(long)]
+    pub(crate) token: String,
+    /// value of the `secret` publish-option, if the push module requires one
+    #[arg(long)]
+    pub(crate) secret: Option<String>,
+}
+
+impl Cli {
+    /// Return the subcommand to execute, `run` if none was given
+    pub(crate) fn command(self) -> Command {
+        if let Some(settings) = self.check_config {
+            return Command::CheckConfig(ConfigArgs { settings });
+        }
+        self.command.unwrap_or(Command::Run(ConfigArgs {
+            settings: self.settings,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Command {
+        Cli::try_parse_from(args).unwrap().command()
+    }
+
+    #[test]
+    fn test_check_config_alias() {
+        assert!(matches!(
+            parse(&["fpush", "--check-config", "settings.json"]),
+            Command::CheckConfig(ConfigArgs { settings }) if settings == "settings.json"
+        ));
+        assert!(matches!(
+            parse(&["fpush", "--check-config"]),
+            Command::CheckConfig(ConfigArgs { settings }) if settings == "./settings.json"
+        ));
+        assert!(matches!(
+            parse(&["fpush", "check-config", "settings.json"]),
+            Command::CheckConfig(ConfigArgs { settings }) if settings == "settings.json"
+        ));
+        assert!(matches!(
+            parse(&["fpush", "settings.json"]),
+            Command::Run(ConfigArgs { settings }) if settings == "settings.json"
+        ));
+    }
+}
@@ -0,0 +1,651 @@
+use std::path::Path;
+
+use crate::error::{Error, Result};
+
+use log::warn;
+use serde_json::Value;
+
+/// Prefix of environment variables overriding settings of the config file
+const ENV_PREFIX: &str = "FPUSH_";
+/// Separator between the keys of nested settings in environment variable names
+const ENV_SEPARATOR: &str = "__";
+
+/// Format of a config file, chosen by its file extension
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ConfigFormat {
+    Json,
+    Toml,
+    Yaml,
+}
+
+impl ConfigFormat {
+    /// Files without or with an unknown extension are read as JSON
+    pub(crate) fn from_path(config_path: &str) -> Self {
+        match Path::new(config_path)
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .map(|extension| extension.to_ascii_lowercase())
+            .as_deref()
+        {
+            Some("toml") => ConfigFormat::Toml,
+            Some("yaml") | Some("yml") => ConfigFormat::Yaml,
+            _ => ConfigFormat::Json,
+        }
+    }
+
+    /// Parse `settings` into a format independent tree of settings
+    pub(crate) fn parse(&self, settings: &str) -> std::result::Result<Value, String> {
+        match self {
+            ConfigFormat::Json => serde_json::from_str(settings).map_err(|e| e.to_string()),
+            ConfigFormat::Toml => toml::from_str(settings).map_err(|e| e.to_string()),
+            ConfigFormat::Yaml => serde_yaml_ng::from_str(settings).map_err(|e| e.to_string()),
+        }
+    }
+}
+
+/// Read the config file and apply the overrides from `FPUSH_` environment variables
+pub(crate) fn read_settings(config_path: &str) -> Result<Value> {
+    let settings = std::fs::read_to_string(config_path)
+        .map_err(|e| Error::Config(format!("Could not read {}: {}", config_path, e)))?;
+    let mut settings = ConfigFormat::from_path(config_path)
+        .parse(&settings)
+        .map_err(Error::Config)?;
+    // variables that are not valid unicode can not be config overrides
+    let vars = std::env::vars_os()
+        .filter_map(|(name, value)| Some((name.into_string().ok()?, value.into_string().ok()?)));
+    apply_env_overrides(&mut settings, vars)?;
+    Ok(settings)
+}
+
+/// Override settings with the values of environment variables starting with `FPUSH_`
+///
+/// `FPUSH_COMPONENT__COMPONENT_KEY` sets `component.componentKey`. Each key separated by `__`
+/// is matched against the setting names of its section ignoring case and underscores, see
+/// `SETTINGS`. Variables not naming a setting are logged and ignored. Variables are applied in
+/// sorted order, so nested overrides are deterministic.
+pub(crate) fn apply_env_overrides(
+    settings: &mut Value,
+    vars: impl Iterator<Item = (String, String)>,
+) -> Result<()> {
+    let mut overrides: Vec<(String, String)> = vars
+        .filter(|(name, _)| name.starts_with(ENV_PREFIX))
+        .collect();
+    overrides.sort();
+    for (name, value) in overrides {
+        let keys: Vec<&str> = name[ENV_PREFIX.len()..].split(ENV_SEPARATOR).collect();
+        if keys.iter().any(|key| key.is_empty()) {
+            warn!("Ignoring environment variable {}: empty key", name);
+            continue;
+        }
+        let target = match override_target(settings, &SETTINGS, &keys) {
+            Ok(target) => target,
+            Err(reason) => {
+                warn!("Ignoring environment variable {}: {}", name, reason);
+                continue;
+            }
+        };
+        *target = override_value(target, &value).map_err(|reason| {
+            Error::Config(format!("Invalid config override {}: {}", name, reason))
+        })?;
+    }
+    Ok(())
+}
+
+/// Shape of the settings, naming every key the way serde expects it
+enum Schema {
+    /// single setting, keys below it are matched against the existing keys or added in camelCase
+    Value,
+    /// section with a fixed set of settings
+    Section(&'static [(&'static str, Schema)]),
+    /// section whose keys are chosen in the config file, e.g. the push module ids
+    Map(&'static Schema),
+    /// list of settings selected by their index
+    List(&'static Schema),
+    /// single section or a list of them, like `component`
+    OneOrList(&'static Schema),
+}
+
+static SETTINGS: Schema = Schema::Section(&[
+    ("component", Schema::OneOrList(&COMPONENT)),
+    ("pushModules", Schema::Map(&PUSH_MODULE)),
+    (
+        "timeout",
+        Schema::Section(&[
+            ("xmppconnectionError", Schema::Value),
+            ("shutdownDeadline", Schema::Value),
+        ]),
+    ),
+    (
+        "registration",
+        Schema::Section(&[
+            ("storePath", Schema::Value),
+            ("commands", Schema::Map(&Schema::Value)),
+            ("allowUnregisteredTokens", Schema::Value),
+            ("maxRegistrationsPerAccount", Schema::Value),
+        ]),
+    ),
+    (
+        "domainRatelimit",
+        Schema::Section(&[
+            ("requestsPerSecond", Schema::Value),
+            ("burst", Schema::Value),
+            ("policy", Schema::Value),
+            ("maxDelay", Schema::Value),
+            ("cleanupInterval", Schema::Value),
+            ("enabled", Schema::Value),
+        ]),
+    ),
+    (
+        "concurrency",
+        Schema::Section(&[
+            ("maxInFlight", Schema::Value),
+            ("queueSize", Schema::Value),
+            ("overflow", Schema::Value),
+        ]),
+    ),
+    (
+        "batching",
+        Schema::Section(&[
+            ("maxBatchSize", Schema::Value),
+            ("flushInterval", Schema::Value),
+            ("metricsInterval", Schema::Value),
+        ]),
+    ),
+    (
+        "reconnect",
+        Schema::Section(&[
+            ("maxDelay", Schema::Value),
+            ("multiplier", Schema::Value),
+            ("jitter", Schema::Value),
+            ("onAuthFailure", Schema::Value),
+            ("statusFile", Schema::Value),
+        ]),
+    ),
+    (
+        "pushQueue",
+        Schema::Section(&[
+            ("path", Schema::Value),
+            ("maxAge", Schema::Value),
+            ("maxEntries", Schema::Value),
+            ("replayInterval", Schema::Value),
+        ]),
+    ),
+]);
+
+static COMPONENT: Schema = Schema::Section(&[
+    ("componentHostname", Schema::Value),
+    ("componentKey", Schema::Value),
+    ("serverHostname", Schema::Value),
+    ("serverPort", Schema::Value),
+    ("socketPath", Schema::Value),
+    (
+        "tls",
+        Schema::Section(&[
+            ("mode", Schema::Value),
+            ("caFile", Schema::Value),
+            ("serverName", Schema::Value),
+            ("clientCertFile", Schema::Value),
+            ("clientKeyFile", Schema::Value),
+        ]),
+    ),
+]);
+
+static PUSH_MODULE: Schema = Schema::Section(&[
+    ("type", Schema::Value),
+    (
+        "apns",
+        Schema::Section(&[
+            ("certFilePath", Schema::Value),
+            ("certPassword", Schema::Value),
+            ("topic", Schema::Value),
+            ("environment", Schema::Value),
+            ("badgeFromSummary", Schema::Value),
+            ("senderAsSubtitle", Schema::Value),
+        ]),
+    ),
+    (
+        "fcm",
+        Schema::Section(&[
+            ("fcmSecretPath", Schema::Value),
+            ("fcmSecret", Schema::Value),
+            ("forwardSummary", Schema::Value),
+        ]),
+    ),
+    (
+        "blacklist",
+        Schema::Section(&[
+            ("invalidToken", BLOCKING_TIMES),
+            ("pushError", BLOCKING_TIMES),
+            ("blockExtension", Schema::Value),
+        ]),
+    ),
+    (
+        "ratelimit",
+        Schema::Section(&[
+            ("hardRatelimitTime", Schema::Value),
+            ("ratelimitTime", Schema::Value),
+            ("ratelimitCleanupInterval", Schema::Value),
+            ("enabled", Schema::Value),
+        ]),
+    ),
+    // the only snake_case setting
+    ("is_default_module", Schema::Value),
+    (
+        "secret",
+        Schema::Section(&[
+            ("mode", Schema::Value),
+            ("secret", Schema::Value),
+            ("key", Schema::Value),
+        ]),
+    ),
+    (
+        "errorPolicy",
+        Schema::Section(&[
+            ("tokenRatelimited", ERROR_REPLY),
+            ("tokenBlocked", ERROR_REPLY),
+            ("tokenInvalid", ERROR_REPLY),
+            ("notAuthorized", ERROR_REPLY),
+            ("filtered", ERROR_REPLY),
+            ("internal", ERROR_REPLY),
+            ("queued", ERROR_REPLY),
+            ("unknownPushModule", ERROR_REPLY),
+        ]),
+    ),
+    ("aliases", Schema::Value),
+    (
+        "filters",
+        Schema::List(&Schema::Section(&[
+            ("name", Schema::Value),
+            (
+                "summaryField",
+                Schema::Section(&[("var", Schema::Value), ("condition", Schema::Value)]),
+            ),
+            ("senderDomains", Schema::Value),
+            ("hasPublishOptions", Schema::Value),
+            ("action", Schema::Value),
+        ])),
+    ),
+    (
+        "retry",
+        Schema::Section(&[
+            ("maxAttempts", Schema::Value),
+            ("initialBackoff", Schema::Value),
+            ("multiplier", Schema::Value),
+            ("maxBackoff", Schema::Value),
+            ("deadline", Schema::Value),
+            ("mode", Schema::Value),
+        ]),
+    ),
+]);
+
+const BLOCKING_TIMES: Schema = Schema::Section(&[
+    ("initalBlocking", Schema::Value),
+    ("extendedBlocking", Schema::Value),
+]);
+
+const ERROR_REPLY: Schema = Schema::Section(&[
+    ("action", Schema::Value),
+    ("type", Schema::Value),
+    ("condition", Schema::Value),
+    ("text", Schema::Value),
+    ("retryAfter", Schema::Value),
+]);
+
+/// Key of a setting within its section or list
+enum PathKey {
+    Key(String),
+    Index(usize),
+}
+
+/// Return the keys of the setting addressed by `keys` as named in the config file
+///
+/// Nothing is created yet, so variables not naming a setting leave the settings untouched.
+fn override_path(
+    settings: &Value,
+    schema: &Schema,
+    keys: &[&str],
+) -> std::result::Result<Vec<PathKey>, String> {
+    let mut path = Vec::with_capacity(keys.len());
+    let mut current = Some(settings);
+    let mut schema = schema;
+    for key in keys {
+        let current_value = current.filter(|value| !value.is_null());
+        if let Schema::OneOrList(section) = schema {
+            if !matches!(current_value, Some(Value::Array(_))) {
+                schema = section;
+            }
+        }
+        let (path_key, next_schema) = match (current_value, schema) {
+            // e.g. FPUSH_COMPONENT__0__COMPONENT_KEY for a list of component connections
+            (
+                Some(Value::Array(values)),
+                Schema::List(_) | Schema::OneOrList(_) | Schema::Value,
+            ) => {
+                let index = key
+                    .parse::<usize>()
+                    .ok()
+                    .filter(|index| *index < values.len())
+                    .ok_or_else(|| format!("{} is not an index of the list", key))?;
+                let item = match schema {
+                    Schema::List(item) | Schema::OneOrList(item) => *item,
+                    _ => &Schema::Value,
+                };
+                (PathKey::Index(index), item)
+            }
+            (None | Some(Value::Object(_)), Schema::Section(fields)) => {
+                let (name, field) = fields
+                    .iter()
+                    .find(|(name, _)| normalize_key(name) == normalize_key(key))
+                    .ok_or_else(|| format!("{} is not a setting", key))?;
+                (PathKey::Key(name.to_string()), field)
+            }
+            (None | Some(Value::Object(_)), Schema::Map(_) | Schema::Value) => {
+                let existing_key = current_value
+                    .and_then(|value| value.as_object())
+                    .and_then(|map| {
+                        map.keys()
+                            .find(|existing| normalize_key(existing) == normalize_key(key))
+                    })
+                    .cloned();
+                match schema {
+                    // keys of maps are ids like the push module id and keep their case
+                    Schema::Map(value) => (
+                        PathKey::Key(existing_key.unwrap_or_else(|| key.to_string())),
+                        *value,
+                    ),
+                    _ => (
+                        PathKey::Key(existing_key.unwrap_or_else(|| camel_case(key))),
+                        &Schema::Value,
+                    ),
+                }
+            }
+            (None, Schema::List(_)) => return Err(format!("{} is not an index of the list", key)),
+            _ => return Err(format!("{} is not part of a section", key)),
+        };
+        current = match (current_value, &path_key) {
+            (Some(Value::Array(values)), PathKey::Index(index)) => values.get(*index),
+            (Some(Value::Object(map)), PathKey::Key(name)) => map.get(name),
+            _ => None,
+        };
+        path.push(path_key);
+        schema = next_schema;
+    }
+    Ok(path)
+}
+
+/// Return the setting addressed by `keys`, creating missing sections on the way
+fn override_target<'a>(
+    settings: &'a mut Value,
+    schema: &Schema,
+    keys: &[&str],
+) -> std::result::Result<&'a mut Value, String> {
+    let path = override_path(settings, schema, keys)?;
+    let mut current = settings;
+    for (key, path_key) in keys.iter().zip(path) {
+        if current.is_null() {
+            *current = Value::Object(serde_json::Map::new());
+        }
+        current = match (current, path_key) {
+            (Value::Array(values), PathKey::Index(index)) => values
+                .get_mut(index)
+                .ok_or_else(|| format!("{} is not an index of the list", key))?,
+            (Value::Object(map), PathKey::Key(name)) => map.entry(name).or_insert(Value::Null),
+            _ => return Err(format!("{} is not part of a section", key)),
+        };
+    }
+    Ok(current)
+}
+
+/// Parse the override keeping the type of the replaced setting
+///
+/// New settings are parsed as JSON if possible, so lists and numbers can be set. Quote the
+/// value to force a string, e.g. `FPUSH_COMPONENT__COMPONENT_KEY='"1234"'`.
+fn override_value(current: &Value, value: &str) -> std::result::Result<Value, String> {
+    match current {
+        Value::String(_) => Ok(Value::String(value.to_string())),
+        Value::Bool(_) | Value::Number(_) => match serde_json::from_str::<Value>(value) {
+            Ok(parsed @ Value::Bool(_)) | Ok(parsed @ Value::Number(_)) => Ok(parsed),
+            _ => Err(format!(
+                "expected a {}",
+                if current.is_boolean() {
+                    "boolean"
+                } else {
+                    "number"
+                }
+            )),
+        },
+        _ => Ok(serde_json::from_str(value).unwrap_or_else(|_| Value::String(value.to_string()))),
+    }
+}
+
+fn normalize_key(key: &str) -> String {
+    key.chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect()
+}
+
+/// Convert a SCREAMING_SNAKE_CASE key to the camelCase used in the config file
+fn camel_case(key: &str) -> String {
+    let mut camel_case = String::with_capacity(key.len());
+    for (index, word) in key.split('_').filter(|word| !word.is_empty()).enumerate() {
+        let word = word.to_ascii_lowercase();
+        if index == 0 {
+            camel_case.push_str(&word);
+        } else {
+            let mut chars = word.chars();
+            if let Some(first) = chars.next() {
+                camel_case.push(first.to_ascii_uppercase());
+                camel_case.extend(chars);
+            }
+        }
+    }
+    camel_case
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn overrides(settings: &mut Value, vars: &[(&str, &str)]) -> Result<()> {
+        apply_env_overrides(
+            settings,
+            vars.iter()
+                .map(|(name, value)| (name.to_string(), value.to_string())),
+        )
+    }
+
+    fn settings() -> Value {
+        json!({
+            "component": {
+                "componentHostname": "push.example.org",
+                "componentKey": "1234",
+                "serverHostname": "localhost",
+                "serverPort": 5347
+            },
+            "pushModules": {
+                "monalProdiOS": {
+                    "type": "apple",
+                    "apns": { "certFilePath": "/etc/fpush/prod.p12", "certPassword": "", "topic": "im.monal.prod" }
+                }
+            }
+        })
+    }
+
+    #[test]
+    fn test_config_format_from_path() {
+        assert_eq!(ConfigFormat::from_path("settings.json"), ConfigFormat::Json);
+        assert_eq!(
+            ConfigFormat::from_path("/etc/fpush/settings.toml"),
+            ConfigFormat::Toml
+        );
+        assert_eq!(ConfigFormat::from_path("settings.yaml"), ConfigFormat::Yaml);
+        assert_eq!(ConfigFormat::from_path("settings.YML"), ConfigFormat::Yaml);
+        assert_eq!(ConfigFormat::from_path("settings"), ConfigFormat::Json);
+        assert_eq!(ConfigFormat::from_path("settings.conf"), ConfigFormat::Json);
+    }
+
+    #[test]
+    fn test_config_format_parse() {
+        let expected = json!({ "component": { "serverPort": 5347 }, "pushModules": {} });
+        let json = r#"{ "component": { "serverPort": 5347 }, "pushModules": {} }"#;
+        let toml = "pushModules = {}\n[component]\nserverPort = 5347\n";
+        let yaml = "component:\n  serverPort: 5347\npushModules: {}\n";
+        assert_eq!(ConfigFormat::Json.parse(json).unwrap(), expected);
+        assert_eq!(ConfigFormat::Toml.parse(toml).unwrap(), expected);
+        assert_eq!(ConfigFormat::Yaml.parse(yaml).unwrap(), expected);
+        assert!(ConfigFormat::Toml.parse(json).is_err());
+        assert!(ConfigFormat::Json.parse(yaml).is_err());
+    }
+
+    #[test]
+    fn test_env_overrides_use_serde_names() {
+        let mut settings = settings();
+        overrides(
+            &mut settings,
+            &[
+                ("FPUSH_COMPONENT__SERVER_PORT", "5348"),
+                (
+                    "FPUSH_PUSH_MODULES__MONALPRODIOS__IS_DEFAULT_MODULE",
+                    "true",
+                ),
+                (
+                    "FPUSH_PUSH_MODULES__MONALPRODIOS__APNS__CERT_PASSWORD",
+                    "secret",
+                ),
+                ("FPUSH_TIMEOUT__SHUTDOWN_DEADLINE", "10s"),
+                ("FPUSH_COMPONENT__TLS__CA_FILE", "/etc/ssl/ca.pem"),
+            ],
+        )
+        .unwrap();
+        assert_eq!(settings["component"]["serverPort"], json!(5348));
+        let module = &settings["pushModules"]["monalProdiOS"];
+        assert_eq!(module["is_default_module"], json!(true));
+        assert_eq!(module["apns"]["certPassword"], json!("secret"));
+        assert_eq!(settings["timeout"]["shutdownDeadline"], json!("10s"));
+        assert_eq!(
+            settings["component"]["tls"]["caFile"],
+            json!("/etc/ssl/ca.pem")
+        );
+    }
+
+    #[test]
+    fn test_env_overrides_create_map_keys_in_their_case() {
+        let mut settings = settings();
+        overrides(
+            &mut settings,
+            &[
+                ("FPUSH_PUSH_MODULES__monalSandboxiOS__TYPE", "apple"),
+                (
+                    "FPUSH_PUSH_MODULES__monalSandboxiOS__APNS__ENVIRONMENT",
+                    "sandbox",
+                ),
+                (
+                    "FPUSH_REGISTRATION__COMMANDS__register-push-apns",
+                    "monalSandboxiOS",
+                ),
+            ],
+        )
+        .unwrap();
+        let module = &settings["pushModules"]["monalSandboxiOS"];
+        assert_eq!(module["type"], json!("apple"));
+        assert_eq!(module["apns"]["environment"], json!("sandbox"));
+        assert_eq!(
+            settings["registration"]["commands"]["register-push-apns"],
+            json!("monalSandboxiOS")
+        );
+    }
+
+    #[test]
+    fn test_env_overrides_ignore_unknown_variables() {
+        let mut settings = settings();
+        overrides(
+            &mut settings,
+            &[
+                ("FPUSH_", "1"),
+                ("FPUSH_COMPONENT_KEY", "1234"),
+                ("FPUSH_COMPONENT____SERVER_PORT", "1"),
+                ("FPUSH_COMPONENT__NOT_A_SETTING", "1"),
+                ("FPUSH_COMPONENT__0__SERVER_PORT", "1"),
+                ("FPUSH_COMPONENT__SERVER_HOSTNAME__HOST", "localhost"),
+                (
+                    "FPUSH_PUSH_MODULES__monalSandboxiOS__CERT_PASSWORD",
+                    "secret",
+                ),
+                (
+                    "FPUSH_PUSH_MODULES__MONALPRODIOS__FILTERS__0__ACTION",
+                    "drop",
+                ),
+                ("OTHER_COMPONENT__SERVER_PORT", "1"),
+            ],
+        )
+        .unwrap();
+        assert_eq!(settings, self::settings());
+    }
+
+    #[test]
+    fn test_env_overrides_select_list_items() {
+        let mut settings = json!({
+            "component": [
+                { "componentHostname": "push.example.org", "serverPort": 5347 },
+                { "componentHostname": "push.example.org", "serverPort": 5347 }
+            ],
+            "pushModules": { "monalProdiOS": { "filters": [{ "action": "allow" }] } }
+        });
+        overrides(
+            &mut settings,
+            &[
+                ("FPUSH_COMPONENT__1__SERVER_PORT", "5348"),
+                ("FPUSH_COMPONENT__2__SERVER_PORT", "5349"),
+                ("FPUSH_COMPONENT__SERVER_PORT", "5350"),
+                (
+                    "FPUSH_PUSH_MODULES__MONALPRODIOS__FILTERS__0__ACTION",
+                    "drop",
+                ),
+            ],
+        )
+        .unwrap();
+        assert_eq!(settings["component"][0]["serverPort"], json!(5347));
+        assert_eq!(settings["component"][1]["serverPort"], json!(5348));
+        assert_eq!(settings["component"].as_array().unwrap().len(), 2);
+        assert_eq!(
+            settings["pushModules"]["monalProdiOS"]["filters"][0]["action"],
+            json!("drop")
+        );
+    }
+
+    #[test]
+    fn test_env_overrides_keep_setting_types() {
+        let mut settings = settings();
+        overrides(
+            &mut settings,
+            &[
+                ("FPUSH_COMPONENT__COMPONENT_KEY", "5678"),
+                (
+                    "FPUSH_PUSH_MODULES__MONALPRODIOS__ALIASES",
+                    r#"["monalProd"]"#,
+                ),
+                ("FPUSH_PUSH_MODULES__MONALPRODIOS__APNS__TOPIC", "im.monal"),
+                (
+                    "FPUSH_PUSH_MODULES__MONALPRODIOS__FCM__FCM_SECRET__FILE",
+                    "/run/secrets/fcm",
+                ),
+            ],
+        )
+        .unwrap();
+        assert_eq!(settings["component"]["componentKey"], json!("5678"));
+        let module = &settings["pushModules"]["monalProdiOS"];
+        assert_eq!(module["aliases"], json!(["monalProd"]));
+        assert_eq!(module["apns"]["topic"], json!("im.monal"));
+        assert_eq!(
+            module["fcm"]["fcmSecret"],
+            json!({ "file": "/run/secrets/fcm" })
+        );
+
+        assert!(matches!(
+            overrides(&mut settings, &[("FPUSH_COMPONENT__SERVER_PORT", "xmpp")]),
+            Err(Error::Config(_))
+        ));
+    }
+}
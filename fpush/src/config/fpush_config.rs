@@ -1,40 +1,296 @@
-use std::time::Duration;
+use std::{collections::HashMap, path::PathBuf, time::Duration};
 
-use crate::error::Result;
-use fpush_push::FpushPushConfig;
+use crate::{config::config_source::read_settings, error::Result};
+use fpush_push::{FpushPushConfig, PushBackendConfig, PushConfig, PushQueueSettings, SecretValue};
+use fpush_ratelimit::{DomainRatelimitSettings, RatelimitSettings};
+use fpush_tokenblocker::BlacklistSettings;
 
 use derive_getters::Getters;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Deserialize, Getters)]
 #[serde(rename_all = "camelCase")]
 pub(crate) struct FpushConfig {
-    component: FpushComponentSettings,
+    /// either a single component connection or a list of them
+    #[serde(rename = "component", deserialize_with = "one_or_many")]
+    components: Vec<FpushComponentSettings>,
     push_modules: FpushPushConfig,
     #[serde(default)]
     timeout: TimeoutConfig,
+    #[serde(default)]
+    registration: Option<RegistrationConfig>,
+    #[serde(default)]
+    domain_ratelimit: DomainRatelimitSettings,
+    #[serde(default)]
+    concurrency: ConcurrencyConfig,
+    #[serde(default)]
+    batching: BatchingConfig,
+    #[serde(default)]
+    reconnect: ReconnectConfig,
+    /// persist push requests that failed transiently and replay them later
+    #[serde(default)]
+    push_queue: Option<PushQueueSettings>,
 }
 
-#[derive(Debug, Deserialize, Getters)]
+#[derive(Debug, Deserialize, Getters, Clone)]
 #[serde(rename_all = "camelCase")]
 pub(crate) struct FpushComponentSettings {
     component_hostname: String,
-    component_key: String,
-    server_hostname: String,
-    server_port: u16,
+    component_key: SecretValue,
+    #[serde(flatten)]
+    endpoint: ComponentEndpoint,
+    /// protect the component stream with TLS, plain TCP if unset
+    #[serde(default)]
+    tls: Option<ComponentTlsConfig>,
+}
+
+/// Address of the component endpoint of the XMPP server
+#[derive(Debug, Deserialize, Clone)]
+#[serde(untagged)]
+pub(crate) enum ComponentEndpoint {
+    #[serde(rename_all = "camelCase")]
+    Tcp {
+        server_hostname: String,
+        server_port: u16,
+    },
+    /// Unix domain socket of an XMPP server running on the same host
+    #[serde(rename_all = "camelCase")]
+    Unix { socket_path: PathBuf },
+}
+
+impl std::fmt::Display for ComponentEndpoint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ComponentEndpoint::Tcp {
+                server_hostname,
+                server_port,
+            } => write!(f, "{}:{}", server_hostname, server_port),
+            ComponentEndpoint::Unix { socket_path } => write!(f, "unix:{}", socket_path.display()),
+        }
+    }
+}
+
+/// TLS settings of a component connection
+#[derive(Debug, Deserialize, Getters, Clone)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct ComponentTlsConfig {
+    mode: ComponentTlsMode,
+    /// PEM file of the CA certificates to trust instead of the system certificates
+    #[serde(default)]
+    ca_file: Option<PathBuf>,
+    /// name the server certificate is verified against, defaults to `serverHostname`
+    #[serde(default)]
+    server_name: Option<String>,
+    /// PEM file of the client certificate chain presented to the server
+    #[serde(default)]
+    client_cert_file: Option<PathBuf>,
+    /// PEM file of the private key of the client certificate
+    #[serde(default)]
+    client_key_file: Option<PathBuf>,
 }
 
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub(crate) enum ComponentTlsMode {
+    /// start TLS right after the TCP connection is established
+    Direct,
+    /// upgrade the component stream using STARTTLS, failing if the server does not offer it
+    Starttls,
+}
+
+/// Settings of the XEP-0050 device registration
 #[derive(Debug, Deserialize, Getters)]
 #[serde(rename_all = "camelCase")]
+pub(crate) struct RegistrationConfig {
+    store_path: PathBuf,
+    /// map of ad-hoc command node to push module identifier
+    commands: HashMap<String, String>,
+    #[serde(default = "default_allow_unregistered_tokens")]
+    allow_unregistered_tokens: bool,
+    /// maximal number of devices registered per account
+    #[serde(default = "default_max_registrations_per_account")]
+    max_registrations_per_account: usize,
+}
+
+impl RegistrationConfig {
+    /// Check that every command assigns devices to a configured push module
+    pub(crate) fn validate(
+        &self,
+        push_modules: &FpushPushConfig,
+    ) -> std::result::Result<(), String> {
+        let has_default_module = push_modules
+            .config()
+            .values()
+            .any(|push_config| push_config.is_default_module());
+        let mut commands: Vec<(&String, &String)> = self.commands.iter().collect();
+        commands.sort();
+        for (command_node, push_module_id) in commands {
+            let is_configured = push_modules.config().contains_key(push_module_id)
+                || (push_module_id == "default" && has_default_module);
+            if !is_configured {
+                return Err(format!(
+                    "commands.{}: unknown push module {}",
+                    command_node, push_module_id
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+fn default_allow_unregistered_tokens() -> bool {
+    true
+}
+
+fn default_max_registrations_per_account() -> usize {
+    10
+}
+
+/// Limits of concurrently handled stanzas per component connection
+#[derive(Debug, Deserialize, Serialize, Getters, Clone)]
+#[serde(rename_all = "camelCase", default)]
+pub(crate) struct ConcurrencyConfig {
+    /// maximal number of stanzas handled at the same time
+    max_in_flight: usize,
+    /// maximal number of stanzas waiting for a free handler
+    queue_size: usize,
+    overflow: OverflowPolicy,
+}
+
+impl Default for ConcurrencyConfig {
+    fn default() -> Self {
+        Self {
+            max_in_flight: 4096,
+            queue_size: 4096,
+            overflow: OverflowPolicy::Reject,
+        }
+    }
+}
+
+impl ConcurrencyConfig {
+    /// Check settings serde can not check on its own
+    pub(crate) fn validate(&self) -> std::result::Result<(), String> {
+        if self.max_in_flight == 0 {
+            return Err("maxInFlight has to be at least 1".to_string());
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub(crate) enum OverflowPolicy {
+    /// reply with a wait error if all handlers and queue slots are in use
+    Reject,
+    /// stop reading from the component connection until a queue slot is free
+    Backpressure,
+}
+
+/// Batching of outgoing replies on the component connection
+#[derive(Debug, Deserialize, Serialize, Getters, Clone)]
+#[serde(rename_all = "camelCase", default)]
+pub(crate) struct BatchingConfig {
+    max_batch_size: u64,
+    #[serde(
+        deserialize_with = "serde_humantime",
+        serialize_with = "serialize_humantime"
+    )]
+    flush_interval: Duration,
+    #[serde(
+        deserialize_with = "serde_humantime",
+        serialize_with = "serialize_humantime"
+    )]
+    metrics_interval: Duration,
+}
+
+impl Default for BatchingConfig {
+    fn default() -> Self {
+        Self {
+            max_batch_size: 64,
+            flush_interval: Duration::from_millis(5),
+            metrics_interval: Duration::from_secs(60),
+        }
+    }
+}
+
+/// Backoff between attempts to open the component connection
+///
+/// The first delay is `timeout.xmppconnectionError`, each further failed attempt multiplies it
+/// by `multiplier` up to `maxDelay`.
+#[derive(Debug, Deserialize, Serialize, Getters, Clone)]
+#[serde(rename_all = "camelCase", default)]
+pub(crate) struct ReconnectConfig {
+    #[serde(
+        deserialize_with = "serde_humantime",
+        serialize_with = "serialize_humantime"
+    )]
+    max_delay: Duration,
+    multiplier: f64,
+    /// fraction of the delay that is randomly added or subtracted
+    jitter: f64,
+    on_auth_failure: AuthFailurePolicy,
+    /// file the state of the component connection is written to
+    status_file: Option<PathBuf>,
+}
+
+impl Default for ReconnectConfig {
+    fn default() -> Self {
+        Self {
+            max_delay: Duration::from_secs(300),
+            multiplier: 2.0,
+            jitter: 0.2,
+            on_auth_failure: AuthFailurePolicy::Retry,
+            status_file: None,
+        }
+    }
+}
+
+impl ReconnectConfig {
+    /// Check settings serde can not check on its own
+    pub(crate) fn validate(&self) -> std::result::Result<(), String> {
+        // a zero delay would reconnect in a busy loop
+        if self.max_delay.is_zero() {
+            return Err("maxDelay has to be greater than 0s".to_string());
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub(crate) enum AuthFailurePolicy {
+    /// keep reconnecting with backoff, logging every failed handshake as error
+    Retry,
+    /// stop fpush, as a wrong component key is not going to fix itself
+    Exit,
+}
+
+#[derive(Debug, Deserialize, Serialize, Getters)]
+#[serde(rename_all = "camelCase")]
 pub(crate) struct TimeoutConfig {
-    #[serde(deserialize_with = "serde_humantime")]
+    #[serde(
+        deserialize_with = "serde_humantime",
+        serialize_with = "serialize_humantime"
+    )]
     xmppconnection_error: std::time::Duration,
+    /// time to wait for in-flight pushes on shutdown
+    #[serde(
+        default = "default_shutdown_deadline",
+        deserialize_with = "serde_humantime",
+        serialize_with = "serialize_humantime"
+    )]
+    shutdown_deadline: std::time::Duration,
+}
+
+fn default_shutdown_deadline() -> Duration {
+    Duration::from_secs(30)
 }
 
 impl Default for TimeoutConfig {
     fn default() -> Self {
         Self {
             xmppconnection_error: Duration::from_secs(10),
+            shutdown_deadline: default_shutdown_deadline(),
         }
     }
 }
@@ -47,12 +303,380 @@ where
         .map(|wrapped_de: serde_humantime::De<Duration>| wrapped_de.into_inner())
 }
 
+pub fn serialize_humantime<S>(
+    duration: &Duration,
+    serializer: S,
+) -> std::result::Result<S::Ok, S::Error>
+where
+    S: serde::Serializer,
+{
+    if duration.subsec_nanos() == 0 {
+        serializer.serialize_str(&format!("{}s", duration.as_secs()))
+    } else {
+        serializer.serialize_str(&format!("{}ms", duration.as_millis()))
+    }
+}
+
+fn one_or_many<'de, D, T>(deserializer: D) -> std::result::Result<Vec<T>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany<T> {
+        One(T),
+        Many(Vec<T>),
+    }
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(value) => vec![value],
+        OneOrMany::Many(values) => values,
+    })
+}
+
+/// Config with placeholders for all required settings and the defaults of all other settings
+pub(crate) fn default_config() -> serde_json::Value {
+    serde_json::json!({
+        "component": {
+            "componentHostname": "push.example.com",
+            "componentKey": "<component secret configured in the XMPP server>",
+            "serverHostname": "localhost",
+            "serverPort": 5347
+        },
+        "pushModules": {
+            "<push module identifier>": default_push_module()
+        },
+        "timeout": TimeoutConfig::default(),
+        "domainRatelimit": DomainRatelimitSettings::default(),
+        "concurrency": ConcurrencyConfig::default(),
+        "batching": BatchingConfig::default(),
+        "reconnect": ReconnectConfig::default()
+    })
+}
+
+/// Push module of the first type supported by this build with placeholders for its settings
+fn default_push_module() -> serde_json::Value {
+    let backends = [
+        (
+            "apple",
+            "apns",
+            serde_json::json!({
+                "certFilePath": "<path to p12 file>",
+                "certPassword": "<cert password>",
+                "topic": "<app bundle id>"
+            }),
+        ),
+        (
+            "google",
+            "fcm",
+            serde_json::json!({ "fcmSecretPath": "<path to service account json from google>" }),
+        ),
+    ];
+    let mut push_module = serde_json::json!({
+        "type": "demo",
+        "is_default_module": true,
+        "ratelimit": RatelimitSettings::default(),
+        "blacklist": BlacklistSettings::default()
+    });
+    if let Some((module_type, key, backend)) = backends
+        .into_iter()
+        .find(|(module_type, _, _)| PushBackendConfig::check_supported_type(module_type).is_ok())
+    {
+        push_module["type"] = module_type.into();
+        push_module[key] = backend;
+    }
+    push_module
+}
+
 // use serde to parse from file to struct
 pub(crate) fn load_config(config_path: &str) -> Result<FpushConfig> {
-    let settings_file = std::fs::File::open(config_path)?;
-    let settings_reader = std::io::BufReader::new(settings_file);
+    let settings = read_settings(config_path)?;
 
-    let config: FpushConfig = serde_json::from_reader(settings_reader)?;
+    let config = FpushConfig::deserialize(&settings)
+        .map_err(|e| crate::error::Error::Config(describe_config_error(&settings, e)))?;
+    config.validate()?;
 
     Ok(config)
 }
+
+/// Name the push module or component connection a deserialization error occurred in
+///
+/// serde only reports line and column, which is not helpful for errors inside flattened or
+/// untagged settings.
+fn describe_config_error(settings: &serde_json::Value, error: serde_json::Error) -> String {
+    if let Some(push_modules) = settings.get("pushModules").and_then(|v| v.as_object()) {
+        for (push_module_id, push_config) in push_modules {
+            if let Some(module_type) = push_config.get("type").and_then(|v| v.as_str()) {
+                if let Err(e) = PushBackendConfig::check_supported_type(module_type) {
+                    return format!("pushModules.{}.type: {}", push_module_id, e);
+                }
+            }
+            if let Err(e) = PushConfig::deserialize(push_config) {
+                return format!("pushModules.{}: {}", push_module_id, e);
+            }
+        }
+    }
+    let components = match settings.get("component") {
+        Some(serde_json::Value::Array(components)) => components.iter().collect(),
+        Some(component) => vec![component],
+        None => return "component: missing field `component`".to_string(),
+    };
+    for (index, component) in components.into_iter().enumerate() {
+        let name = component
+            .get("componentHostname")
+            .and_then(|v| v.as_str())
+            .map(|component_hostname| component_hostname.to_string())
+            .unwrap_or_else(|| format!("#{}", index));
+        if component.get("serverHostname").is_none() && component.get("socketPath").is_none() {
+            return format!(
+                "component {}: either serverHostname and serverPort or socketPath has to be set",
+                name
+            );
+        }
+        if let Err(e) = FpushComponentSettings::deserialize(component) {
+            return format!("component {}: {}", name, e);
+        }
+    }
+    type SectionCheck = fn(&serde_json::Value) -> std::result::Result<(), serde_json::Error>;
+    let sections: [(&str, SectionCheck); 7] = [
+        ("timeout", |v| TimeoutConfig::deserialize(v).map(|_| ())),
+        ("registration", |v| {
+            RegistrationConfig::deserialize(v).map(|_| ())
+        }),
+        ("domainRatelimit", |v| {
+            DomainRatelimitSettings::deserialize(v).map(|_| ())
+        }),
+        ("concurrency", |v| {
+            ConcurrencyConfig::deserialize(v).map(|_| ())
+        }),
+        ("batching", |v| BatchingConfig::deserialize(v).map(|_| ())),
+        ("reconnect", |v| ReconnectConfig::deserialize(v).map(|_| ())),
+        ("pushQueue", |v| {
+            PushQueueSettings::deserialize(v).map(|_| ())
+        }),
+    ];
+    for (section, check) in sections {
+        if let Some(Err(e)) = settings.get(section).map(check) {
+            return format!("{}: {}", section, e);
+        }
+    }
+    error.to_string()
+}
+
+impl FpushConfig {
+    /// Check settings serde can not check on its own
+    fn validate(&self) -> Result<()> {
+        self.validate_components()?;
+        self.push_modules()
+            .validate()
+            .map_err(crate::error::Error::Config)?;
+        if let Some(push_queue) = self.push_queue() {
+            push_queue
+                .validate()
+                .map_err(|e| crate::error::Error::Config(format!("pushQueue: {}", e)))?;
+        }
+        if let Some(registration) = self.registration() {
+            registration
+                .validate(self.push_modules())
+                .map_err(|e| crate::error::Error::Config(format!("registration: {}", e)))?;
+        }
+        self.concurrency
+            .validate()
+            .map_err(|e| crate::error::Error::Config(format!("concurrency: {}", e)))?;
+        self.reconnect
+            .validate()
+            .map_err(|e| crate::error::Error::Config(format!("reconnect: {}", e)))?;
+        Ok(())
+    }
+
+    fn validate_components(&self) -> Result<()> {
+        if self.components.is_empty() {
+            return Err(crate::error::Error::Config(
+                "At least one component connection has to be configured".to_string(),
+            ));
+        }
+        let mut component_hostnames = std::collections::HashSet::new();
+        for component in &self.components {
+            if let (ComponentEndpoint::Unix { .. }, Some(_)) =
+                (component.endpoint(), component.tls())
+            {
+                return Err(crate::error::Error::Config(format!(
+                    "Component {} can not use TLS over a unix socket",
+                    component.component_hostname()
+                )));
+            }
+            if !component_hostnames.insert(component.component_hostname()) {
+                return Err(crate::error::Error::Config(format!(
+                    "Component {} is configured more than once",
+                    component.component_hostname()
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings_with(section: &str, value: serde_json::Value) -> serde_json::Value {
+        let mut settings = serde_json::json!({
+            "component": {
+                "componentHostname": "push.example.org",
+                "componentKey": "key",
+                "serverHostname": "localhost",
+                "serverPort": 5347
+            },
+            "pushModules": {}
+        });
+        settings[section] = value;
+        settings
+    }
+
+    fn config_error(settings: serde_json::Value) -> String {
+        let error = FpushConfig::deserialize(&settings).unwrap_err();
+        describe_config_error(&settings, error)
+    }
+
+    #[test]
+    fn test_push_module_errors() {
+        let error = config_error(settings_with(
+            "pushModules",
+            serde_json::json!({ "monalProdiOS": { "type": "carrier-pigeon" } }),
+        ));
+        assert!(
+            error.starts_with("pushModules.monalProdiOS.type: unknown push module type"),
+            "{}",
+            error
+        );
+
+        let error = config_error(settings_with(
+            "pushModules",
+            serde_json::json!({ "monalProdiOS": { "isDefaultModule": true } }),
+        ));
+        assert!(error.starts_with("pushModules.monalProdiOS: "), "{}", error);
+    }
+
+    #[test]
+    fn test_component_errors() {
+        let error = config_error(settings_with(
+            "component",
+            serde_json::json!({ "componentHostname": "push.example.org", "componentKey": "key" }),
+        ));
+        assert_eq!(
+            error,
+            "component push.example.org: either serverHostname and serverPort or socketPath has to be set"
+        );
+
+        let error = config_error(settings_with(
+            "component",
+            serde_json::json!([
+                {
+                    "componentHostname": "push.example.org",
+                    "componentKey": "key",
+                    "socketPath": "/run/prosody/component.sock"
+                },
+                {
+                    "componentKey": "key",
+                    "serverHostname": "localhost",
+                    "serverPort": 5347
+                }
+            ]),
+        ));
+        assert_eq!(error, "component #1: missing field `componentHostname`");
+    }
+
+    #[test]
+    fn test_default_config_is_valid() {
+        let config = FpushConfig::deserialize(&default_config()).unwrap();
+        assert!(config.push_modules().validate().is_ok());
+    }
+
+    fn validation_error(settings: serde_json::Value) -> String {
+        match FpushConfig::deserialize(&settings).unwrap().validate() {
+            Err(crate::error::Error::Config(e)) => e,
+            result => panic!("expected a config error, got {:?}", result.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn test_section_defaults() {
+        let config = FpushConfig::deserialize(&settings_with(
+            "concurrency",
+            serde_json::json!({ "maxInFlight": 16 }),
+        ))
+        .unwrap();
+        assert_eq!(*config.concurrency().max_in_flight(), 16);
+        assert_eq!(*config.concurrency().queue_size(), 4096);
+        assert_eq!(*config.concurrency().overflow(), OverflowPolicy::Reject);
+        assert!(config.validate().is_ok());
+
+        let config = FpushConfig::deserialize(&settings_with(
+            "batching",
+            serde_json::json!({ "maxBatchSize": 128 }),
+        ))
+        .unwrap();
+        assert_eq!(*config.batching().max_batch_size(), 128);
+        assert_eq!(
+            *config.batching().flush_interval(),
+            Duration::from_millis(5)
+        );
+        assert_eq!(
+            *config.batching().metrics_interval(),
+            Duration::from_secs(60)
+        );
+
+        let config = FpushConfig::deserialize(&settings_with(
+            "reconnect",
+            serde_json::json!({ "onAuthFailure": "exit" }),
+        ))
+        .unwrap();
+        assert_eq!(
+            *config.reconnect().on_auth_failure(),
+            AuthFailurePolicy::Exit
+        );
+        assert_eq!(*config.reconnect().max_delay(), Duration::from_secs(300));
+        assert_eq!(*config.reconnect().multiplier(), 2.0);
+        assert_eq!(*config.reconnect().jitter(), 0.2);
+    }
+
+    #[test]
+    fn test_section_errors() {
+        let error = validation_error(settings_with(
+            "concurrency",
+            serde_json::json!({ "maxInFlight": 0 }),
+        ));
+        assert_eq!(error, "concurrency: maxInFlight has to be at least 1");
+
+        let error = validation_error(settings_with(
+            "reconnect",
+            serde_json::json!({ "maxDelay": "0s" }),
+        ));
+        assert_eq!(error, "reconnect: maxDelay has to be greater than 0s");
+
+        let error = config_error(settings_with(
+            "registration",
+            serde_json::json!({ "storePath": "/var/lib/fpush/registrations.json" }),
+        ));
+        assert_eq!(error, "registration: missing field `commands`");
+
+        let error = validation_error(settings_with(
+            "registration",
+            serde_json::json!({
+                "storePath": "/var/lib/fpush/registrations.json",
+                "commands": { "register-push-fcm": "fcm" }
+            }),
+        ));
+        assert_eq!(
+            error,
+            "registration: commands.register-push-fcm: unknown push module fcm"
+        );
+
+        let error = config_error(settings_with(
+            "timeout",
+            serde_json::json!({ "xmppconnectionError": "soon" }),
+        ));
+        assert!(error.starts_with("timeout: "), "{}", error);
+    }
+}
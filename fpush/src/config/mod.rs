@@ -1,3 +1,4 @@
+mod config_source;
 pub(crate) mod fpush_config;
 
-pub(crate) use fpush_config::load_config;
+pub(crate) use fpush_config::{default_config, load_config, FpushConfig};
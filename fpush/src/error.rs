@@ -4,13 +4,13 @@ pub type Result<T> = std::result::Result<T, Error>;
 #[derive(Debug, From, Display)]
 pub enum Error {
     Io(std::io::Error),
-    ConfigError(serde_json::Error),
+    Json(serde_json::Error),
     Config(String),
     Xmpp(Box<tokio_xmpp::Error>),
-    PubSubNonPublish,
-    PubSubInvalidFormat,
-    PubSubToManyPublishOptions,
-    PubSubInvalidPushModuleConfiguration,
+    /// the XMPP server did not complete the connection setup as expected
+    #[from(ignore)]
+    Connection(String),
+    Tls(tokio_rustls::rustls::Error),
 }
 
 impl std::convert::From<tokio_xmpp::Error> for Error {
@@ -18,3 +18,10 @@ impl std::convert::From<tokio_xmpp::Error> for Error {
         Error::Xmpp(Box::new(e))
     }
 }
+
+impl Error {
+    /// returns true if the XMPP server rejected the component handshake
+    pub fn is_auth_failure(&self) -> bool {
+        matches!(self, Error::Xmpp(e) if matches!(**e, tokio_xmpp::Error::Auth(_)))
+    }
+}
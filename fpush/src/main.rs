@@ -1,59 +1,230 @@
+mod cli;
 mod config;
 mod error;
+mod registration;
 mod xmpp;
-use fpush_push::FpushPush;
+use cli::{Cli, Command, SendTestPushArgs};
+use config::FpushConfig;
+use fpush_push::{FpushPush, FpushPushArc, PushRequest};
+use xmpp::{component_connection_loop, ComponentConnection, ConnectionExit, ConnectionMonitor};
 
+use clap::Parser;
 use log::{debug, error, info};
-use std::sync::Arc;
+use std::{collections::HashMap, sync::Arc};
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    sync::watch,
+};
 
 /// init env_logger
 fn setup_logging() {
     env_logger::init();
 }
 
-#[tokio::main]
-async fn main() {
-    setup_logging();
+/// Log an error that prevents fpush from starting and exit
+fn exit_with_error(message: &str) -> ! {
+    error!("{}", message);
+    std::process::exit(1);
+}
 
-    // get settings name
-    let args: Vec<String> = std::env::args().collect();
-    let settings_filename = match args.get(1) {
-        Some(f) => f,
-        None => "./settings.json",
+/// Request shutdown once SIGTERM or SIGINT was received
+fn spawn_shutdown_listener(shutdown_sender: Arc<watch::Sender<bool>>) {
+    let mut sigterm = match signal(SignalKind::terminate()) {
+        Ok(sigterm) => sigterm,
+        Err(e) => exit_with_error(&format!("Could not register SIGTERM handler: {}", e)),
     };
-    info!("Loading config file {}", settings_filename);
+    tokio::spawn(async move {
+        tokio::select! {
+            _ = sigterm.recv() => info!("Received SIGTERM, shutting down"),
+            _ = tokio::signal::ctrl_c() => info!("Received SIGINT, shutting down"),
+        }
+        shutdown_sender.send_replace(true);
+    });
+}
 
+/// Reload the push modules from the config file whenever SIGHUP is received
+///
+/// Component connections stay open, all other settings require a restart.
+fn spawn_reload_listener(settings_filename: String, push_modules: FpushPushArc) {
+    let mut sighup = match signal(SignalKind::hangup()) {
+        Ok(sighup) => sighup,
+        Err(e) => exit_with_error(&format!("Could not register SIGHUP handler: {}", e)),
+    };
+    tokio::spawn(async move {
+        while sighup.recv().await.is_some() {
+            info!("Received SIGHUP, reloading {}", settings_filename);
+            match crate::config::load_config(&settings_filename) {
+                Ok(settings) => {
+                    push_modules.reload(settings.push_modules()).await;
+                    info!("Changes to settings other than pushModules require a restart");
+                }
+                Err(e) => error!("Keeping current config, could not load config file: {}", e),
+            }
+        }
+    });
+}
+
+/// Load the config file and check the component settings, exits on errors
+fn load_settings(settings_filename: &str) -> FpushConfig {
+    info!("Loading config file {}", settings_filename);
     let settings = match crate::config::load_config(settings_filename) {
         Ok(s) => {
             debug!("Config loaded");
             s
         }
-        Err(e) => {
-            panic!("Error loading config file: {}", e);
+        Err(e) => exit_with_error(&format!(
+            "Error loading config file {}: {}",
+            settings_filename, e
+        )),
+    };
+    for component in settings.components() {
+        if let Err(e) = ComponentConnection::check_settings(component) {
+            exit_with_error(&format!(
+                "Error in component {}: {}",
+                component.component_hostname(),
+                e
+            ));
         }
+    }
+    settings
+}
+
+/// Load all push modules, exits on errors
+///
+/// Only the daemon opens the push queue, so other commands neither replay nor rewrite it.
+async fn load_push_modules(settings: &FpushConfig, with_queue: bool) -> FpushPushArc {
+    let queue_settings = settings.push_queue().as_ref().filter(|_| with_queue);
+    match FpushPush::new(settings.push_modules(), queue_settings).await {
+        Ok(push_impl) => Arc::new(push_impl),
+        Err(e) => exit_with_error(&format!("Error loading push modules: {}", e)),
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    setup_logging();
+
+    match Cli::parse().command() {
+        Command::Run(args) => run(&args.settings).await,
+        Command::CheckConfig(args) => check_config(&args.settings).await,
+        Command::SendTestPush(args) => send_test_push(args).await,
+        Command::PrintDefaultConfig => print_default_config(),
+    }
+}
+
+/// Connect to the XMPP server and handle push requests until shutdown
+async fn run(settings_filename: &str) {
+    let settings = load_settings(settings_filename);
+    let push_impl = load_push_modules(&settings, true).await;
+    let xmpp_ctx = match crate::xmpp::XmppContext::new(&settings, push_impl) {
+        Ok(ctx) => Arc::new(ctx),
+        Err(e) => exit_with_error(&format!("Error opening push registration store: {}", e)),
     };
 
-    let push_impl: Arc<FpushPush> = Arc::new(FpushPush::new(settings.push_modules()).await);
+    let settings = Arc::new(settings);
+    let monitor = Arc::new(ConnectionMonitor::new(
+        settings.reconnect().status_file().clone(),
+    ));
+    let (shutdown_sender, _) = watch::channel(false);
+    let shutdown_sender = Arc::new(shutdown_sender);
+    spawn_shutdown_listener(shutdown_sender.clone());
+    spawn_reload_listener(
+        settings_filename.to_string(),
+        xmpp_ctx.push_modules().clone(),
+    );
 
-    loop {
-        info!(
-            "Opening connection to {}",
-            settings.component().server_hostname()
-        );
-        // open component connection
-        match crate::xmpp::init_component_connection(&settings).await {
+    // every component connection gets its own reconnect loop, all share the push modules
+    let connections: Vec<_> = settings
+        .components()
+        .iter()
+        .map(|component| {
+            tokio::spawn(component_connection_loop(
+                component.clone(),
+                settings.clone(),
+                xmpp_ctx.clone(),
+                monitor.clone(),
+                shutdown_sender.clone(),
+            ))
+        })
+        .collect();
+    let mut exit_code = 0;
+    for connection in futures::future::join_all(connections).await {
+        match connection {
+            Ok(ConnectionExit::Shutdown) => {}
+            Ok(ConnectionExit::AuthFailed) => exit_code = 1,
             Err(e) => {
-                error!("Could not connect to XMPP Server {}", e);
-                info!(
-                    "Waiting {} seconds before reconnecting",
-                    settings.timeout().xmppconnection_error().as_secs()
-                );
-                tokio::time::sleep(*settings.timeout().xmppconnection_error()).await;
+                error!("Component connection task failed: {}", e);
+                exit_code = 1;
             }
-            Ok(component) => {
-                // open new messageLoop
-                crate::xmpp::message_loop_main_thread(component, push_impl.clone()).await;
+        }
+    }
+
+    xmpp_ctx.push_modules().shutdown().await;
+    info!("Shutdown complete");
+    if exit_code != 0 {
+        std::process::exit(exit_code);
+    }
+}
+
+/// Load the config file and all push modules without connecting to the XMPP server
+async fn check_config(settings_filename: &str) {
+    let settings = load_settings(settings_filename);
+    let push_impl = load_push_modules(&settings, false).await;
+    if let Err(e) = crate::xmpp::XmppContext::new(&settings, push_impl.clone()) {
+        exit_with_error(&format!("Error opening push registration store: {}", e));
+    }
+    push_impl.shutdown().await;
+    println!("Config file {} is valid", settings_filename);
+}
+
+/// Send a single push notification through the configured push modules without XMPP
+async fn send_test_push(args: SendTestPushArgs) {
+    let settings = load_settings(&args.config.settings);
+    let push_impl = load_push_modules(&settings, false).await;
+    // allow the same identifiers XMPP servers use in the to attribute
+    let module_id = if push_impl.has_push_module(&args.module) {
+        args.module
+    } else {
+        match push_impl.push_module_for_alias(&args.module) {
+            Some(module_id) => module_id,
+            None => {
+                let module_ids = push_impl.push_module_ids();
+                push_impl.shutdown().await;
+                exit_with_error(&format!(
+                    "Unknown push module {}, configured push modules are {:?}",
+                    args.module, module_ids
+                ))
             }
         }
+    };
+    let mut publish_options = HashMap::new();
+    if let Some(secret) = args.secret {
+        publish_options.insert("secret".to_string(), vec![secret]);
+    }
+    let request = PushRequest::new(args.token).with_publish_options(publish_options);
+
+    // report transient errors instead of acknowledging the push and retrying in background
+    let result = push_impl.push_blocking(&module_id, &request).await;
+    push_impl.shutdown().await;
+    match result {
+        Ok(()) => println!(
+            "Sent push notification to {} using {}",
+            request.token(),
+            module_id
+        ),
+        Err(e) => exit_with_error(&format!(
+            "Could not send push notification to {} using {}: {}",
+            request.token(),
+            module_id,
+            e
+        )),
+    }
+}
+
+/// Print a config file containing placeholders for all required settings and all defaults
+fn print_default_config() {
+    match serde_json::to_string_pretty(&crate::config::default_config()) {
+        Ok(config) => println!("{}", config),
+        Err(e) => exit_with_error(&format!("Could not serialize default config: {}", e)),
     }
 }
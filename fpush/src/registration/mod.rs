@@ -0,0 +1,2 @@
+mod token_store;
+pub(crate) use token_store::TokenStore;
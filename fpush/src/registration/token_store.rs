@@ -0,0 +1,256 @@
+use std::{
+    collections::HashMap,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use crate::error::Result;
+
+use dashmap::DashMap;
+use derive_getters::Getters;
+use log::{debug, info};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// A device registered via ad-hoc command
+#[derive(Debug, Clone, Serialize, Deserialize, Getters)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct PushRegistration {
+    module_id: String,
+    token: String,
+    secret: String,
+    jid: String,
+    device_id: String,
+}
+
+impl PushRegistration {
+    /// returns true if the `secret` publish-option matches the secret handed out on registration
+    pub(crate) fn verify_secret(&self, secret: Option<&str>) -> bool {
+        match secret {
+            Some(secret) if secret.len() == self.secret.len() => {
+                secret
+                    .bytes()
+                    .zip(self.secret.bytes())
+                    .fold(0, |acc, (x, y)| acc | (x ^ y))
+                    == 0
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Persistent map of opaque pubsub nodes to the push tokens registered by devices
+pub(crate) struct TokenStore {
+    path: PathBuf,
+    registrations: Arc<DashMap<String, PushRegistration>>,
+    /// number of registrations per account, locked while an account registers a device
+    registration_counts: DashMap<String, usize>,
+    write_lock: Arc<Mutex<()>>,
+    max_registrations_per_account: usize,
+}
+
+impl TokenStore {
+    /// Open the token store, loading all registrations from disk if the file exists
+    pub(crate) fn open(path: &Path, max_registrations_per_account: usize) -> Result<Self> {
+        let mut registrations = DashMap::new();
+        let registration_counts = DashMap::new();
+        if path.exists() {
+            let store_file = std::fs::File::open(path)?;
+            let loaded: HashMap<String, PushRegistration> =
+                serde_json::from_reader(std::io::BufReader::new(store_file))?;
+            info!(
+                "Loaded {} push registrations from {}",
+                loaded.len(),
+                path.display()
+            );
+            for registration in loaded.values() {
+                *registration_counts
+                    .entry(registration.jid.clone())
+                    .or_insert(0) += 1;
+            }
+            registrations.extend(loaded);
+        }
+        Ok(Self {
+            path: path.to_path_buf(),
+            registrations: Arc::new(registrations),
+            registration_counts,
+            write_lock: Arc::new(Mutex::new(())),
+            max_registrations_per_account,
+        })
+    }
+
+    /// Register a token and return the opaque node and the secret handed to the device
+    ///
+    /// The node is derived from the account and device, so a device re-registering a new
+    /// token keeps its node, while the secret is rotated on every registration.
+    /// Returns `None` if the account already registered the maximal number of other devices.
+    pub(crate) async fn register(
+        &self,
+        module_id: &str,
+        token: &str,
+        jid: &str,
+        device_id: &str,
+    ) -> Result<Option<(String, PushRegistration)>> {
+        let node = Self::derive_node(module_id, jid, device_id);
+        let registration = PushRegistration {
+            module_id: module_id.to_string(),
+            token: token.to_string(),
+            secret: Self::generate_secret(),
+            jid: jid.to_string(),
+            device_id: device_id.to_string(),
+        };
+        {
+            // check the limit and insert while holding the count of the account
+            let mut registration_count =
+                self.registration_counts.entry(jid.to_string()).or_insert(0);
+            if !self.registrations.contains_key(&node) {
+                if *registration_count >= self.max_registrations_per_account {
+                    debug!("Rejecting registration of {}, too many devices", jid);
+                    return Ok(None);
+                }
+                *registration_count += 1;
+            }
+            debug!("Registering node {} for {} on {}", node, jid, module_id);
+            self.registrations
+                .insert(node.clone(), registration.clone());
+        }
+        let path = self.path.clone();
+        let registrations = self.registrations.clone();
+        let write_lock = self.write_lock.clone();
+        tokio::task::spawn_blocking(move || Self::persist(&path, &registrations, &write_lock))
+            .await
+            .map_err(std::io::Error::other)??;
+        Ok(Some((node, registration)))
+    }
+
+    #[inline(always)]
+    pub(crate) fn lookup(&self, node: &str) -> Option<PushRegistration> {
+        self.registrations
+            .get(node)
+            .map(|registration| registration.value().clone())
+    }
+
+    fn derive_node(module_id: &str, jid: &str, device_id: &str) -> String {
+        let mut hasher = Sha256::new();
+        for part in [module_id, jid, device_id] {
+            hasher.update(part.as_bytes());
+            hasher.update([0u8]);
+        }
+        hex::encode(&hasher.finalize()[..20])
+    }
+
+    fn generate_secret() -> String {
+        let mut secret = [0u8; 24];
+        rand::thread_rng().fill_bytes(&mut secret);
+        hex::encode(secret)
+    }
+
+    /// Write all registrations to a temporary file and atomically replace the store file
+    fn persist(
+        path: &Path,
+        registrations: &DashMap<String, PushRegistration>,
+        write_lock: &Mutex<()>,
+    ) -> Result<()> {
+        let _guard = write_lock.lock().unwrap();
+        let snapshot: HashMap<String, PushRegistration> = registrations
+            .iter()
+            .map(|entry| (entry.key().clone(), entry.value().clone()))
+            .collect();
+        let tmp_path = path.with_extension("tmp");
+        {
+            let mut tmp_writer = std::io::BufWriter::new(std::fs::File::create(&tmp_path)?);
+            serde_json::to_writer(&mut tmp_writer, &snapshot)?;
+            tmp_writer.flush()?;
+        }
+        std::fs::rename(&tmp_path, path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn store_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "fpush-token-store-{}-{}.json",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    #[tokio::test]
+    async fn test_register_and_reopen() {
+        let path = store_path("reopen");
+        let store = TokenStore::open(&path, 10).unwrap();
+        let (node, registration) = store
+            .register("google", "token1", "user@example.org", "dev1")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(registration.token(), "token1");
+        assert!(registration.verify_secret(Some(registration.secret())));
+        assert!(!registration.verify_secret(Some("wrong")));
+        assert!(!registration.verify_secret(None));
+
+        // re-registering the device keeps the node, but rotates the secret
+        let (same_node, new_registration) = store
+            .register("google", "token2", "user@example.org", "dev1")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(node, same_node);
+        assert_ne!(registration.secret(), new_registration.secret());
+        drop(store);
+
+        let store = TokenStore::open(&path, 10).unwrap();
+        let loaded = store.lookup(&node).unwrap();
+        assert_eq!(loaded.token(), "token2");
+        assert_eq!(loaded.secret(), new_registration.secret());
+        assert!(store.lookup("unknown").is_none());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_registrations_per_account() {
+        let path = store_path("limit");
+        let store = TokenStore::open(&path, 2).unwrap();
+        for device_id in ["dev1", "dev2"] {
+            assert!(store
+                .register("google", "token", "user@example.org", device_id)
+                .await
+                .unwrap()
+                .is_some());
+        }
+        assert!(store
+            .register("google", "token", "user@example.org", "dev3")
+            .await
+            .unwrap()
+            .is_none());
+        // known devices and other accounts can still register
+        assert!(store
+            .register("google", "new-token", "user@example.org", "dev1")
+            .await
+            .unwrap()
+            .is_some());
+        assert!(store
+            .register("google", "token", "other@example.org", "dev3")
+            .await
+            .unwrap()
+            .is_some());
+        drop(store);
+
+        // the limit also counts registrations loaded from disk
+        let store = TokenStore::open(&path, 2).unwrap();
+        assert!(store
+            .register("google", "token", "user@example.org", "dev3")
+            .await
+            .unwrap()
+            .is_none());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,337 @@
+use crate::xmpp::{context::XmppContext, error_messages::send_stanza_error_iq};
+
+use log::{error, info};
+use tokio::sync::mpsc;
+use xmpp_parsers::{
+    data_forms::DataForm,
+    iq::{Iq, IqType},
+    ns,
+    stanza_error::{DefinedCondition, ErrorType},
+    BareJid, Element, Jid,
+};
+
+/// Namespace of XEP-0050 ad-hoc commands
+pub const NS_COMMANDS: &str = "http://jabber.org/protocol/commands";
+
+#[inline(always)]
+pub fn is_ad_hoc_command(iq_payload: &Element) -> bool {
+    iq_payload.is("command", NS_COMMANDS)
+}
+
+/// Handle a p2 compatible `register-push-*` command
+///
+/// The client submits its device token (`token`) and device id (`android-id` or `device-id`)
+/// and receives an opaque `node` and a `secret` to enable push on its XMPP server with.
+pub async fn handle_ad_hoc_command(
+    conn: &mpsc::Sender<Iq>,
+    ctx: &XmppContext,
+    iq_payload: Element,
+    id: &str,
+    jid: Jid,
+    from: Jid,
+) {
+    let registration = match ctx.registration() {
+        Some(registration) => registration,
+        None => {
+            send_stanza_error_iq(
+                conn,
+                id,
+                jid,
+                from,
+                ErrorType::Cancel,
+                DefinedCondition::ServiceUnavailable,
+                "Device registration is disabled",
+            )
+            .await;
+            return;
+        }
+    };
+    let command_node = iq_payload.attr("node").unwrap_or_default().to_string();
+    let module_id = match registration.commands().get(&command_node) {
+        Some(module_id) => module_id,
+        None => {
+            send_stanza_error_iq(
+                conn,
+                id,
+                jid,
+                from,
+                ErrorType::Cancel,
+                DefinedCondition::ItemNotFound,
+                "Unknown command",
+            )
+            .await;
+            return;
+        }
+    };
+    if iq_payload.attr("action") == Some("cancel") {
+        send_command_result(conn, id, jid, from, &command_node, "canceled", None).await;
+        return;
+    }
+    let form = match iq_payload
+        .get_child("x", ns::DATA_FORMS)
+        .and_then(|form| DataForm::try_from(form.clone()).ok())
+    {
+        Some(form) => form,
+        None => {
+            send_stanza_error_iq(
+                conn,
+                id,
+                jid,
+                from,
+                ErrorType::Modify,
+                DefinedCondition::BadRequest,
+                "Missing registration form",
+            )
+            .await;
+            return;
+        }
+    };
+    let field_value = |var: &str| -> Option<&str> {
+        form.fields
+            .iter()
+            .find(|field| field.var == var)
+            .and_then(|field| field.values.first())
+            .map(|value| value.as_str())
+            .filter(|value| !value.is_empty())
+    };
+    let token = match field_value("token") {
+        Some(token) => token,
+        None => {
+            send_stanza_error_iq(
+                conn,
+                id,
+                jid,
+                from,
+                ErrorType::Modify,
+                DefinedCondition::BadRequest,
+                "Missing token",
+            )
+            .await;
+            return;
+        }
+    };
+    let device_id = field_value("android-id")
+        .or_else(|| field_value("device-id"))
+        .unwrap_or_default();
+    let account = BareJid::from(jid.clone()).to_string();
+
+    match registration
+        .token_store()
+        .register(module_id, token, &account, device_id)
+        .await
+    {
+        Ok(Some((node, push_registration))) => {
+            info!(
+                "Registered device of {} for push module {}",
+                account, module_id
+            );
+            let result_form = Element::builder("x", ns::DATA_FORMS)
+                .attr("type", "result")
+                .append(form_field("node", &node))
+                .append(form_field("secret", push_registration.secret()))
+                .build();
+            send_command_result(
+                conn,
+                id,
+                jid,
+                from,
+                &command_node,
+                "completed",
+                Some(result_form),
+            )
+            .await;
+        }
+        Ok(None) => {
+            info!(
+                "Rejected registration of {}, too many registered devices",
+                account
+            );
+            send_stanza_error_iq(
+                conn,
+                id,
+                jid,
+                from,
+                ErrorType::Wait,
+                DefinedCondition::ResourceConstraint,
+                "Too many registered devices",
+            )
+            .await;
+        }
+        Err(e) => {
+            error!("Could not store push registration of {}: {}", account, e);
+            send_stanza_error_iq(
+                conn,
+                id,
+                jid,
+                from,
+                ErrorType::Wait,
+                DefinedCondition::InternalServerError,
+                "Could not store registration",
+            )
+            .await;
+        }
+    }
+}
+
+#[inline(always)]
+fn form_field(var: &str, value: &str) -> Element {
+    Element::builder("field", ns::DATA_FORMS)
+        .attr("var", var)
+        .append(
+            Element::builder("value", ns::DATA_FORMS)
+                .append(value)
+                .build(),
+        )
+        .build()
+}
+
+async fn send_command_result(
+    conn: &mpsc::Sender<Iq>,
+    id: &str,
+    jid: Jid,
+    from: Jid,
+    command_node: &str,
+    status: &str,
+    form: Option<Element>,
+) {
+    let mut command = Element::builder("command", NS_COMMANDS)
+        .attr("node", command_node)
+        .attr("sessionid", id)
+        .attr("status", status);
+    if let Some(form) = form {
+        command = command.append(form);
+    }
+    let iq = Iq {
+        from: Some(from),
+        to: Some(jid),
+        id: id.to_string(),
+        payload: IqType::Result(Some(command.build())),
+    };
+    if let Err(e) = conn.send(iq).await {
+        error!("Could not forward outgoing iq to main handler: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::xmpp::context::tests::{registration_settings, test_context};
+    use std::str::FromStr;
+
+    fn command(node: &str, fields: &[(&str, &str)]) -> Element {
+        let mut form = Element::builder("x", ns::DATA_FORMS).attr("type", "submit");
+        for (var, value) in fields {
+            form = form.append(form_field(var, value));
+        }
+        Element::builder("command", NS_COMMANDS)
+            .attr("node", node)
+            .attr("action", "execute")
+            .append(form.build())
+            .build()
+    }
+
+    async fn run_command(ctx: &XmppContext, iq_payload: Element) -> Iq {
+        let (conn, mut rx) = mpsc::channel(1);
+        handle_ad_hoc_command(
+            &conn,
+            ctx,
+            iq_payload,
+            "reg1",
+            Jid::from_str("user@example.org/phone").unwrap(),
+            Jid::from_str("push.example.org").unwrap(),
+        )
+        .await;
+        rx.recv().await.unwrap()
+    }
+
+    fn error_condition(iq: Iq) -> DefinedCondition {
+        match iq.payload {
+            IqType::Error(error) => error.defined_condition,
+            payload => panic!("expected error reply, got {:?}", payload),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_register_device() {
+        let ctx = test_context(Some(registration_settings("ad-hoc"))).await;
+        let iq = run_command(
+            &ctx,
+            command(
+                "register-push-fcm",
+                &[("token", "device-token"), ("android-id", "dev1")],
+            ),
+        )
+        .await;
+        assert_eq!(iq.id, "reg1");
+        let command = match iq.payload {
+            IqType::Result(Some(command)) => command,
+            payload => panic!("expected command result, got {:?}", payload),
+        };
+        assert_eq!(command.attr("status"), Some("completed"));
+        let form =
+            DataForm::try_from(command.get_child("x", ns::DATA_FORMS).unwrap().clone()).unwrap();
+        let value = |var: &str| {
+            form.fields
+                .iter()
+                .find(|field| field.var == var)
+                .unwrap()
+                .values[0]
+                .clone()
+        };
+        let registration = ctx
+            .registration()
+            .as_ref()
+            .unwrap()
+            .token_store()
+            .lookup(&value("node"))
+            .unwrap();
+        assert_eq!(registration.token(), "device-token");
+        assert_eq!(registration.jid(), "user@example.org");
+        assert_eq!(registration.secret(), &value("secret"));
+    }
+
+    #[tokio::test]
+    async fn test_invalid_commands() {
+        let ctx = test_context(Some(registration_settings("ad-hoc-invalid"))).await;
+        let unknown = command("register-push-unknown", &[("token", "device-token")]);
+        assert_eq!(
+            error_condition(run_command(&ctx, unknown).await),
+            DefinedCondition::ItemNotFound
+        );
+        let missing_token = command("register-push-fcm", &[("android-id", "dev1")]);
+        assert_eq!(
+            error_condition(run_command(&ctx, missing_token).await),
+            DefinedCondition::BadRequest
+        );
+
+        // registration is limited to two devices per account
+        for device_id in ["dev1", "dev2"] {
+            let register = command(
+                "register-push-fcm",
+                &[("token", "device-token"), ("device-id", device_id)],
+            );
+            assert!(matches!(
+                run_command(&ctx, register).await.payload,
+                IqType::Result(_)
+            ));
+        }
+        let register = command(
+            "register-push-fcm",
+            &[("token", "device-token"), ("device-id", "dev3")],
+        );
+        assert_eq!(
+            error_condition(run_command(&ctx, register).await),
+            DefinedCondition::ResourceConstraint
+        );
+    }
+
+    #[tokio::test]
+    async fn test_registration_disabled() {
+        let ctx = test_context(None).await;
+        let register = command("register-push-fcm", &[("token", "device-token")]);
+        assert_eq!(
+            error_condition(run_command(&ctx, register).await),
+            DefinedCondition::ServiceUnavailable
+        );
+    }
+}
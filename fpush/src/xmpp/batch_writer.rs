@@ -0,0 +1,193 @@
+use std::{
+    fmt::Display,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Instant,
+};
+
+use crate::config::fpush_config::BatchingConfig;
+
+use futures::{Sink, SinkExt};
+use log::{error, info};
+use tokio::sync::mpsc;
+use xmpp_parsers::{iq::Iq, Element};
+
+/// Statistics about the size of flushed reply batches
+#[derive(Default)]
+pub(crate) struct BatchMetrics {
+    batches: AtomicU64,
+    stanzas: AtomicU64,
+    max_batch_size: AtomicU64,
+}
+
+impl BatchMetrics {
+    #[inline(always)]
+    fn record(&self, batch_size: u64) {
+        self.batches.fetch_add(1, Ordering::Relaxed);
+        self.stanzas.fetch_add(batch_size, Ordering::Relaxed);
+        self.max_batch_size.fetch_max(batch_size, Ordering::Relaxed);
+    }
+
+    /// Log the batch statistics since the last report and reset them
+    fn report(&self) {
+        let batches = self.batches.swap(0, Ordering::Relaxed);
+        let stanzas = self.stanzas.swap(0, Ordering::Relaxed);
+        let max_batch_size = self.max_batch_size.swap(0, Ordering::Relaxed);
+        if batches > 0 {
+            info!(
+                "Sent {} replies in {} batches (avg batch size {:.1}, max batch size {})",
+                stanzas,
+                batches,
+                stanzas as f64 / batches as f64,
+                max_batch_size
+            );
+        }
+    }
+}
+
+/// Write all outgoing iqs to the component connection, flushing them in batches
+///
+/// A batch is flushed once `maxBatchSize` replies are queued or `flushInterval` passed since
+/// the first reply of the batch was queued.
+pub(crate) async fn batch_writer<S>(
+    mut sink: S,
+    mut out_recv: mpsc::Receiver<Iq>,
+    batching: BatchingConfig,
+) where
+    S: Sink<Element> + Unpin,
+    S::Error: Display,
+{
+    let metrics = BatchMetrics::default();
+    let mut last_report = Instant::now();
+    while let Some(first_msg) = out_recv.recv().await {
+        if let Err(e) = sink.feed(first_msg.into()).await {
+            error!("Could not reply iq: {}", e);
+            return;
+        }
+        let mut batch_size: u64 = 1;
+        let deadline = tokio::time::Instant::now() + *batching.flush_interval();
+        while batch_size < *batching.max_batch_size() {
+            let next_msg = if batching.flush_interval().is_zero() {
+                out_recv.try_recv().ok()
+            } else {
+                tokio::time::timeout_at(deadline, out_recv.recv())
+                    .await
+                    .ok()
+                    .flatten()
+            };
+            match next_msg {
+                Some(msg) => {
+                    if let Err(e) = sink.feed(msg.into()).await {
+                        error!("Could not reply iq: {}", e);
+                        return;
+                    }
+                    batch_size += 1;
+                }
+                None => break,
+            }
+        }
+        if let Err(e) = sink.flush().await {
+            error!("Could not flush replies: {}", e);
+            return;
+        }
+        metrics.record(batch_size);
+        if last_report.elapsed() >= *batching.metrics_interval() {
+            metrics.report();
+            last_report = Instant::now();
+        }
+    }
+    metrics.report();
+    if let Err(e) = sink.close().await {
+        error!("Could not close component connection: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        pin::Pin,
+        sync::{Arc, Mutex},
+        task::{Context, Poll},
+    };
+
+    /// Sink recording the size of every flushed batch
+    #[derive(Clone, Default)]
+    struct RecordingSink {
+        pending: Arc<Mutex<usize>>,
+        batches: Arc<Mutex<Vec<usize>>>,
+        closed: Arc<Mutex<bool>>,
+    }
+
+    impl Sink<Element> for RecordingSink {
+        type Error = std::convert::Infallible;
+
+        fn poll_ready(self: Pin<&mut Self>, _: &mut Context) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn start_send(self: Pin<&mut Self>, _: Element) -> Result<(), Self::Error> {
+            *self.pending.lock().unwrap() += 1;
+            Ok(())
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context) -> Poll<Result<(), Self::Error>> {
+            let mut pending = self.pending.lock().unwrap();
+            if *pending > 0 {
+                self.batches.lock().unwrap().push(*pending);
+                *pending = 0;
+            }
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+            *self.closed.lock().unwrap() = true;
+            self.poll_flush(cx)
+        }
+    }
+
+    fn batching(max_batch_size: u64, flush_interval: &str) -> BatchingConfig {
+        serde_json::from_value(serde_json::json!({
+            "maxBatchSize": max_batch_size,
+            "flushInterval": flush_interval,
+            "metricsInterval": "60s",
+        }))
+        .unwrap()
+    }
+
+    fn reply(id: usize) -> Iq {
+        Iq::from_result(
+            id.to_string(),
+            None::<xmpp_parsers::disco::DiscoItemsResult>,
+        )
+    }
+
+    #[tokio::test]
+    async fn test_flush_full_batches() {
+        let sink = RecordingSink::default();
+        let (out_sender, out_recv) = mpsc::channel(16);
+        for id in 0..10 {
+            out_sender.send(reply(id)).await.unwrap();
+        }
+        drop(out_sender);
+        batch_writer(sink.clone(), out_recv, batching(4, "0s")).await;
+        assert_eq!(*sink.batches.lock().unwrap(), vec![4, 4, 2]);
+        assert!(*sink.closed.lock().unwrap());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_flush_after_interval() {
+        let sink = RecordingSink::default();
+        let (out_sender, out_recv) = mpsc::channel(16);
+        let writer = tokio::spawn(batch_writer(sink.clone(), out_recv, batching(64, "10ms")));
+        out_sender.send(reply(1)).await.unwrap();
+        out_sender.send(reply(2)).await.unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        assert_eq!(*sink.batches.lock().unwrap(), vec![2]);
+
+        out_sender.send(reply(3)).await.unwrap();
+        drop(out_sender);
+        writer.await.unwrap();
+        assert_eq!(*sink.batches.lock().unwrap(), vec![2, 1]);
+        assert!(*sink.closed.lock().unwrap());
+    }
+}
@@ -0,0 +1,302 @@
+use std::{
+    pin::Pin,
+    str::FromStr,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use crate::{
+    config::fpush_config::{ComponentEndpoint, ComponentTlsMode, FpushComponentSettings},
+    error::{Error, Result},
+    xmpp::tls::TlsSettings,
+};
+
+use futures::{Sink, Stream, StreamExt};
+use log::debug;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpStream, UnixStream},
+};
+use tokio_xmpp::{xmpp_stream::XMPPStream, AuthError, Packet};
+use xmpp_parsers::{component::Handshake, Element, Jid};
+
+const NS_COMPONENT_ACCEPT: &str = "jabber:component:accept";
+const NS_STREAM: &str = "http://etherx.jabber.org/streams";
+const NS_XMPP_TLS: &str = "urn:ietf:params:xml:ns:xmpp-tls";
+
+/// Time to wait for the stream features offering STARTTLS
+const STARTTLS_FEATURES_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Byte stream the component connection runs over
+pub(crate) trait Transport: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Transport for T {}
+
+/// XEP-0114 component connection over plain TCP, TLS or a unix socket
+///
+/// Behaves like `tokio_xmpp::Component`, which can only open plain TCP connections.
+pub(crate) struct ComponentConnection {
+    stream: XMPPStream<Box<dyn Transport>>,
+    stream_end_sent: bool,
+}
+
+impl ComponentConnection {
+    /// Check the settings of the component connection without connecting
+    ///
+    /// Loads the configured TLS certificates and keys, so missing or invalid files are reported.
+    pub(crate) fn check_settings(config: &FpushComponentSettings) -> Result<()> {
+        Self::component_jid(config)?;
+        Self::component_key(config)?;
+        if let (
+            ComponentEndpoint::Tcp {
+                server_hostname, ..
+            },
+            Some(tls_config),
+        ) = (config.endpoint(), config.tls())
+        {
+            TlsSettings::new(tls_config, server_hostname)?;
+        }
+        Ok(())
+    }
+
+    fn component_jid(config: &FpushComponentSettings) -> Result<Jid> {
+        Jid::from_str(config.component_hostname()).map_err(|e| {
+            Error::Config(format!(
+                "Invalid componentHostname {}: {}",
+                config.component_hostname(),
+                e
+            ))
+        })
+    }
+
+    /// Read the component key, referenced files are read again on every connect
+    fn component_key(config: &FpushComponentSettings) -> Result<String> {
+        config
+            .component_key()
+            .resolve()
+            .map_err(|e| Error::Config(format!("componentKey: {}", e)))
+    }
+
+    /// Open the transport configured in `config` and authenticate as component
+    pub(crate) async fn connect(config: &FpushComponentSettings) -> Result<Self> {
+        let jid = Self::component_jid(config)?;
+        let component_key = Self::component_key(config)?;
+        let transport: Box<dyn Transport> = match (config.endpoint(), config.tls()) {
+            (ComponentEndpoint::Unix { socket_path }, _) => {
+                Box::new(UnixStream::connect(socket_path).await?)
+            }
+            (
+                ComponentEndpoint::Tcp {
+                    server_hostname,
+                    server_port,
+                },
+                tls_config,
+            ) => {
+                let tcp_stream =
+                    TcpStream::connect((server_hostname.as_str(), *server_port)).await?;
+                match tls_config {
+                    None => Box::new(tcp_stream),
+                    Some(tls_config) => {
+                        let tls = TlsSettings::new(tls_config, server_hostname)?;
+                        match tls_config.mode() {
+                            ComponentTlsMode::Direct => Box::new(tls.connect(tcp_stream).await?),
+                            ComponentTlsMode::Starttls => {
+                                let tcp_stream = Self::starttls(tcp_stream, jid.clone()).await?;
+                                Box::new(tls.connect(tcp_stream).await?)
+                            }
+                        }
+                    }
+                }
+            }
+        };
+        let mut stream = XMPPStream::start(transport, jid, NS_COMPONENT_ACCEPT.to_owned()).await?;
+        Self::handshake(&mut stream, &component_key).await?;
+        Ok(Self {
+            stream,
+            stream_end_sent: false,
+        })
+    }
+
+    /// Negotiate STARTTLS and return the plain stream to be wrapped in TLS
+    ///
+    /// Never falls back to an unencrypted connection if the server does not offer STARTTLS.
+    async fn starttls(tcp_stream: TcpStream, jid: Jid) -> Result<TcpStream> {
+        let mut stream = XMPPStream::start(tcp_stream, jid, NS_COMPONENT_ACCEPT.to_owned()).await?;
+        let features = match tokio::time::timeout(STARTTLS_FEATURES_TIMEOUT, stream.next()).await {
+            Ok(Some(Ok(Packet::Stanza(features)))) if features.is("features", NS_STREAM) => {
+                features
+            }
+            Ok(Some(Err(e))) => return Err(e.into()),
+            _ => {
+                return Err(Error::Connection(
+                    "XMPP Server did not send stream features on the component stream".to_string(),
+                ))
+            }
+        };
+        if !features.has_child("starttls", NS_XMPP_TLS) {
+            return Err(Error::Connection(
+                "XMPP Server does not offer STARTTLS on the component stream".to_string(),
+            ));
+        }
+        stream
+            .send_stanza(Element::builder("starttls", NS_XMPP_TLS).build())
+            .await?;
+        loop {
+            match stream.next().await {
+                Some(Ok(Packet::Stanza(ref stanza))) if stanza.is("proceed", NS_XMPP_TLS) => {
+                    debug!("XMPP Server accepted STARTTLS");
+                    return Ok(stream.into_inner());
+                }
+                Some(Ok(Packet::Text(_))) => {}
+                Some(Ok(_)) => {
+                    return Err(Error::Connection(
+                        "XMPP Server refused STARTTLS on the component stream".to_string(),
+                    ))
+                }
+                Some(Err(e)) => return Err(e.into()),
+                None => return Err(tokio_xmpp::Error::Disconnected.into()),
+            }
+        }
+    }
+
+    /// Authenticate as component using the XEP-0114 handshake
+    async fn handshake(stream: &mut XMPPStream<Box<dyn Transport>>, password: &str) -> Result<()> {
+        let handshake = Handshake::from_password_and_stream_id(password, &stream.id);
+        stream.send_stanza(handshake).await?;
+        loop {
+            match stream.next().await {
+                Some(Ok(Packet::Stanza(ref stanza)))
+                    if stanza.is("handshake", NS_COMPONENT_ACCEPT) =>
+                {
+                    return Ok(())
+                }
+                Some(Ok(Packet::Text(_))) => {}
+                Some(Ok(_)) => return Err(tokio_xmpp::Error::Auth(AuthError::ComponentFail).into()),
+                Some(Err(e)) => return Err(e.into()),
+                None => return Err(tokio_xmpp::Error::Disconnected.into()),
+            }
+        }
+    }
+}
+
+impl Stream for ComponentConnection {
+    type Item = Element;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        loop {
+            match Pin::new(&mut self.stream).poll_next(cx) {
+                Poll::Ready(Some(Ok(Packet::Stanza(stanza)))) => return Poll::Ready(Some(stanza)),
+                Poll::Ready(Some(Ok(Packet::Text(_)))) => {
+                    // ignore whitespace keepalives
+                }
+                Poll::Ready(Some(Ok(_))) | Poll::Ready(Some(Err(_))) | Poll::Ready(None) => {
+                    return Poll::Ready(None)
+                }
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+impl Sink<Element> for ComponentConnection {
+    type Error = tokio_xmpp::Error;
+
+    fn poll_ready(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context,
+    ) -> Poll<std::result::Result<(), Self::Error>> {
+        Pin::new(&mut self.stream).poll_ready(cx)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: Element) -> std::result::Result<(), Self::Error> {
+        Pin::new(&mut self.stream).start_send(Packet::Stanza(item))
+    }
+
+    fn poll_flush(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context,
+    ) -> Poll<std::result::Result<(), Self::Error>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    /// Close the XMPP stream before closing the transport
+    fn poll_close(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context,
+    ) -> Poll<std::result::Result<(), Self::Error>> {
+        if !self.stream_end_sent {
+            match Pin::new(&mut self.stream).poll_ready(cx) {
+                Poll::Ready(Ok(())) => {}
+                other => return other,
+            }
+            Pin::new(&mut self.stream).start_send(Packet::StreamEnd)?;
+            self.stream_end_sent = true;
+        }
+        Pin::new(&mut self.stream).poll_close(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn component_settings(settings: serde_json::Value) -> FpushComponentSettings {
+        serde_json::from_value(settings).unwrap()
+    }
+
+    #[test]
+    fn test_endpoints() {
+        let tcp = component_settings(serde_json::json!({
+            "componentHostname": "push.example.org",
+            "componentKey": "key",
+            "serverHostname": "xmpp.example.org",
+            "serverPort": 5347
+        }));
+        assert_eq!(tcp.endpoint().to_string(), "xmpp.example.org:5347");
+        assert!(ComponentConnection::check_settings(&tcp).is_ok());
+
+        let unix = component_settings(serde_json::json!({
+            "componentHostname": "push.example.org",
+            "componentKey": "key",
+            "socketPath": "/run/prosody/component.sock"
+        }));
+        assert_eq!(
+            unix.endpoint().to_string(),
+            "unix:/run/prosody/component.sock"
+        );
+        assert!(ComponentConnection::check_settings(&unix).is_ok());
+    }
+
+    #[test]
+    fn test_invalid_settings() {
+        let invalid_jid = component_settings(serde_json::json!({
+            "componentHostname": "@push.example.org",
+            "componentKey": "key",
+            "serverHostname": "xmpp.example.org",
+            "serverPort": 5347
+        }));
+        assert!(matches!(
+            ComponentConnection::check_settings(&invalid_jid),
+            Err(Error::Config(e)) if e.starts_with("Invalid componentHostname")
+        ));
+
+        let missing_key = component_settings(serde_json::json!({
+            "componentHostname": "push.example.org",
+            "componentKey": { "file": "/nonexistent/fpush-component-key" },
+            "serverHostname": "xmpp.example.org",
+            "serverPort": 5347
+        }));
+        assert!(matches!(
+            ComponentConnection::check_settings(&missing_key),
+            Err(Error::Config(e)) if e.starts_with("componentKey")
+        ));
+
+        let missing_ca = component_settings(serde_json::json!({
+            "componentHostname": "push.example.org",
+            "componentKey": "key",
+            "serverHostname": "xmpp.example.org",
+            "serverPort": 5347,
+            "tls": { "mode": "starttls", "caFile": "/nonexistent/fpush-ca.pem" }
+        }));
+        assert!(ComponentConnection::check_settings(&missing_ca).is_err());
+    }
+}
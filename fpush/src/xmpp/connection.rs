@@ -0,0 +1,111 @@
+use std::sync::Arc;
+
+use crate::config::fpush_config::{AuthFailurePolicy, FpushComponentSettings, FpushConfig};
+use crate::xmpp::{
+    init_component_connection, message_loop_main_thread, Backoff, ConnectionMonitor,
+    ConnectionState, MessageLoopExit, XmppContextArc,
+};
+
+use log::{error, info};
+use tokio::sync::watch;
+
+/// Reason the reconnect loop of a component connection returned
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum ConnectionExit {
+    Shutdown,
+    /// the handshake was rejected and `onAuthFailure` is set to `exit`
+    AuthFailed,
+}
+
+/// Keep the component connection open until shutdown is requested
+///
+/// Each configured component connection runs its own reconnect loop and message loop, while
+/// all of them share the push modules in `ctx`.
+pub(crate) async fn component_connection_loop(
+    component: FpushComponentSettings,
+    settings: Arc<FpushConfig>,
+    ctx: XmppContextArc,
+    monitor: Arc<ConnectionMonitor>,
+    shutdown_sender: Arc<watch::Sender<bool>>,
+) -> ConnectionExit {
+    let mut shutdown = shutdown_sender.subscribe();
+    let connection_name = component.component_hostname().clone();
+    let mut backoff = Backoff::new(
+        *settings.timeout().xmppconnection_error(),
+        settings.reconnect(),
+    );
+    let mut exit = ConnectionExit::Shutdown;
+
+    while !*shutdown.borrow() {
+        info!(
+            "Opening connection to {} as {}",
+            component.endpoint(),
+            connection_name
+        );
+        monitor.update(
+            &connection_name,
+            ConnectionState::Connecting,
+            backoff.failed_attempts(),
+            None,
+        );
+        // open component connection
+        match init_component_connection(&component).await {
+            Err(e) => {
+                let delay = backoff.next_delay();
+                if e.is_auth_failure() {
+                    error!(
+                        "XMPP Server rejected the handshake of {}, check componentKey: {}",
+                        connection_name, e
+                    );
+                    monitor.update(
+                        &connection_name,
+                        ConnectionState::AuthFailed,
+                        backoff.failed_attempts(),
+                        Some(e.to_string()),
+                    );
+                    if *settings.reconnect().on_auth_failure() == AuthFailurePolicy::Exit {
+                        // stop all other component connections as well
+                        exit = ConnectionExit::AuthFailed;
+                        shutdown_sender.send_replace(true);
+                        break;
+                    }
+                } else {
+                    error!("Could not connect to XMPP Server {}", e);
+                    monitor.update(
+                        &connection_name,
+                        ConnectionState::Disconnected,
+                        backoff.failed_attempts(),
+                        Some(e.to_string()),
+                    );
+                }
+                info!(
+                    "Waiting {:.1} seconds before reconnecting {}",
+                    delay.as_secs_f64(),
+                    connection_name
+                );
+                tokio::select! {
+                    _ = tokio::time::sleep(delay) => {}
+                    _ = shutdown.changed() => {}
+                }
+            }
+            Ok(conn) => {
+                backoff.reset();
+                monitor.update(&connection_name, ConnectionState::Connected, 0, None);
+                // open new messageLoop
+                let loop_exit = message_loop_main_thread(conn, ctx.clone(), shutdown.clone()).await;
+                if loop_exit == MessageLoopExit::Shutdown {
+                    break;
+                }
+                monitor.update(
+                    &connection_name,
+                    ConnectionState::Disconnected,
+                    0,
+                    Some("Connection closed".to_string()),
+                );
+            }
+        }
+    }
+
+    monitor.update(&connection_name, ConnectionState::Stopped, 0, None);
+    exit
+}
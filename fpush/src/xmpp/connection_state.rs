@@ -0,0 +1,137 @@
+use std::{
+    collections::BTreeMap,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use log::{error, info, warn};
+use serde::Serialize;
+
+/// State of a component connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) enum ConnectionState {
+    Connecting,
+    Connected,
+    /// the connection failed or was lost, waiting before reconnecting
+    Disconnected,
+    /// the XMPP server rejected the component handshake
+    AuthFailed,
+    Stopped,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ConnectionStatus {
+    state: ConnectionState,
+    /// unix timestamp of the last state change
+    since: u64,
+    failed_attempts: u32,
+    last_error: Option<String>,
+}
+
+/// Tracks the state of all component connections and writes it to the optional status file
+///
+/// The status file holds a json object mapping each component hostname to its state, so it
+/// can be picked up by monitoring to alert on lost connections.
+pub(crate) struct ConnectionMonitor {
+    status_file: Option<PathBuf>,
+    connections: Mutex<BTreeMap<String, ConnectionStatus>>,
+}
+
+impl ConnectionMonitor {
+    pub(crate) fn new(status_file: Option<PathBuf>) -> Self {
+        Self {
+            status_file,
+            connections: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Record a state change of the connection `name`
+    pub(crate) fn update(
+        &self,
+        name: &str,
+        state: ConnectionState,
+        failed_attempts: u32,
+        last_error: Option<String>,
+    ) {
+        match state {
+            ConnectionState::AuthFailed => error!("Component connection {} is {:?}", name, state),
+            ConnectionState::Disconnected => warn!("Component connection {} is {:?}", name, state),
+            _ => info!("Component connection {} is {:?}", name, state),
+        }
+        let since = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        let mut connections = self.connections.lock().unwrap();
+        connections.insert(
+            name.to_string(),
+            ConnectionStatus {
+                state,
+                since,
+                failed_attempts,
+                last_error,
+            },
+        );
+        if let Some(status_file) = &self.status_file {
+            if let Err(e) = Self::write_status_file(status_file, &connections) {
+                error!(
+                    "Could not write connection status to {}: {}",
+                    status_file.display(),
+                    e
+                );
+            }
+        }
+    }
+
+    /// Write the status to a temporary file and atomically replace the status file
+    fn write_status_file(
+        status_file: &Path,
+        connections: &BTreeMap<String, ConnectionStatus>,
+    ) -> crate::error::Result<()> {
+        let tmp_path = status_file.with_extension("tmp");
+        {
+            let mut tmp_writer = std::io::BufWriter::new(std::fs::File::create(&tmp_path)?);
+            serde_json::to_writer(&mut tmp_writer, connections)?;
+            tmp_writer.flush()?;
+        }
+        std::fs::rename(&tmp_path, status_file)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_status_file() {
+        let status_file = std::env::temp_dir().join(format!(
+            "fpush-connection-status-{}.json",
+            std::process::id()
+        ));
+        let monitor = ConnectionMonitor::new(Some(status_file.clone()));
+        monitor.update("push.example.org", ConnectionState::Connected, 0, None);
+        monitor.update(
+            "push2.example.org",
+            ConnectionState::Disconnected,
+            3,
+            Some("Connection refused".to_string()),
+        );
+        monitor.update("push.example.org", ConnectionState::AuthFailed, 1, None);
+
+        let status: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(&status_file).unwrap()).unwrap();
+        assert_eq!(status["push.example.org"]["state"], "authFailed");
+        assert_eq!(status["push.example.org"]["failedAttempts"], 1);
+        assert_eq!(status["push2.example.org"]["state"], "disconnected");
+        assert_eq!(
+            status["push2.example.org"]["lastError"],
+            "Connection refused"
+        );
+        std::fs::remove_file(status_file).unwrap();
+    }
+}
@@ -0,0 +1,194 @@
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use crate::{
+    config::fpush_config::{BatchingConfig, ConcurrencyConfig, FpushConfig},
+    error::Result,
+    registration::TokenStore,
+};
+use fpush_push::{FpushPushArc, PushRequest};
+use fpush_ratelimit::FpushDomainRateLimit;
+
+use derive_getters::Getters;
+
+pub(crate) type XmppContextArc = Arc<XmppContext>;
+
+/// State shared between all handlers of incoming stanzas
+#[derive(Getters)]
+pub(crate) struct XmppContext {
+    push_modules: FpushPushArc,
+    registration: Option<RegistrationContext>,
+    domain_ratelimit: Arc<FpushDomainRateLimit>,
+    concurrency: ConcurrencyConfig,
+    batching: BatchingConfig,
+    shutdown_deadline: Duration,
+}
+
+#[derive(Getters)]
+pub(crate) struct RegistrationContext {
+    token_store: TokenStore,
+    commands: HashMap<String, String>,
+    allow_unregistered_tokens: bool,
+}
+
+impl XmppContext {
+    pub(crate) fn new(config: &FpushConfig, push_modules: FpushPushArc) -> Result<Self> {
+        let registration = match config.registration() {
+            Some(registration_config) => Some(RegistrationContext {
+                token_store: TokenStore::open(
+                    registration_config.store_path(),
+                    *registration_config.max_registrations_per_account(),
+                )?,
+                commands: registration_config.commands().clone(),
+                allow_unregistered_tokens: *registration_config.allow_unregistered_tokens(),
+            }),
+            None => None,
+        };
+        let domain_ratelimit = Arc::new(FpushDomainRateLimit::new(config.domain_ratelimit()));
+        if config.domain_ratelimit().is_enabled() {
+            Self::spawn_domain_ratelimit_cleanup(
+                domain_ratelimit.clone(),
+                config.domain_ratelimit().cleanup_interval(),
+            );
+        }
+        Ok(Self {
+            push_modules,
+            registration,
+            domain_ratelimit,
+            concurrency: config.concurrency().clone(),
+            batching: config.batching().clone(),
+            shutdown_deadline: *config.timeout().shutdown_deadline(),
+        })
+    }
+
+    fn spawn_domain_ratelimit_cleanup(
+        domain_ratelimit: Arc<FpushDomainRateLimit>,
+        cleanup_interval: Duration,
+    ) {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(cleanup_interval);
+            loop {
+                interval.tick().await;
+                domain_ratelimit.cleanup();
+            }
+        });
+    }
+}
+
+/// Result of looking up the pubsub node of a push request in the token store
+pub(crate) enum NodeResolution {
+    /// module id and push request with the device token
+    Resolved(String, Box<PushRequest>),
+    /// the node is registered for the push module, but the secret did not match
+    NotAuthorized(String),
+    /// the node is unknown and raw tokens are not accepted, contains the requested push module
+    UnknownNode(String),
+}
+
+impl RegistrationContext {
+    /// Replace the opaque node of a push request with the registered device token
+    pub(crate) fn resolve(&self, module_id: String, push_request: PushRequest) -> NodeResolution {
+        match self.token_store.lookup(push_request.token()) {
+            Some(registration) => {
+                if !registration.verify_secret(push_request.publish_option("secret")) {
+                    return NodeResolution::NotAuthorized(registration.module_id().to_string());
+                }
+                NodeResolution::Resolved(
+                    registration.module_id().to_string(),
+                    Box::new(
+                        push_request
+                            .with_token(registration.token().to_string())
+                            .with_verified_secret(),
+                    ),
+                )
+            }
+            None if self.allow_unregistered_tokens => {
+                NodeResolution::Resolved(module_id, Box::new(push_request))
+            }
+            None => NodeResolution::UnknownNode(module_id),
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Create a context without push modules, enabling registration if settings are given
+    pub(crate) async fn test_context(registration: Option<serde_json::Value>) -> XmppContext {
+        let mut settings = serde_json::json!({
+            "component": {
+                "componentHostname": "push.example.org",
+                "componentKey": "key",
+                "serverHostname": "localhost",
+                "serverPort": 5347
+            },
+            "pushModules": {}
+        });
+        if let Some(registration) = registration {
+            settings["registration"] = registration;
+        }
+        let config: FpushConfig = serde_json::from_value(settings).unwrap();
+        let push_modules = fpush_push::FpushPush::new(config.push_modules(), None)
+            .await
+            .unwrap();
+        XmppContext::new(&config, Arc::new(push_modules)).unwrap()
+    }
+
+    pub(crate) fn registration_settings(name: &str) -> serde_json::Value {
+        let store_path = std::env::temp_dir().join(format!(
+            "fpush-registrations-{}-{}.json",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&store_path);
+        serde_json::json!({
+            "storePath": store_path,
+            "commands": { "register-push-fcm": "google" },
+            "allowUnregisteredTokens": false,
+            "maxRegistrationsPerAccount": 2
+        })
+    }
+
+    fn request_with_secret(node: &str, secret: &str) -> PushRequest {
+        PushRequest::new(node.to_string()).with_publish_options(HashMap::from([(
+            "secret".to_string(),
+            vec![secret.to_string()],
+        )]))
+    }
+
+    #[tokio::test]
+    async fn test_resolve_registered_node() {
+        let ctx = test_context(Some(registration_settings("resolve"))).await;
+        let registration = ctx.registration().as_ref().unwrap();
+        let (node, push_registration) = registration
+            .token_store()
+            .register("google", "device-token", "user@example.org", "dev1")
+            .await
+            .unwrap()
+            .unwrap();
+
+        match registration.resolve(
+            "apple".to_string(),
+            request_with_secret(&node, push_registration.secret()),
+        ) {
+            NodeResolution::Resolved(module_id, push_request) => {
+                assert_eq!(module_id, "google");
+                assert_eq!(push_request.token(), "device-token");
+                assert!(push_request.secret_verified());
+                assert_eq!(push_request.publish_option("secret"), None);
+            }
+            _ => panic!("registered node was not resolved"),
+        }
+        assert!(matches!(
+            registration.resolve("google".to_string(), request_with_secret(&node, "wrong")),
+            NodeResolution::NotAuthorized(module_id) if module_id == "google"
+        ));
+        assert!(matches!(
+            registration.resolve(
+                "google".to_string(),
+                PushRequest::new("unknown-node".to_string())
+            ),
+            NodeResolution::UnknownNode(module_id) if module_id == "google"
+        ));
+    }
+}
@@ -0,0 +1,286 @@
+use crate::xmpp::{ad_hoc::NS_COMMANDS, context::XmppContext};
+use log::error;
+use tokio::sync::mpsc;
+use xmpp_parsers::{
+    disco::{
+        DiscoInfoQuery, DiscoInfoResult, DiscoItemsQuery, DiscoItemsResult, Feature, Identity, Item,
+    },
+    iq::Iq,
+    ns,
+    stanza_error::{DefinedCondition, ErrorType, StanzaError},
+    Element, Jid,
+};
+
+/// Namespace of XEP-0357 push notifications
+pub(crate) const NS_PUSH: &str = "urn:xmpp:push:0";
+
+#[inline(always)]
+pub fn is_disco_info_query(iq_payload: &Element) -> bool {
+    iq_payload.is("query", ns::DISCO_INFO)
+}
+
+#[inline(always)]
+pub fn is_disco_items_query(iq_payload: &Element) -> bool {
+    iq_payload.is("query", ns::DISCO_ITEMS)
+}
+
+fn push_component_features(ctx: &XmppContext) -> Vec<Feature> {
+    let mut features = vec![
+        Feature::new(ns::DISCO_INFO),
+        Feature::new(ns::DISCO_ITEMS),
+        Feature::new(ns::PING),
+        Feature::new(NS_PUSH),
+    ];
+    if ctx.registration().is_some() {
+        features.push(Feature::new(NS_COMMANDS));
+    }
+    features
+}
+
+/// true if the node is either a push module or the ad-hoc command list
+#[inline(always)]
+fn is_known_node(ctx: &XmppContext, node: &str) -> bool {
+    ctx.push_modules().has_push_module(node)
+        || (node == NS_COMMANDS && ctx.registration().is_some())
+}
+
+/// Answer a disco#info query for the component itself or one of the push modules announced as node
+pub async fn send_disco_info_iq(
+    conn: &mpsc::Sender<Iq>,
+    ctx: &XmppContext,
+    iq_payload: Element,
+    id: &str,
+    jid: Jid,
+    from: Jid,
+) {
+    let query = match DiscoInfoQuery::try_from(iq_payload) {
+        Ok(query) => query,
+        Err(_) => {
+            send_disco_error_iq(conn, id, jid, from, DefinedCondition::BadRequest).await;
+            return;
+        }
+    };
+    if let Some(node) = &query.node {
+        if !is_known_node(ctx, node) {
+            send_disco_error_iq(conn, id, jid, from, DefinedCondition::ItemNotFound).await;
+            return;
+        }
+    }
+    let disco_info = DiscoInfoResult {
+        node: query.node,
+        identities: vec![Identity::new("pubsub", "push", "en", "fpush")],
+        features: push_component_features(ctx),
+        extensions: vec![],
+    };
+    if let Err(e) = conn
+        .send(
+            Iq::from_result((*id).to_string(), Some(disco_info))
+                .with_to(jid)
+                .with_from(from),
+        )
+        .await
+    {
+        error!("Could not forward outgoing iq to main handler: {}", e);
+    }
+}
+
+/// Answer a disco#items query by listing all configured push modules as nodes
+pub async fn send_disco_items_iq(
+    conn: &mpsc::Sender<Iq>,
+    ctx: &XmppContext,
+    iq_payload: Element,
+    id: &str,
+    jid: Jid,
+    from: Jid,
+) {
+    let query = match DiscoItemsQuery::try_from(iq_payload) {
+        Ok(query) => query,
+        Err(_) => {
+            send_disco_error_iq(conn, id, jid, from, DefinedCondition::BadRequest).await;
+            return;
+        }
+    };
+    if let Some(node) = &query.node {
+        if !is_known_node(ctx, node) {
+            send_disco_error_iq(conn, id, jid, from, DefinedCondition::ItemNotFound).await;
+            return;
+        }
+    }
+    // push modules do not have any sub items
+    let items = if query.node.as_deref() == Some(NS_COMMANDS) {
+        command_items(ctx, &from)
+    } else if query.node.is_none() {
+        ctx.push_modules()
+            .push_module_ids()
+            .into_iter()
+            .map(|module_id| Item {
+                jid: from.clone(),
+                node: Some(module_id.clone()),
+                name: Some(module_id),
+            })
+            .collect()
+    } else {
+        vec![]
+    };
+    let disco_items = DiscoItemsResult {
+        node: query.node,
+        items,
+    };
+    if let Err(e) = conn
+        .send(
+            Iq::from_result((*id).to_string(), Some(disco_items))
+                .with_to(jid)
+                .with_from(from),
+        )
+        .await
+    {
+        error!("Could not forward outgoing iq to main handler: {}", e);
+    }
+}
+
+/// List all registration commands as described in XEP-0050
+fn command_items(ctx: &XmppContext, component_jid: &Jid) -> Vec<Item> {
+    let mut command_nodes: Vec<&String> = match ctx.registration() {
+        Some(registration) => registration.commands().keys().collect(),
+        None => vec![],
+    };
+    command_nodes.sort();
+    command_nodes
+        .into_iter()
+        .map(|command_node| Item {
+            jid: component_jid.clone(),
+            node: Some(command_node.to_string()),
+            name: Some(command_node.to_string()),
+        })
+        .collect()
+}
+
+#[inline(always)]
+async fn send_disco_error_iq(
+    conn: &mpsc::Sender<Iq>,
+    id: &str,
+    jid: Jid,
+    from: Jid,
+    condition: DefinedCondition,
+) {
+    let error_stanza = StanzaError::new(ErrorType::Cancel, condition, "en", "Invalid disco query");
+    if let Err(e) = conn
+        .send(
+            Iq::from_error((*id).to_string(), error_stanza)
+                .with_to(jid)
+                .with_from(from),
+        )
+        .await
+    {
+        error!("Could not forward outgoing iq to main handler: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::xmpp::context::tests::{registration_settings, test_context};
+    use std::str::FromStr;
+    use xmpp_parsers::iq::IqType;
+
+    fn query(ns: &str, node: Option<&str>) -> Element {
+        let mut query = Element::builder("query", ns);
+        if let Some(node) = node {
+            query = query.attr("node", node);
+        }
+        query.build()
+    }
+
+    async fn disco_info(ctx: &XmppContext, node: Option<&str>) -> IqType {
+        let (conn, mut rx) = mpsc::channel(1);
+        send_disco_info_iq(
+            &conn,
+            ctx,
+            query(ns::DISCO_INFO, node),
+            "disco1",
+            Jid::from_str("user@example.org/phone").unwrap(),
+            Jid::from_str("push.example.org").unwrap(),
+        )
+        .await;
+        rx.recv().await.unwrap().payload
+    }
+
+    async fn disco_items(ctx: &XmppContext, node: Option<&str>) -> IqType {
+        let (conn, mut rx) = mpsc::channel(1);
+        send_disco_items_iq(
+            &conn,
+            ctx,
+            query(ns::DISCO_ITEMS, node),
+            "disco1",
+            Jid::from_str("user@example.org/phone").unwrap(),
+            Jid::from_str("push.example.org").unwrap(),
+        )
+        .await;
+        rx.recv().await.unwrap().payload
+    }
+
+    fn feature_vars(payload: IqType) -> Vec<String> {
+        match payload {
+            IqType::Result(Some(result)) => DiscoInfoResult::try_from(result)
+                .unwrap()
+                .features
+                .into_iter()
+                .map(|feature| feature.var)
+                .collect(),
+            payload => panic!("expected disco#info result, got {:?}", payload),
+        }
+    }
+
+    fn is_item_not_found(payload: &IqType) -> bool {
+        matches!(payload, IqType::Error(error) if error.defined_condition == DefinedCondition::ItemNotFound)
+    }
+
+    #[tokio::test]
+    async fn test_disco_info() {
+        let ctx = test_context(None).await;
+        let features = feature_vars(disco_info(&ctx, None).await);
+        assert!(features.contains(&NS_PUSH.to_string()));
+        assert!(features.contains(&ns::PING.to_string()));
+        assert!(!features.contains(&NS_COMMANDS.to_string()));
+        assert!(is_item_not_found(&disco_info(&ctx, Some("unknown")).await));
+        assert!(is_item_not_found(
+            &disco_info(&ctx, Some(NS_COMMANDS)).await
+        ));
+
+        let ctx = test_context(Some(registration_settings("disco-info"))).await;
+        let features = feature_vars(disco_info(&ctx, None).await);
+        assert!(features.contains(&NS_COMMANDS.to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_disco_items() {
+        let ctx = test_context(Some(registration_settings("disco-items"))).await;
+        let items = match disco_items(&ctx, Some(NS_COMMANDS)).await {
+            IqType::Result(Some(result)) => DiscoItemsResult::try_from(result).unwrap().items,
+            payload => panic!("expected disco#items result, got {:?}", payload),
+        };
+        assert_eq!(items.len(), 1);
+        assert_eq!(items[0].node.as_deref(), Some("register-push-fcm"));
+        assert_eq!(items[0].jid, Jid::from_str("push.example.org").unwrap());
+        assert!(is_item_not_found(&disco_items(&ctx, Some("unknown")).await));
+    }
+
+    #[tokio::test]
+    async fn test_invalid_query() {
+        let ctx = test_context(None).await;
+        let (conn, mut rx) = mpsc::channel(1);
+        send_disco_info_iq(
+            &conn,
+            &ctx,
+            Element::builder("query", ns::DISCO_ITEMS).build(),
+            "disco1",
+            Jid::from_str("user@example.org/phone").unwrap(),
+            Jid::from_str("push.example.org").unwrap(),
+        )
+        .await;
+        assert!(matches!(
+            rx.recv().await.unwrap().payload,
+            IqType::Error(error) if error.defined_condition == DefinedCondition::BadRequest
+        ));
+    }
+}
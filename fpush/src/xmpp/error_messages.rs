@@ -1,6 +1,11 @@
+use fpush_push::{ErrorReply, StanzaErrorCondition, StanzaErrorType};
 use log::error;
 use tokio::sync::mpsc;
-use xmpp_parsers::{iq::Iq, stanza_error::StanzaError, Jid};
+use xmpp_parsers::{
+    iq::Iq,
+    stanza_error::{DefinedCondition, ErrorType, StanzaError},
+    Jid,
+};
 
 #[inline(always)]
 pub async fn send_ack_iq(conn: &mpsc::Sender<Iq>, id: &str, jid: Jid, from: Jid) {
@@ -13,10 +18,10 @@ pub async fn send_ack_iq(conn: &mpsc::Sender<Iq>, id: &str, jid: Jid, from: Jid)
 }
 
 #[inline(always)]
-pub async fn send_error_policy_iq(conn: &mpsc::Sender<Iq>, id: &str, jid: Jid, from: Jid) {
+pub async fn send_error_iq(conn: &mpsc::Sender<Iq>, id: &str, jid: Jid, from: Jid) {
     let error_stanza = StanzaError::new(
-        xmpp_parsers::stanza_error::ErrorType::Cancel,
-        xmpp_parsers::stanza_error::DefinedCondition::PolicyViolation,
+        ErrorType::Cancel,
+        DefinedCondition::BadRequest,
         "en",
         "A error occured",
     );
@@ -33,13 +38,16 @@ pub async fn send_error_policy_iq(conn: &mpsc::Sender<Iq>, id: &str, jid: Jid, f
 }
 
 #[inline(always)]
-pub async fn send_error_iq(conn: &mpsc::Sender<Iq>, id: &str, jid: Jid, from: Jid) {
-    let error_stanza = StanzaError::new(
-        xmpp_parsers::stanza_error::ErrorType::Cancel,
-        xmpp_parsers::stanza_error::DefinedCondition::BadRequest,
-        "en",
-        "A error occured",
-    );
+pub async fn send_stanza_error_iq(
+    conn: &mpsc::Sender<Iq>,
+    id: &str,
+    jid: Jid,
+    from: Jid,
+    error_type: ErrorType,
+    condition: DefinedCondition,
+    text: &str,
+) {
+    let error_stanza = StanzaError::new(error_type, condition, "en", text);
     if let Err(e) = conn
         .send(
             Iq::from_error((*id).to_string(), error_stanza)
@@ -52,27 +60,59 @@ pub async fn send_error_iq(conn: &mpsc::Sender<Iq>, id: &str, jid: Jid, from: Ji
     }
 }
 
+/// Reply to a failed push request as configured in the error policy of the push module
 #[inline(always)]
-pub async fn send_wait_iq_reason_old_prosody(
+pub async fn send_error_reply_iq(
     conn: &mpsc::Sender<Iq>,
     id: &str,
     jid: Jid,
     from: Jid,
+    reply: &ErrorReply,
 ) {
-    let error_stanza = StanzaError::new(
-        xmpp_parsers::stanza_error::ErrorType::Wait,
-        xmpp_parsers::stanza_error::DefinedCondition::BadRequest,
-        "en",
-        "Invalid push format, update your prosody community modules (debian stable has a bug, use backports instead)",
-    );
-    if let Err(e) = conn
-        .send(
-            Iq::from_error((*id).to_string(), error_stanza)
-                .with_to(jid)
-                .with_from(from),
-        )
-        .await
-    {
-        error!("Could not forward outgoing iq to main handler: {}", e);
+    match reply {
+        ErrorReply::Ack => send_ack_iq(conn, id, jid, from).await,
+        ErrorReply::Error(error_reply) => {
+            send_stanza_error_iq(
+                conn,
+                id,
+                jid,
+                from,
+                to_error_type(*error_reply.error_type()),
+                to_defined_condition(*error_reply.condition()),
+                &error_reply.text_with_retry_hint(),
+            )
+            .await
+        }
+    }
+}
+
+fn to_error_type(error_type: StanzaErrorType) -> ErrorType {
+    match error_type {
+        StanzaErrorType::Auth => ErrorType::Auth,
+        StanzaErrorType::Cancel => ErrorType::Cancel,
+        StanzaErrorType::Continue => ErrorType::Continue,
+        StanzaErrorType::Modify => ErrorType::Modify,
+        StanzaErrorType::Wait => ErrorType::Wait,
+    }
+}
+
+fn to_defined_condition(condition: StanzaErrorCondition) -> DefinedCondition {
+    match condition {
+        StanzaErrorCondition::BadRequest => DefinedCondition::BadRequest,
+        StanzaErrorCondition::Conflict => DefinedCondition::Conflict,
+        StanzaErrorCondition::FeatureNotImplemented => DefinedCondition::FeatureNotImplemented,
+        StanzaErrorCondition::Forbidden => DefinedCondition::Forbidden,
+        StanzaErrorCondition::InternalServerError => DefinedCondition::InternalServerError,
+        StanzaErrorCondition::ItemNotFound => DefinedCondition::ItemNotFound,
+        StanzaErrorCondition::NotAcceptable => DefinedCondition::NotAcceptable,
+        StanzaErrorCondition::NotAllowed => DefinedCondition::NotAllowed,
+        StanzaErrorCondition::NotAuthorized => DefinedCondition::NotAuthorized,
+        StanzaErrorCondition::PolicyViolation => DefinedCondition::PolicyViolation,
+        StanzaErrorCondition::RecipientUnavailable => DefinedCondition::RecipientUnavailable,
+        StanzaErrorCondition::RegistrationRequired => DefinedCondition::RegistrationRequired,
+        StanzaErrorCondition::ResourceConstraint => DefinedCondition::ResourceConstraint,
+        StanzaErrorCondition::ServiceUnavailable => DefinedCondition::ServiceUnavailable,
+        StanzaErrorCondition::UndefinedCondition => DefinedCondition::UndefinedCondition,
+        StanzaErrorCondition::UnexpectedRequest => DefinedCondition::UnexpectedRequest,
     }
 }
@@ -1,245 +1,426 @@
-use crate::config::fpush_config::FpushConfig;
-use crate::xmpp::error_messages::send_wait_iq_reason_old_prosody;
+use crate::config::fpush_config::{FpushComponentSettings, OverflowPolicy};
+use crate::xmpp::ad_hoc::{handle_ad_hoc_command, is_ad_hoc_command};
+use crate::xmpp::batch_writer::batch_writer;
+use crate::xmpp::component::ComponentConnection;
+use crate::xmpp::context::{NodeResolution, XmppContext, XmppContextArc};
+use crate::xmpp::disco::{
+    is_disco_info_query, is_disco_items_query, send_disco_info_iq, send_disco_items_iq,
+};
+use crate::xmpp::push_iq::PushIq;
 use crate::{
-    error::{Error, Result},
-    xmpp::error_messages::{send_ack_iq, send_error_iq, send_error_policy_iq},
+    error::Result,
+    xmpp::error_messages::{send_ack_iq, send_error_iq, send_error_reply_iq, send_stanza_error_iq},
 };
-use fpush_push::{FpushPushArc, PushRequestError, PushRequestResult};
+use fpush_push::PushRequestError;
+
+use std::{sync::Arc, time::Duration};
 
-use futures::{SinkExt, StreamExt};
+use futures::StreamExt;
 use log::{debug, error, info, warn};
 
-use tokio::sync::mpsc;
-use tokio_xmpp::Component;
-use xmpp_parsers::{data_forms::Field, iq::Iq, pubsub::PubSub, Element, Jid};
+use tokio::{
+    sync::{mpsc, watch, OwnedSemaphorePermit, Semaphore},
+    task::JoinHandle,
+};
+use xmpp_parsers::{
+    iq::{Iq, IqType},
+    stanza_error::{DefinedCondition, ErrorType, StanzaError},
+    Element, Jid,
+};
 
-pub(crate) async fn init_component_connection(config: &FpushConfig) -> Result<Component> {
-    let component = Component::new(
-        config.component().component_hostname(),
-        config.component().component_key(),
-        config.component().server_hostname(),
-        *config.component().server_port(),
-    )
-    .await?;
+/// Reply slots reserved for wait errors sent by the message loop itself
+const OVERLOAD_REPLY_SLOTS: usize = 512;
+
+/// Reason the message loop of a component connection returned
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum MessageLoopExit {
+    /// the connection was lost and should be reopened
+    ConnectionClosed,
+    /// shutdown was requested and in-flight requests were drained
+    Shutdown,
+}
+
+pub(crate) async fn init_component_connection(
+    config: &FpushComponentSettings,
+) -> Result<ComponentConnection> {
+    let component = ComponentConnection::connect(config).await?;
 
     Ok(component)
 }
 
 #[inline(always)]
 pub(crate) async fn message_loop_main_thread(
-    mut conn: tokio_xmpp::Component,
-    push_modules: FpushPushArc,
-) {
+    conn: ComponentConnection,
+    ctx: XmppContextArc,
+    mut shutdown: watch::Receiver<bool>,
+) -> MessageLoopExit {
     // #[cfg(feature = "random_delay_before_push")]
     //let mut rng = rand::thread_rng();
 
-    let (out_sender, mut out_recv) = mpsc::channel::<Iq>(3000);
+    let concurrency = ctx.concurrency();
+    // every admitted stanza causes at most one reply, so handlers never wait for the channel
+    let admission = Arc::new(Semaphore::new(
+        concurrency.max_in_flight() + concurrency.queue_size(),
+    ));
+    let in_flight = Arc::new(Semaphore::new(*concurrency.max_in_flight()));
+    let backpressure = *concurrency.overflow() == OverflowPolicy::Backpressure;
+
+    let (out_sender, out_recv) = mpsc::channel::<Iq>(
+        concurrency.max_in_flight() + concurrency.queue_size() + OVERLOAD_REPLY_SLOTS,
+    );
+    // replies are written by a separate task, so they do not compete with reading stanzas
+    let (conn_sink, mut conn_stream) = conn.split();
+    let mut writer = tokio::spawn(batch_writer(conn_sink, out_recv, ctx.batching().clone()));
+    // in backpressure mode a queue slot is reserved before the next stanza is read
+    let mut reserved_permit: Option<OwnedSemaphorePermit> = None;
     loop {
         tokio::select! {
-            xmpp_msg = out_recv.recv() => {
-                if let Some(msg) = xmpp_msg {
-                    if let Err(e) = conn.send(msg.into()).await {
-                        error!("Could not reply iq: {}", e);
-                    }
-                } else {
-                    error!("Connection closed");
-                    return;
-                }
+            _ = shutdown.changed() => {
+                info!("Stopped reading stanzas, waiting for in-flight requests");
+                drop(reserved_permit);
+                let total_permits = concurrency.max_in_flight() + concurrency.queue_size();
+                drain_in_flight(
+                    admission,
+                    total_permits,
+                    out_sender,
+                    writer,
+                    *ctx.shutdown_deadline(),
+                )
+                .await;
+                return MessageLoopExit::Shutdown;
+            }
+            _ = &mut writer => {
+                error!("Connection closed");
+                return MessageLoopExit::ConnectionClosed;
             }
-            xmpp_poll = conn.next() => {
+            permit = admission.clone().acquire_owned(), if backpressure && reserved_permit.is_none() => {
+                reserved_permit = permit.ok();
+            }
+            // stop reading new stanzas until a queue slot is free
+            xmpp_poll = conn_stream.next(), if !backpressure || reserved_permit.is_some() => {
                 match xmpp_poll {
                     Some(stanza) => {
-                        dispatch_xmpp_msg_to_thread(&out_sender, push_modules.clone(), stanza);
+                        let admission_permit = if backpressure {
+                            reserved_permit.take()
+                        } else {
+                            admission.clone().try_acquire_owned().ok()
+                        };
+                        match admission_permit {
+                            Some(admission_permit) => dispatch_xmpp_msg_to_thread(
+                                &out_sender,
+                                ctx.clone(),
+                                stanza,
+                                admission_permit,
+                                in_flight.clone(),
+                            ),
+                            None => reject_overloaded_stanza(&out_sender, stanza),
+                        }
                     },
                     None => {
                         error!("The stream was closed, opening new connection");
-                        return;
+                        return MessageLoopExit::ConnectionClosed;
                     }
                 }
             },
-            else => {
-                error!("Main loop error: Closing");
-                return;
-            }
         };
     }
-    // TODO: batch out iq's together send every xxxms out
+}
+
+/// Wait until all admitted stanzas are handled and their replies are written
+///
+/// Handlers still running after `deadline` are abandoned and the connection is closed anyway.
+async fn drain_in_flight(
+    admission: Arc<Semaphore>,
+    total_permits: usize,
+    out_sender: mpsc::Sender<Iq>,
+    mut writer: JoinHandle<()>,
+    deadline: Duration,
+) {
+    let deadline = tokio::time::Instant::now() + deadline;
+    // every running or queued handler holds an admission permit until it is done
+    let total_permits = u32::try_from(total_permits).unwrap_or(u32::MAX);
+    if tokio::time::timeout_at(deadline, admission.acquire_many(total_permits))
+        .await
+        .is_err()
+    {
+        warn!(
+            "Shutdown deadline reached with {} requests still in flight",
+            total_permits as usize - admission.available_permits()
+        );
+    }
+    // the writer flushes the remaining replies and closes the connection once all senders are gone
+    drop(out_sender);
+    if tokio::time::timeout_at(deadline, &mut writer)
+        .await
+        .is_err()
+    {
+        warn!("Shutdown deadline reached before all replies were written");
+        writer.abort();
+    }
 }
 
 #[inline(always)]
 fn dispatch_xmpp_msg_to_thread(
     conn: &mpsc::Sender<Iq>,
-    push_modules: FpushPushArc,
+    ctx: XmppContextArc,
     stanza: Element,
+    admission_permit: OwnedSemaphorePermit,
+    in_flight: Arc<Semaphore>,
 ) {
     let conn_to_master = conn.clone();
     tokio::spawn(async move {
-        handle_iq(&conn_to_master, push_modules, stanza).await;
+        let iq = match Iq::try_from(stanza) {
+            Ok(iq) => iq,
+            Err(e) => {
+                debug!("Could not parse stanza: {}", e);
+                return;
+            }
+        };
+        // a delayed request must not hold a handler slot while it waits for its domain
+        if !check_domain_ratelimit(&conn_to_master, &ctx, &iq).await {
+            return;
+        }
+        // wait for a free handler slot, the admission permit bounds the number of waiting stanzas
+        let _in_flight_permit = match in_flight.acquire_owned().await {
+            Ok(permit) => permit,
+            Err(_) => return,
+        };
+        handle_iq(&conn_to_master, &ctx, iq).await;
+        drop(admission_permit);
     });
 }
 
+/// Apply the domain ratelimit to push requests and registrations
+///
+/// Returns false if the request was rejected and answered with an error.
+async fn check_domain_ratelimit(conn: &mpsc::Sender<Iq>, ctx: &XmppContext, iq: &Iq) -> bool {
+    let (to, from) = match (&iq.to, &iq.from, &iq.payload) {
+        (Some(to), Some(from), IqType::Set(_)) => (to, from),
+        _ => return true,
+    };
+    if ctx
+        .domain_ratelimit()
+        .lookup_ratelimit(&from.clone().domain())
+        .await
+    {
+        return true;
+    }
+    info!("Rejecting request from {} due to domain ratelimit", from);
+    send_stanza_error_iq(
+        conn,
+        &iq.id,
+        from.clone(),
+        to.clone(),
+        ErrorType::Wait,
+        DefinedCondition::ResourceConstraint,
+        "Too many requests from your domain",
+    )
+    .await;
+    false
+}
+
+/// Reply with a wait error to a stanza that could not be queued
 #[inline(always)]
-async fn handle_iq(conn: &mpsc::Sender<Iq>, push_modules: FpushPushArc, stanza: Element) {
-    // parse message
-    match Iq::try_from(stanza) {
-        Err(e) => {
-            debug!("Could not parse stanza: {}", e);
-        }
-        Ok(iq) => {
-            let (to, from, iq_payload) = match (iq.to, iq.from, iq.payload) {
-                (Some(to), Some(from), xmpp_parsers::iq::IqType::Set(iq_payload)) => {
-                    (to, from, iq_payload)
-                }
-                (Some(to), Some(from), xmpp_parsers::iq::IqType::Get(iq_payload)) => {
-                    if iq_payload.name() == "ping" {
-                        info!("Received ping from {}", from);
-                        send_ack_iq(conn, &iq.id, from, to).await;
-                    } else {
-                        send_error_iq(conn, &iq.id, from, to).await;
-                    }
-                    return;
-                }
-                (Some(to), Some(from), _) => {
-                    info!("Received unhandled iq from {}", from);
-                    send_error_iq(conn, &iq.id, from, to).await;
-                    return;
-                }
-                (_, None, _) => {
-                    warn!("Received iq without from");
-                    return;
-                }
-                (_, _, _) => {
-                    return;
-                }
-            };
-            let (module_id, token) = match parse_token_and_module_id(iq_payload) {
-                Ok((module_id, token)) => (module_id, token),
-                Err(e) => {
-                    warn!(
-                        "Could not retrieve token or module_id: {} source: {}",
-                        e, from
-                    );
-                    send_wait_iq_reason_old_prosody(conn, &iq.id, from, to).await;
-                    return;
-                }
-            };
-            warn!(
-                "Selected push_module {} for JID {} with token {}",
-                module_id, from, token
-            );
-            // handle_push_request
-            let push_result = push_modules.push(&module_id, token.clone()).await;
-            handle_push_result(conn, &module_id, &token, &push_result, from, to, iq.id).await
+fn reject_overloaded_stanza(conn: &mpsc::Sender<Iq>, stanza: Element) {
+    let iq = match Iq::try_from(stanza) {
+        Ok(iq) => iq,
+        Err(_) => return,
+    };
+    if let (Some(to), Some(from), IqType::Get(_) | IqType::Set(_)) = (iq.to, iq.from, iq.payload) {
+        warn!("Rejecting iq from {} as all handlers are busy", from);
+        let error_stanza = StanzaError::new(
+            ErrorType::Wait,
+            DefinedCondition::ResourceConstraint,
+            "en",
+            "Push server overloaded, try again later",
+        );
+        // never block the message loop, drop the reply if the reply channel is full
+        if let Err(e) = conn.try_send(
+            Iq::from_error(iq.id, error_stanza)
+                .with_to(from)
+                .with_from(to),
+        ) {
+            error!("Could not forward outgoing iq to main handler: {}", e);
         }
     }
 }
 
-async fn handle_push_result(
-    conn: &mpsc::Sender<Iq>,
-    module_id: &str,
-    token: &str,
-    push_result: &PushRequestResult<()>,
-    from: Jid,
-    to: Jid,
-    iq_id: String,
-) {
+#[inline(always)]
+async fn handle_iq(conn: &mpsc::Sender<Iq>, ctx: &XmppContext, iq: Iq) {
+    let (to, from, iq_payload) = match (iq.to, iq.from, iq.payload) {
+        (Some(to), Some(from), xmpp_parsers::iq::IqType::Set(iq_payload)) => {
+            if is_ad_hoc_command(&iq_payload) {
+                debug!("Received ad-hoc command from {}", from);
+                handle_ad_hoc_command(conn, ctx, iq_payload, &iq.id, from, to).await;
+                return;
+            }
+            (to, from, iq_payload)
+        }
+        (Some(to), Some(from), xmpp_parsers::iq::IqType::Get(iq_payload)) => {
+            if iq_payload.name() == "ping" {
+                info!("Received ping from {}", from);
+                send_ack_iq(conn, &iq.id, from, to).await;
+            } else if is_disco_info_query(&iq_payload) {
+                debug!("Received disco#info query from {}", from);
+                send_disco_info_iq(conn, ctx, iq_payload, &iq.id, from, to).await;
+            } else if is_disco_items_query(&iq_payload) {
+                debug!("Received disco#items query from {}", from);
+                send_disco_items_iq(conn, ctx, iq_payload, &iq.id, from, to).await;
+            } else {
+                send_error_iq(conn, &iq.id, from, to).await;
+            }
+            return;
+        }
+        (Some(to), Some(from), _) => {
+            info!("Received unhandled iq from {}", from);
+            send_error_iq(conn, &iq.id, from, to).await;
+            return;
+        }
+        (_, None, _) => {
+            warn!("Received iq without from");
+            return;
+        }
+        (_, _, _) => {
+            return;
+        }
+    };
+    let (module_id, push_request) = match PushIq::parse(&iq_payload) {
+        Ok(push_iq) => {
+            let (module_id, push_request) = push_iq.into_push_request();
+            (
+                select_module_by_alias(ctx, module_id, &to),
+                push_request.with_sender(from.to_string(), from.clone().domain()),
+            )
+        }
+        Err(e) => {
+            warn!("Received invalid push request from {}: {}", from, e);
+            send_stanza_error_iq(
+                conn,
+                &iq.id,
+                from,
+                to,
+                e.error_type(),
+                e.condition(),
+                &e.to_string(),
+            )
+            .await;
+            return;
+        }
+    };
+    let (module_id, push_request) = match ctx.registration() {
+        Some(registration) => match registration.resolve(module_id, push_request) {
+            NodeResolution::Resolved(module_id, push_request) => (module_id, *push_request),
+            NodeResolution::NotAuthorized(module_id) => {
+                warn!("Received push request with invalid secret from {}", from);
+                let push_error = PushRequestError::NotAuthorized;
+                send_push_error_reply(conn, ctx, &module_id, &push_error, &iq.id, from, to).await;
+                return;
+            }
+            // an unregistered node is handled like a token the push service reported invalid
+            NodeResolution::UnknownNode(module_id) => {
+                warn!("Received push request for unregistered node from {}", from);
+                let push_error = PushRequestError::TokenInvalid;
+                send_push_error_reply(conn, ctx, &module_id, &push_error, &iq.id, from, to).await;
+                return;
+            }
+        },
+        None => (module_id, push_request),
+    };
+    warn!(
+        "Selected push_module {} for JID {} with token {}",
+        module_id,
+        from,
+        push_request.token()
+    );
+    // handle_push_request
+    let push_result = ctx.push_modules().push(&module_id, &push_request).await;
     match push_result {
-        Ok(()) => send_ack_iq(conn, &iq_id, from, to).await,
-        Err(PushRequestError::TokenRatelimited) => {
-            // Some admins did not understood the wait_iq -> we know send an ack
-            send_ack_iq(conn, &iq_id, from, to).await
+        Ok(()) => send_ack_iq(conn, &iq.id, from, to).await,
+        Err(push_error) => {
+            log_push_error(&module_id, push_request.token(), &from, &push_error);
+            send_push_error_reply(conn, ctx, &module_id, &push_error, &iq.id, from, to).await;
         }
-        Err(PushRequestError::TokenBlocked) => {
+    }
+}
+
+/// Select the push module from the localpart or resource of the addressed component JID
+///
+/// Only applies if the push request did not name a push module in its publish-options.
+#[inline(always)]
+fn select_module_by_alias(ctx: &XmppContext, module_id: String, to: &Jid) -> String {
+    if module_id != "default" {
+        return module_id;
+    }
+    let resource = match to {
+        Jid::Full(full_jid) => Some(full_jid.resource.clone()),
+        Jid::Bare(_) => None,
+    };
+    to.clone()
+        .node()
+        .into_iter()
+        .chain(resource)
+        .find_map(|alias| ctx.push_modules().push_module_for_alias(&alias))
+        .unwrap_or(module_id)
+}
+
+fn log_push_error(module_id: &str, token: &str, from: &Jid, push_error: &PushRequestError) {
+    match push_error {
+        PushRequestError::TokenRatelimited => {}
+        PushRequestError::TokenBlocked => {
             warn!(
                 "{}: Received push request from blocked token {} from {}",
                 module_id, token, from
             );
-            send_error_policy_iq(conn, &iq_id, from, to).await;
         }
-        Err(PushRequestError::Internal) => {
+        PushRequestError::TokenInvalid => {
+            warn!(
+                "{}: Received push request for invalid token {} from {}",
+                module_id, token, from
+            );
+        }
+        PushRequestError::NotAuthorized => {
+            warn!(
+                "{}: Received push request with invalid secret for token {} from {}",
+                module_id, token, from
+            );
+        }
+        PushRequestError::Filtered => {
+            debug!(
+                "{}: Push request for token {} from {} was filtered",
+                module_id, token, from
+            );
+        }
+        PushRequestError::Internal => {
             warn!(
                 "{}: Incountered internal push error for token {} from {}",
                 module_id, token, from
             );
-            send_error_iq(conn, &iq_id, from, to).await;
         }
-        Err(PushRequestError::UnkownPushModule) => {
+        PushRequestError::Queued => {
+            info!(
+                "{}: Queued push request for token {} from {} until the push service recovers",
+                module_id, token, from
+            );
+        }
+        PushRequestError::UnkownPushModule => {
             warn!(
                 "{}: Unkown push module requested for token {} from {}",
                 module_id, token, from
             );
-            send_error_iq(conn, &iq_id, from, to).await;
         }
     }
 }
 
-#[inline(always)]
-fn parse_token_and_module_id(iq_payload: Element) -> Result<(String, String)> {
-    if let Ok(pubsub) = PubSub::try_from(iq_payload) {
-        match pubsub {
-            PubSub::Publish {
-                publish: pubsub_payload,
-                publish_options: None,
-            } => {
-                // Actual push notifications from prosody have a child value in the
-                // message body of one of the <field> tags, but in some cases
-                // mod_cloud_notify sends unimportant notifications that do not correspond
-                // to unread messages but were getting captured by fpush anyway.
-                // this logic filters out those notifications so they do not reach the user
-                for item in pubsub_payload.items {
-                    if let Some(payload) = &item.payload {
-                        for form_child in payload.children() {
-                            if let Ok(form) = Element::try_from(form_child.clone()) {
-                                for child in form.children() {
-                                    if let Ok(field) = Field::try_from(child.clone()) {
-                                        if field.var == "last-message-body" {
-                                            if field.values.is_empty() {
-                                                warn!("this is an unimportant notification from mod_cloud_notify, do not send to FCM");
-                                                return Err(Error::PubSubNonPublish);
-                                            } else if field
-                                                .values
-                                                .get(0)
-                                                .map(|value| value.as_str())
-                                                == Some("false")
-                                            {
-                                                warn!("this notification was marked to be skipped, do not send to FCM");
-                                                return Err(Error::PubSubNonPublish);
-                                            }
-                                        }
-                                    }
-                                }
-                            }
-                        }
-                    }
-                }
-                Ok(("default".to_string(), pubsub_payload.node.0))
-            }
-            PubSub::Publish {
-                publish: pubsub_payload,
-                publish_options: Some(publish_options),
-            } => {
-                if let Some(data_forms) = publish_options.form {
-                    if data_forms.fields.len() > 5 {
-                        return Err(Error::PubSubToManyPublishOptions);
-                    }
-                    for field in data_forms.fields {
-                        if field.var == "pushModule" {
-                            if field.values.len() != 1 {
-                                return Err(Error::PubSubInvalidPushModuleConfiguration);
-                            }
-                            if let Some(push_module_id) = field.values.first() {
-                                return Ok((push_module_id.to_string(), pubsub_payload.node.0));
-                            } else {
-                                unreachable!();
-                            }
-                        }
-                    }
-                }
-                Ok(("default".to_string(), pubsub_payload.node.0))
-            }
-            _ => Err(Error::PubSubNonPublish),
-        }
-    } else {
-        Err(Error::PubSubInvalidFormat)
-    }
+/// Reply to a failed push request as configured in the error policy of the push module
+async fn send_push_error_reply(
+    conn: &mpsc::Sender<Iq>,
+    ctx: &XmppContext,
+    module_id: &str,
+    push_error: &PushRequestError,
+    iq_id: &str,
+    from: Jid,
+    to: Jid,
+) {
+    let error_policy = ctx.push_modules().error_policy(module_id);
+    send_error_reply_iq(conn, iq_id, from, to, error_policy.reply_for(push_error)).await;
 }
@@ -1,3 +1,20 @@
 mod message_loop;
-pub(crate) use message_loop::{init_component_connection, message_loop_main_thread};
+pub(crate) use message_loop::{
+    init_component_connection, message_loop_main_thread, MessageLoopExit,
+};
+mod context;
+pub(crate) use context::{XmppContext, XmppContextArc};
+mod connection_state;
+pub(crate) use connection_state::{ConnectionMonitor, ConnectionState};
+mod reconnect;
+pub(crate) use reconnect::Backoff;
+mod connection;
+pub(crate) use connection::{component_connection_loop, ConnectionExit};
+mod ad_hoc;
+mod batch_writer;
+mod component;
+pub(crate) use component::ComponentConnection;
+mod disco;
 mod error_messages;
+mod push_iq;
+mod tls;
@@ -0,0 +1,467 @@
+use std::{collections::HashMap, fmt};
+
+use crate::xmpp::disco::NS_PUSH;
+use fpush_push::{PushPriority, PushRequest, PushSummary};
+
+use xmpp_parsers::{
+    data_forms::{DataForm, Field},
+    ns,
+    stanza_error::{DefinedCondition, ErrorType},
+    Element,
+};
+
+/// FORM_TYPE of the XEP-0060 publish-options form
+const FORM_TYPE_PUBLISH_OPTIONS: &str = "http://jabber.org/protocol/pubsub#publish-options";
+/// FORM_TYPE of the XEP-0357 notification summary
+const FORM_TYPE_PUSH_SUMMARY: &str = "urn:xmpp:push:summary";
+/// publish-options understood by fpush
+const KNOWN_PUBLISH_OPTIONS: [&str; 3] = ["pushModule", "secret", "priority"];
+
+/// Push notification published by an XMPP server as defined in XEP-0357
+#[derive(Debug)]
+pub(crate) struct PushIq {
+    /// node the notification was published to, which is the push token
+    token: String,
+    /// value of the `pushModule` publish-option
+    module_id: Option<String>,
+    publish_options: HashMap<String, Vec<String>>,
+    summary_fields: Vec<Field>,
+    item_id: Option<String>,
+}
+
+/// Reason a push IQ could not be parsed
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum PushIqError {
+    /// the IQ payload is not a pubsub element
+    NotPubSub,
+    /// the pubsub element does not publish an item
+    NotPublish,
+    /// the publish element has no or an empty node attribute
+    MissingNode,
+    /// an element unknown to XEP-0357 is part of the pubsub request
+    UnknownElement(String),
+    /// the publish-options form could not be parsed
+    InvalidPublishOptions(String),
+    /// the publish-options contain a field fpush does not understand
+    UnknownPublishOption(String),
+    /// the `pushModule` publish-option does not have exactly one value
+    InvalidPushModule,
+    /// the notification summary form could not be parsed
+    InvalidSummary(String),
+}
+
+impl fmt::Display for PushIqError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PushIqError::NotPubSub => write!(f, "Expected a pubsub publish request"),
+            PushIqError::NotPublish => write!(f, "Only publishing push notifications is supported"),
+            PushIqError::MissingNode => write!(f, "Missing push token in the publish node"),
+            PushIqError::UnknownElement(name) => write!(f, "Unknown element {}", name),
+            PushIqError::InvalidPublishOptions(reason) => {
+                write!(f, "Invalid publish-options: {}", reason)
+            }
+            PushIqError::UnknownPublishOption(var) => {
+                write!(f, "Unsupported publish-option {}", var)
+            }
+            PushIqError::InvalidPushModule => {
+                write!(
+                    f,
+                    "The pushModule publish-option requires exactly one value"
+                )
+            }
+            PushIqError::InvalidSummary(reason) => {
+                write!(f, "Invalid notification summary: {}", reason)
+            }
+        }
+    }
+}
+
+impl PushIqError {
+    /// Stanza error type of the reply to the XMPP server
+    pub(crate) fn error_type(&self) -> ErrorType {
+        match self {
+            PushIqError::NotPubSub
+            | PushIqError::NotPublish
+            | PushIqError::UnknownPublishOption(_) => ErrorType::Cancel,
+            _ => ErrorType::Modify,
+        }
+    }
+
+    /// Defined condition of the reply to the XMPP server
+    pub(crate) fn condition(&self) -> DefinedCondition {
+        match self {
+            PushIqError::NotPubSub | PushIqError::NotPublish => {
+                DefinedCondition::FeatureNotImplemented
+            }
+            PushIqError::InvalidPushModule => DefinedCondition::NotAcceptable,
+            // XEP-0060 answers publish-options that cannot be satisfied with a conflict
+            PushIqError::UnknownPublishOption(_) => DefinedCondition::Conflict,
+            _ => DefinedCondition::BadRequest,
+        }
+    }
+}
+
+impl PushIq {
+    /// Parse the payload of a push IQ of type set
+    ///
+    /// Unlike `xmpp_parsers::pubsub::PubSub` every publish-option is kept and each problem is
+    /// reported separately, so the XMPP server receives an accurate error.
+    pub(crate) fn parse(iq_payload: &Element) -> Result<Self, PushIqError> {
+        if !iq_payload.is("pubsub", ns::PUBSUB) {
+            return Err(PushIqError::NotPubSub);
+        }
+        let mut publish = None;
+        let mut publish_options = None;
+        for child in iq_payload.children() {
+            if child.is("publish", ns::PUBSUB) && publish.is_none() {
+                publish = Some(child);
+            } else if child.is("publish-options", ns::PUBSUB) && publish_options.is_none() {
+                publish_options = Some(child);
+            } else if child.ns() == ns::PUBSUB && publish.is_none() {
+                // e.g. subscribe or retract
+                return Err(PushIqError::NotPublish);
+            } else {
+                return Err(PushIqError::UnknownElement(child.name().to_string()));
+            }
+        }
+        let publish = publish.ok_or(PushIqError::NotPublish)?;
+        let token = match publish.attr("node") {
+            Some(node) if !node.is_empty() => node.to_string(),
+            _ => return Err(PushIqError::MissingNode),
+        };
+
+        let mut item_id = None;
+        let mut summary_fields = Vec::new();
+        for (index, item) in publish.children().enumerate() {
+            if !item.is("item", ns::PUBSUB) {
+                return Err(PushIqError::UnknownElement(item.name().to_string()));
+            }
+            if index == 0 {
+                item_id = item.attr("id").map(|id| id.to_string());
+            }
+            for notification in item.children() {
+                if notification.is("notification", NS_PUSH) {
+                    summary_fields.extend(Self::parse_summary(notification)?);
+                }
+            }
+        }
+
+        let publish_options = match publish_options {
+            Some(publish_options) => Self::parse_publish_options(publish_options)?,
+            None => HashMap::new(),
+        };
+        let module_id = match publish_options.get("pushModule") {
+            Some(values) if values.len() == 1 => Some(values[0].clone()),
+            Some(_) => return Err(PushIqError::InvalidPushModule),
+            None => None,
+        };
+
+        Ok(Self {
+            token,
+            module_id,
+            publish_options,
+            summary_fields,
+            item_id,
+        })
+    }
+
+    /// Collect all fields of the publish-options form
+    ///
+    /// Forms with a foreign FORM_TYPE are ignored, while unknown fields are rejected, as fpush
+    /// could not honour them.
+    fn parse_publish_options(
+        publish_options: &Element,
+    ) -> Result<HashMap<String, Vec<String>>, PushIqError> {
+        let mut options = HashMap::new();
+        for child in publish_options.children() {
+            if !child.is("x", ns::DATA_FORMS) {
+                return Err(PushIqError::UnknownElement(child.name().to_string()));
+            }
+            let form = DataForm::try_from(child.clone())
+                .map_err(|e| PushIqError::InvalidPublishOptions(e.to_string()))?;
+            if !matches!(
+                form.form_type.as_deref(),
+                None | Some(FORM_TYPE_PUBLISH_OPTIONS)
+            ) {
+                continue;
+            }
+            for field in form.fields {
+                if !KNOWN_PUBLISH_OPTIONS.contains(&field.var.as_str()) {
+                    return Err(PushIqError::UnknownPublishOption(field.var));
+                }
+                options.insert(field.var, field.values);
+            }
+        }
+        Ok(options)
+    }
+
+    /// Collect the fields of the summary forms included in a notification
+    fn parse_summary(notification: &Element) -> Result<Vec<Field>, PushIqError> {
+        let mut fields = Vec::new();
+        // other payloads of the notification, e.g. encrypted ones, are not forwarded
+        for child in notification
+            .children()
+            .filter(|child| child.is("x", ns::DATA_FORMS))
+        {
+            let form = DataForm::try_from(child.clone())
+                .map_err(|e| PushIqError::InvalidSummary(e.to_string()))?;
+            if matches!(
+                form.form_type.as_deref(),
+                None | Some(FORM_TYPE_PUSH_SUMMARY)
+            ) {
+                fields.extend(form.fields);
+            }
+        }
+        Ok(fields)
+    }
+
+    /// Build the push request and return it together with the requested push module
+    ///
+    /// Push IQs without `pushModule` publish-option use the `default` push module.
+    pub(crate) fn into_push_request(self) -> (String, PushRequest) {
+        let module_id = self.module_id.unwrap_or_else(|| "default".to_string());
+        let priority = self
+            .publish_options
+            .get("priority")
+            .and_then(|values| values.first())
+            .and_then(|priority| PushPriority::from_publish_option(priority))
+            .unwrap_or_default();
+        let push_request = PushRequest::new(self.token)
+            .with_item_id(self.item_id)
+            .with_publish_options(self.publish_options)
+            .with_priority(priority)
+            .with_summary(parse_push_summary(&self.summary_fields));
+        (module_id, push_request)
+    }
+}
+
+/// Parse the `urn:xmpp:push:summary` fields into a typed summary
+fn parse_push_summary(fields: &[Field]) -> PushSummary {
+    let field_value = |var: &str| -> Option<String> {
+        fields
+            .iter()
+            .find(|field| field.var == var)
+            .and_then(|field| field.values.first())
+            .filter(|value| !value.is_empty())
+            .cloned()
+    };
+    PushSummary::new(
+        field_value("message-count").and_then(|count| count.parse().ok()),
+        field_value("last-message-sender"),
+        field_value("last-message-body"),
+        field_value("pending-subscription-count").and_then(|count| count.parse().ok()),
+    )
+    .with_fields(
+        fields
+            .iter()
+            .map(|field| (field.var.clone(), field.values.clone()))
+            .collect(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(xml: &str) -> Result<PushIq, PushIqError> {
+        PushIq::parse(&xml.parse::<Element>().unwrap())
+    }
+
+    fn publish_with_options(options: &str) -> String {
+        format!(
+            "<pubsub xmlns='http://jabber.org/protocol/pubsub'>\
+               <publish node='token'><item/></publish>\
+               <publish-options>{}</publish-options>\
+             </pubsub>",
+            options
+        )
+    }
+
+    #[test]
+    fn test_prosody_push_iq() {
+        let push_iq = parse(
+            "<pubsub xmlns='http://jabber.org/protocol/pubsub'>\
+               <publish node='device-token'>\
+                 <item id='current'>\
+                   <notification xmlns='urn:xmpp:push:0'>\
+                     <x xmlns='jabber:x:data' type='form'>\
+                       <field var='FORM_TYPE' type='hidden'><value>urn:xmpp:push:summary</value></field>\
+                       <field var='message-count'><value>2</value></field>\
+                       <field var='last-message-sender'><value>juliet@example.org/balcony</value></field>\
+                       <field var='last-message-body'><value>Wherefore art thou?</value></field>\
+                     </x>\
+                     <encrypted xmlns='tigase:push:encrypt:0' iv='aXY='>cGF5bG9hZA==</encrypted>\
+                   </notification>\
+                 </item>\
+               </publish>\
+               <publish-options>\
+                 <x xmlns='jabber:x:data' type='submit'>\
+                   <field var='FORM_TYPE' type='hidden'><value>http://jabber.org/protocol/pubsub#publish-options</value></field>\
+                   <field var='pushModule'><value>apple</value></field>\
+                   <field var='secret'><value>s3cr3t</value></field>\
+                   <field var='priority'><value>normal</value></field>\
+                 </x>\
+               </publish-options>\
+             </pubsub>",
+        )
+        .unwrap();
+        let (module_id, push_request) = push_iq.into_push_request();
+        assert_eq!(module_id, "apple");
+        assert_eq!(push_request.token(), "device-token");
+        assert_eq!(push_request.item_id(), Some("current"));
+        assert_eq!(push_request.publish_option("secret"), Some("s3cr3t"));
+        assert_eq!(push_request.priority(), PushPriority::Normal);
+        assert_eq!(push_request.summary().message_count(), Some(2));
+        assert_eq!(
+            push_request.summary().last_message_body(),
+            Some("Wherefore art thou?")
+        );
+    }
+
+    #[test]
+    fn test_conversations_push_iq() {
+        // Conversations registers via ad-hoc command and only sends the node and secret
+        let push_iq = parse(
+            "<pubsub xmlns='http://jabber.org/protocol/pubsub'>\
+               <publish node='0123456789abcdef'><item/></publish>\
+               <publish-options>\
+                 <x xmlns='jabber:x:data' type='submit'>\
+                   <field var='FORM_TYPE' type='hidden'><value>http://jabber.org/protocol/pubsub#publish-options</value></field>\
+                   <field var='secret'><value>s3cr3t</value></field>\
+                 </x>\
+               </publish-options>\
+             </pubsub>",
+        )
+        .unwrap();
+        let (module_id, push_request) = push_iq.into_push_request();
+        assert_eq!(module_id, "default");
+        assert_eq!(push_request.token(), "0123456789abcdef");
+        assert_eq!(push_request.item_id(), None);
+        assert_eq!(push_request.priority(), PushPriority::High);
+        assert_eq!(push_request.summary().message_count(), None);
+    }
+
+    #[test]
+    fn test_foreign_forms_are_ignored() {
+        let push_iq = parse(
+            "<pubsub xmlns='http://jabber.org/protocol/pubsub'>\
+               <publish node='token'>\
+                 <item>\
+                   <notification xmlns='urn:xmpp:push:0'>\
+                     <x xmlns='jabber:x:data' type='result'>\
+                       <field var='FORM_TYPE' type='hidden'><value>urn:example:other</value></field>\
+                       <field var='message-count'><value>5</value></field>\
+                     </x>\
+                     <unknown xmlns='urn:example:other'/>\
+                   </notification>\
+                 </item>\
+               </publish>\
+               <publish-options>\
+                 <x xmlns='jabber:x:data' type='submit'>\
+                   <field var='FORM_TYPE' type='hidden'><value>urn:example:other</value></field>\
+                   <field var='custom'><value>value</value></field>\
+                 </x>\
+               </publish-options>\
+             </pubsub>",
+        )
+        .unwrap();
+        let (_, push_request) = push_iq.into_push_request();
+        assert!(push_request.publish_options().is_empty());
+        assert_eq!(push_request.summary().message_count(), None);
+    }
+
+    #[test]
+    fn test_push_iq_errors() {
+        assert_eq!(
+            parse("<query xmlns='http://jabber.org/protocol/disco#info'/>").unwrap_err(),
+            PushIqError::NotPubSub
+        );
+        assert_eq!(
+            parse(
+                "<pubsub xmlns='http://jabber.org/protocol/pubsub'>\
+                   <subscribe node='token' jid='push.example.org'/>\
+                 </pubsub>"
+            )
+            .unwrap_err(),
+            PushIqError::NotPublish
+        );
+        assert_eq!(
+            parse("<pubsub xmlns='http://jabber.org/protocol/pubsub'/>").unwrap_err(),
+            PushIqError::NotPublish
+        );
+        assert_eq!(
+            parse(
+                "<pubsub xmlns='http://jabber.org/protocol/pubsub'>\
+                   <publish node=''><item/></publish>\
+                 </pubsub>"
+            )
+            .unwrap_err(),
+            PushIqError::MissingNode
+        );
+        assert_eq!(
+            parse(
+                "<pubsub xmlns='http://jabber.org/protocol/pubsub'>\
+                   <publish node='token'><other/></publish>\
+                 </pubsub>"
+            )
+            .unwrap_err(),
+            PushIqError::UnknownElement("other".to_string())
+        );
+        assert!(matches!(
+            parse(&publish_with_options(
+                "<x xmlns='jabber:x:data' type='invalid'/>"
+            ))
+            .unwrap_err(),
+            PushIqError::InvalidPublishOptions(_)
+        ));
+        assert_eq!(
+            parse(&publish_with_options(
+                "<x xmlns='jabber:x:data' type='submit'>\
+                   <field var='endpoint'><value>https://example.org</value></field>\
+                 </x>"
+            ))
+            .unwrap_err(),
+            PushIqError::UnknownPublishOption("endpoint".to_string())
+        );
+        assert_eq!(
+            parse(&publish_with_options(
+                "<x xmlns='jabber:x:data' type='submit'>\
+                   <field var='pushModule'><value>apple</value><value>google</value></field>\
+                 </x>"
+            ))
+            .unwrap_err(),
+            PushIqError::InvalidPushModule
+        );
+        assert!(matches!(
+            parse(
+                "<pubsub xmlns='http://jabber.org/protocol/pubsub'>\
+                   <publish node='token'>\
+                     <item><notification xmlns='urn:xmpp:push:0'><x xmlns='jabber:x:data'/></notification></item>\
+                   </publish>\
+                 </pubsub>"
+            )
+            .unwrap_err(),
+            PushIqError::InvalidSummary(_)
+        ));
+    }
+
+    #[test]
+    fn test_error_replies() {
+        let unknown_option = PushIqError::UnknownPublishOption("endpoint".to_string());
+        assert_eq!(unknown_option.error_type(), ErrorType::Cancel);
+        assert_eq!(unknown_option.condition(), DefinedCondition::Conflict);
+        assert_eq!(
+            PushIqError::NotPublish.condition(),
+            DefinedCondition::FeatureNotImplemented
+        );
+        assert_eq!(
+            PushIqError::InvalidPushModule.condition(),
+            DefinedCondition::NotAcceptable
+        );
+        assert_eq!(PushIqError::MissingNode.error_type(), ErrorType::Modify);
+        assert_eq!(
+            PushIqError::MissingNode.condition(),
+            DefinedCondition::BadRequest
+        );
+    }
+}
@@ -0,0 +1,91 @@
+use std::time::Duration;
+
+use crate::config::fpush_config::ReconnectConfig;
+
+use rand::Rng;
+
+/// Exponential backoff with jitter between attempts to open the component connection
+pub(crate) struct Backoff {
+    initial_delay: Duration,
+    config: ReconnectConfig,
+    failed_attempts: u32,
+}
+
+impl Backoff {
+    pub(crate) fn new(initial_delay: Duration, config: &ReconnectConfig) -> Self {
+        Self {
+            initial_delay,
+            config: config.clone(),
+            failed_attempts: 0,
+        }
+    }
+
+    /// Return the delay before the next attempt and increase it for the attempt after
+    pub(crate) fn next_delay(&mut self) -> Duration {
+        let max_delay = self.config.max_delay().as_secs_f64();
+        let base_delay = (self.initial_delay.as_secs_f64()
+            * self
+                .config
+                .multiplier()
+                .max(1.0)
+                .powi(self.failed_attempts as i32))
+        .min(max_delay);
+        self.failed_attempts = self.failed_attempts.saturating_add(1);
+
+        let jitter = self.config.jitter().clamp(0.0, 1.0);
+        let jitter_factor = if jitter > 0.0 {
+            1.0 + rand::thread_rng().gen_range(-jitter..=jitter)
+        } else {
+            1.0
+        };
+        Duration::from_secs_f64((base_delay * jitter_factor).clamp(0.0, max_delay))
+    }
+
+    /// Number of failed attempts since the last successful connection
+    #[inline(always)]
+    pub(crate) fn failed_attempts(&self) -> u32 {
+        self.failed_attempts
+    }
+
+    #[inline(always)]
+    pub(crate) fn reset(&mut self) {
+        self.failed_attempts = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reconnect_config(jitter: f64) -> ReconnectConfig {
+        serde_json::from_value(serde_json::json!({
+            "maxDelay": "10s",
+            "multiplier": 2.0,
+            "jitter": jitter,
+            "onAuthFailure": "retry",
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_exponential_backoff() {
+        let mut backoff = Backoff::new(Duration::from_secs(1), &reconnect_config(0.0));
+        let delays: Vec<u64> = (0..6).map(|_| backoff.next_delay().as_secs()).collect();
+        assert_eq!(delays, vec![1, 2, 4, 8, 10, 10]);
+        assert_eq!(backoff.failed_attempts(), 6);
+
+        backoff.reset();
+        assert_eq!(backoff.failed_attempts(), 0);
+        assert_eq!(backoff.next_delay(), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_jitter_stays_within_bounds() {
+        let mut backoff = Backoff::new(Duration::from_secs(4), &reconnect_config(0.5));
+        let delay = backoff.next_delay();
+        assert!(delay >= Duration::from_secs(2) && delay <= Duration::from_secs(6));
+        for _ in 0..20 {
+            assert!(backoff.next_delay() <= Duration::from_secs(10));
+        }
+    }
+}